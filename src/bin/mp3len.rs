@@ -14,7 +14,7 @@ use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 
-static LOGGING_FORMAT: &'static str = "{d(%Y-%m-%dT%H:%M:%S%.3f%z)} {l:5.5} [{T}] {M}: {m}{n}";
+static LOGGING_FORMAT: &str = "{d(%Y-%m-%dT%H:%M:%S%.3f%z)} {l:5.5} [{T}] {M}: {m}{n}";
 
 const FIXED: bool = false;
 
@@ -60,7 +60,7 @@ fn main() {
             result => result
         };
 
-        if !frame.is_ok() {
+        if frame.is_err() {
             continue;
         }
 
@@ -69,4 +69,5 @@ fn main() {
     }
 
     println!("{:?}", mp3_duration::from_path(p));
+    println!("{:?}", phatnoise::metadata::mp3::analyse_path(p));
 }
@@ -14,10 +14,14 @@ use log4rs::config::{Appender, Config, Root};
 use phatnoise::dms;
 use phatnoise::library;
 use phatnoise::sync;
+use phatnoise::sync::plan::SyncPlan;
+use phatnoise::sync::timestamps::DEFAULT_TOLERANCE;
+use phatnoise::sync::manifest::Manifest;
 
+use std::env;
 use std::path::PathBuf;
 
-static LOGGING_FORMAT: &'static str = "{d(%Y-%m-%d %H:%M:%S)} {l:5.5} [{T}] {M}: {m}{n}";
+static LOGGING_FORMAT: &str = "{d(%Y-%m-%d %H:%M:%S)} {l:5.5} [{T}] {M}: {m}{n}";
 
 fn configure_rayon() {
     rayon::ThreadPoolBuilder::new().thread_name(|c| format!("rayon-cpu-{:02}", c))
@@ -44,18 +48,35 @@ fn main () {
     debug_library();
 }
 
+/// The library roots to debug, from the arguments or else the default music directory.
+fn library_dirs() -> Vec<PathBuf> {
+    let dirs: Vec<PathBuf> = env::args_os().skip(1).map(PathBuf::from).collect();
+
+    if dirs.is_empty() {
+        sync::default_music_dir().into_iter().collect()
+    } else {
+        dirs
+    }
+}
+
 fn debug_library() {
-    let local_library = library::get_local_media_library(&PathBuf::from("/home/naftuli/Music"));
+    let local_library = library::get_merged_media_library(&library_dirs());
     let dms_library = library::get_dms_media_library();
+    let dms_dir = dms::get_dms_mount_point().unwrap_or_default();
 
-    let added_files = sync::added_files(&local_library, &dms_library);
-    let deleted_files = sync::deleted_files(&local_library, &dms_library);
-    let changed_files = sync::changed_files(&local_library, &dms_library);
+    let manifest = Manifest::load(&dms_dir);
+    let plan =
+        SyncPlan::compute(&local_library, &dms_library, &dms_dir, &manifest, DEFAULT_TOLERANCE);
 
     info!("Library: Local Files: {}; DMS Files: {}", local_library.len(), dms_library.len());
-    info!("Files on Local But Not DMS: {}", added_files.len());
-    info!("Files on DMS But Not Local: {}", deleted_files.len());
-    info!("Updated Files: {}", changed_files.len());
+    info!("Files on Local But Not DMS: {}", plan.copies.len());
+    info!("Files on DMS But Not Local: {}", plan.deletes.len());
+    info!("Updated Files: {}", plan.updates.len());
+    info!("Moved Files: {}", plan.renames.len());
+
+    for line in plan.to_string().lines() {
+        debug!("{}", line);
+    }
 }
 
 fn debug_dms() {
@@ -0,0 +1,89 @@
+extern crate clap;
+extern crate log;
+extern crate log4rs;
+extern crate phatnoise;
+extern crate serde_json;
+
+use clap::{App, Arg};
+
+use log::{error, info, LevelFilter};
+
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::config::{Appender, Config, Root};
+
+use phatnoise::data::encoding::OutputEncoding;
+use phatnoise::data::verify::verify;
+use phatnoise::dms;
+
+use std::path::PathBuf;
+use std::process;
+
+static LOGGING_FORMAT: &str = "{d(%Y-%m-%dT%H:%M:%S%.3f%z)} {l:5.5} [{T}] {M}: {m}{n}";
+
+fn configure_logging() {
+    // the report goes to stdout, so keep logs out of its way
+    let appender = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new(LOGGING_FORMAT)))
+        .build();
+
+    let config = Config::builder()
+        .appender(Appender::builder().build("stderr", Box::new(appender)))
+        .build(Root::builder().appender("stderr").build(LevelFilter::Info))
+        .unwrap();
+
+    log4rs::init_config(config).unwrap();
+}
+
+fn main() {
+    let matches = App::new("phatnoise-fsck")
+        .about("Checks the databases on a PhatNoise DMS for problems.")
+        .arg(Arg::with_name("database")
+            .value_name("DATABASE_DIR")
+            .required(true)
+            .help("The directory holding the databases"))
+        .arg(Arg::with_name("media-root")
+            .value_name("MEDIA_ROOT")
+            .help("The directory media paths are relative to [default: the DMS mount point]"))
+        .arg(Arg::with_name("encoding")
+            .value_name("ENCODING")
+            .validator(|v| v.parse::<OutputEncoding>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("The encoding of the databases: utf-8, latin-1, or cp1252 [default: utf-8]"))
+        .get_matches();
+
+    configure_logging();
+
+    let database = PathBuf::from(matches.value_of("database").unwrap());
+
+    // media paths are relative to the DMS root, so default to wherever the DMS is mounted
+    let media_root = match matches
+        .value_of("media-root")
+        .map(PathBuf::from)
+        .or_else(dms::get_dms_mount_point)
+    {
+        Some(root) => root,
+        None => {
+            error!("No media root given and no DMS is mounted.");
+            process::exit(2);
+        }
+    };
+
+    let encoding = matches
+        .value_of("encoding")
+        .map(|e| e.parse().unwrap())
+        .unwrap_or_default();
+
+    info!("Verifying {} against {}...", database.display(), media_root.display());
+
+    let report = verify(&database, &media_root, encoding);
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    if report.is_ok() {
+        info!("No issues found in {} tracks.", report.tracks);
+    } else {
+        error!("Found {} issues in {} tracks.", report.issues.len(), report.tracks);
+        process::exit(1);
+    }
+}
@@ -14,14 +14,15 @@ use log4rs::config::{Appender, Config, Logger, Root};
 
 use phatnoise::library::get_local_media_library;
 use phatnoise::metadata::MediaMetadata;
+use phatnoise::metadata::cache::MetadataCache;
+use phatnoise::sync::default_music_dir;
 use phatnoise::utils::StringPool;
 
-use rayon::iter::IntoParallelIterator;
-use rayon::iter::ParallelIterator;
+use std::env;
+use std::path::PathBuf;
+use std::process;
 
-use std::path::Path;
-
-static LOGGING_FORMAT: &'static str = "{d(%Y-%m-%dT%H:%M:%S%.3f%z)} {l:5.5} [{T}] {M}: {m}{n}";
+static LOGGING_FORMAT: &str = "{d(%Y-%m-%dT%H:%M:%S%.3f%z)} {l:5.5} [{T}] {M}: {m}{n}";
 
 fn configure_logging() {
     let appender = ConsoleAppender::builder()
@@ -51,7 +52,14 @@ fn main() {
 
     info!("Scanning media library...");
 
-    let library_dir = Path::new("/home/naftuli/Music");
+    let library_dir = match env::args_os().nth(1).map(PathBuf::from).or_else(default_music_dir) {
+        Some(dir) => dir,
+        None => {
+            error!("No library directory given and unable to detect the home directory.");
+            process::exit(2);
+        }
+    };
+    let library_dir = library_dir.as_path();
     let pool = StringPool::new();
     // convert local media library into a sequence of PathBufs, then into a sequence of
     // Result<MediaMetadata>, then bounce down to MediaMetadata
@@ -61,11 +69,23 @@ fn main() {
         .build()
         .unwrap();
 
+    // only files which are new or have changed since the last run need to be parsed
+    let cache_path = MetadataCache::default_path().unwrap_or_else(|| {
+        warn!("Unable to determine the cache directory, caching in the working directory");
+        PathBuf::from("phatnoise-metadata.json")
+    });
+    let mut cache = MetadataCache::open(cache_path);
+
     threadpool.install(|| {
-        let mut files = get_local_media_library(&library_dir).into_par_iter()
-            .map(|l| MediaMetadata::load(&l.path, &library_dir, &pool))
-            .filter_map(|m| m.ok())
-            .collect::<Vec<MediaMetadata>>();
+        let paths = get_local_media_library(library_dir).into_iter()
+            .map(|l| l.path)
+            .collect::<Vec<PathBuf>>();
+
+        let mut files = cache.load_all(&paths, library_dir, &pool);
         files.sort_by(MediaMetadata::by_artist);
     });
+
+    if let Err(e) = cache.save() {
+        warn!("Unable to save the metadata cache: {}", e);
+    }
 }
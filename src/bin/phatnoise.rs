@@ -1,30 +1,43 @@
+extern crate clap;
+#[macro_use]
 extern crate log;
 extern crate log4rs;
 extern crate phatnoise;
 extern crate rayon;
+extern crate serde_json;
+
+use clap::{App, Arg};
 
 use log::LevelFilter;
 
-use log4rs::append::console::ConsoleAppender;
+use log4rs::append::console::{ConsoleAppender, Target};
 use log4rs::encode::pattern::PatternEncoder;
 use log4rs::config::{Appender, Config, Logger, Root};
 
-use phatnoise::sync::synchronize;
+use phatnoise::data::encoding::OutputEncoding;
+use phatnoise::metadata::cache::MetadataCache;
+use phatnoise::playlist::smart::SmartPlaylistConfig;
+use phatnoise::sync;
+use phatnoise::sync::{ErrorPolicy, SyncOptions};
+
+use std::process;
+use std::time::Duration;
 
-static LOGGING_FORMAT: &'static str = "{d(%Y-%m-%dT%H:%M:%S%.3f%z)} {l:5.5} [{T}] {M}: {m}{n}";
+static LOGGING_FORMAT: &str = "{d(%Y-%m-%dT%H:%M:%S%.3f%z)} {l:5.5} [{T}] {M}: {m}{n}";
 
-fn configure_logging() {
+fn configure_logging(target: Target) {
     let appender = ConsoleAppender::builder()
+        .target(target)
         .encoder(Box::new(PatternEncoder::new(LOGGING_FORMAT)))
         .build();
 
     let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(appender)))
+        .appender(Appender::builder().build("console", Box::new(appender)))
         .logger(Logger::builder().build("id3::frame::stream::v3", LevelFilter::Info))
         .logger(Logger::builder().build("id3::tag", LevelFilter::Info))
         .logger(Logger::builder().build("phatnoise::metadata", LevelFilter::Info))
         .logger(Logger::builder().build("phatnoise::utils::stringpool", LevelFilter::Info))
-        .build(Root::builder().appender("stdout").build(LevelFilter::Trace))
+        .build(Root::builder().appender("console").build(LevelFilter::Trace))
         .unwrap();
 
     log4rs::init_config(config).unwrap();
@@ -36,7 +49,115 @@ fn configure_rayon() {
 }
 
 fn main() {
-    configure_logging();
-    configure_rayon();
-    synchronize();
+    let matches = App::new("phatnoise")
+        .about("Synchronizes the local music library with a PhatNoise DMS.")
+        .arg(Arg::with_name("dry-run")
+            .long("dry-run")
+            .short("n")
+            .help("Prints what a sync would do without changing the DMS"))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .requires("dry-run")
+            .help("The format of the dry run's plan [default: text]"))
+        .arg(Arg::with_name("source")
+            .long("source")
+            .short("s")
+            .value_name("DIR")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("A library root to sync from; may be given more than once [default: $HOME/Music]"))
+        .arg(Arg::with_name("target")
+            .long("target")
+            .short("t")
+            .value_name("DIR")
+            .takes_value(true)
+            .help("A directory to sync to instead of the attached DMS"))
+        .arg(Arg::with_name("mtime-tolerance")
+            .long("mtime-tolerance")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("The largest difference in modification times counted as unchanged [default: 2]"))
+        .arg(Arg::with_name("encoding")
+            .long("encoding")
+            .value_name("ENCODING")
+            .takes_value(true)
+            .validator(|v| v.parse::<OutputEncoding>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("The encoding playlists are written in: utf-8, latin-1, or cp1252 [default: utf-8]"))
+        .arg(Arg::with_name("fail-fast")
+            .long("fail-fast")
+            .help("Stops at the first file which can't be synced instead of carrying on"))
+        .get_matches();
+
+    let mut options = SyncOptions::new();
+
+    for source in matches.values_of("source").into_iter().flatten() {
+        options = options.source(source);
+    }
+
+    if let Some(target) = matches.value_of("target") {
+        options = options.target(target);
+    }
+
+    if let Some(tolerance) = matches.value_of("mtime-tolerance") {
+        options = options.mtime_tolerance(Duration::from_secs(tolerance.parse().unwrap()));
+    }
+
+    if let Some(encoding) = matches.value_of("encoding") {
+        options = options.encoding(encoding.parse().unwrap());
+    }
+
+    if let Some(config) = SmartPlaylistConfig::default_path() {
+        options = options.smart_playlists(config);
+    }
+
+    if let Some(cache) = MetadataCache::default_path() {
+        options = options.metadata_cache(cache);
+    }
+
+    if matches.is_present("fail-fast") {
+        options = options.error_policy(ErrorPolicy::FailFast);
+    }
+
+    if matches.is_present("dry-run") {
+        // the plan goes to stdout, so keep logs out of its way
+        configure_logging(Target::Stderr);
+        configure_rayon();
+
+        let plan = match sync::plan(&options) {
+            Ok(plan) => plan,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        };
+
+        match matches.value_of("format") {
+            Some("json") => println!("{}", serde_json::to_string_pretty(&plan).unwrap()),
+            _ => println!("{}", plan),
+        }
+    } else {
+        configure_logging(Target::Stdout);
+        configure_rayon();
+
+        match sync::synchronize(&options) {
+            Ok(ref report) if report.is_ok() => {}
+            Ok(report) => {
+                error!("Unable to sync {} files:", report.failures.len());
+
+                for failure in &report.failures {
+                    error!("  {}", failure);
+                }
+
+                process::exit(1);
+            }
+            Err(e) => {
+                error!("Sync aborted: {}", e);
+                process::exit(1);
+            }
+        }
+    }
 }
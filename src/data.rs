@@ -1,7 +1,90 @@
+pub mod encoding;
+pub mod reader;
+pub mod verify;
+
+use log::{debug, warn};
+
+use crate::data::encoding::{Encoder, EncodingReport, OutputEncoding};
+use crate::metadata::MediaMetadata;
+use crate::utils::fs::sync_dir;
+use crate::utils::sanitize::sanitize_field;
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs;
 use std::fs::File;
-use std::io::BufWriter;
+use std::hash::Hash;
+use std::io;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// The file name of the tab-separated tracks database.
+pub static TRACKS_DB: &str = "tracks.csv";
+/// The file name of the index into the tracks database.
+pub static TRACKS_IDX: &str = "tracks.idx";
+/// The files of the artists database.
+pub const ARTISTS: GroupFiles = GroupFiles {
+    db: "artists.csv",
+    idx: "artists.idx",
+    dir: "artists",
+};
+/// The files of the albums database.
+pub const ALBUMS: GroupFiles = GroupFiles {
+    db: "albums.csv",
+    idx: "albums.idx",
+    dir: "albums",
+};
+/// The files of the genres database.
+pub const GENRES: GroupFiles = GroupFiles {
+    db: "genres.csv",
+    idx: "genres.idx",
+    dir: "genres",
+};
+
+/// The tracks database, to be rendered to disk as `tracks.csv` with one row per track.
+pub struct TracksDb {
+    pub contents: Vec<u8>,
+    pub index: TracksDbIndex,
+}
+
+impl TracksDb {
+    /// Build the tracks database from loaded metadata, keeping the order of the given tracks.
+    ///
+    /// The offset of each track's row is recorded in the index at the same position as the track.
+    pub fn build(tracks: &[MediaMetadata], encoder: &mut Encoder) -> Result<Self, DbError> {
+        // the head unit seeks directly to these, so they must be exact byte offsets of the encoded
+        // rows
+        let (contents, track_offsets) = render_rows(
+            tracks.iter().map(|t| t.to_csv()),
+            TRACKS_DB,
+            encoder,
+            Encoder::encode_location_row,
+        )?;
+
+        Ok(TracksDb {
+            contents,
+            index: TracksDbIndex { track_offsets },
+        })
+    }
+
+    /// Replace the tracks database and its index in the given directory.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Result<(), DbError> {
+        let mut staged = StagedFiles::new();
+        self.stage(dir.as_ref(), &mut staged)?;
+        staged.commit()
+    }
+
+    fn stage(&self, dir: &Path, staged: &mut StagedFiles) -> Result<(), DbError> {
+        staged.stage(dir.join(TRACKS_DB), &self.contents)?;
+        staged.stage(
+            dir.join(TRACKS_IDX),
+            &index_bytes(&self.index.track_offsets),
+        )
+    }
+}
 
 /// The database index into the tracks CSV file, to be rendered to disk as `tracks.idx`.
 pub struct TracksDbIndex {
@@ -9,28 +92,923 @@ pub struct TracksDbIndex {
 }
 
 impl TracksDbIndex {
-    /// Dump this database index to the given file.
-    pub fn write<P: AsRef<Path>>(&self, dest: P) {
-        let mut f = BufWriter::new(File::create(dest).expect("unable to create tracks.idx"));
+    /// Replace the given file with this database index.
+    pub fn write<P: AsRef<Path>>(&self, dest: P) -> Result<(), DbError> {
+        let mut staged = StagedFiles::new();
+        staged.stage(
+            dest.as_ref().to_path_buf(),
+            &index_bytes(&self.track_offsets),
+        )?;
+        staged.commit()
+    }
+}
+
+/// The names of the files making up a database which groups tracks, such as the artists database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GroupFiles {
+    /// The file name of the database, with one row per group.
+    pub db: &'static str,
+    /// The file name of the index into the database.
+    pub idx: &'static str,
+    /// The directory holding the index of each group's tracks.
+    pub dir: &'static str,
+}
+
+/// A database grouping tracks by artist, album, or genre, to be rendered to disk with one row per
+/// group, along with an index into it and an index of each group's tracks.
+pub struct GroupDb {
+    pub files: GroupFiles,
+    pub contents: Vec<u8>,
+    /// The offset of each group's row in the database, by group ID.
+    pub group_offsets: Vec<u32>,
+    pub groups: Vec<GroupDbIndex>,
+}
+
+impl GroupDb {
+    /// Build the artists database from loaded metadata and the index of the tracks database built
+    /// from the same tracks.
+    ///
+    /// Artists are ordered and assigned IDs according to `MediaMetadata::by_artist`, and each
+    /// artist's track offsets are listed in that same order.
+    pub fn artists(
+        tracks: &[MediaMetadata],
+        tracks_index: &TracksDbIndex,
+        encoder: &mut Encoder,
+    ) -> Result<Self, DbError> {
+        // artists sharing a sortable name may interleave, so group on the exact name
+        let groups = group_tracks(tracks, tracks_index, MediaMetadata::by_artist, |t| {
+            sanitize_field(&t.artist)
+        });
+        let rows = groups
+            .iter()
+            .enumerate()
+            .map(|(id, (artist, _))| format!("{}\t{}", id, artist))
+            .collect();
+
+        GroupDb::build(ARTISTS, rows, groups, encoder)
+    }
+
+    /// Build the albums database from loaded metadata and the index of the tracks database built
+    /// from the same tracks.
+    ///
+    /// Albums of the same name by different artists are distinct. Albums are ordered and assigned
+    /// IDs according to `MediaMetadata::by_artist`, so each album's tracks are in track order.
+    pub fn albums(
+        tracks: &[MediaMetadata],
+        tracks_index: &TracksDbIndex,
+        encoder: &mut Encoder,
+    ) -> Result<Self, DbError> {
+        let groups = group_tracks(tracks, tracks_index, MediaMetadata::by_artist, |t| {
+            (sanitize_field(&t.artist), sanitize_field(&t.album))
+        });
+        let rows = groups
+            .iter()
+            .enumerate()
+            .map(|(id, ((artist, album), _))| format!("{}\t{}\t{}", id, album, artist))
+            .collect();
+
+        GroupDb::build(ALBUMS, rows, groups, encoder)
+    }
+
+    /// Build the genres database from loaded metadata and the index of the tracks database built
+    /// from the same tracks.
+    ///
+    /// Genres are ordered and assigned IDs according to `MediaMetadata::by_genre`, and each
+    /// genre's track offsets are listed in that same order.
+    pub fn genres(
+        tracks: &[MediaMetadata],
+        tracks_index: &TracksDbIndex,
+        encoder: &mut Encoder,
+    ) -> Result<Self, DbError> {
+        let groups = group_tracks(tracks, tracks_index, MediaMetadata::by_genre, |t| {
+            sanitize_field(&t.genre)
+        });
+        let rows = groups
+            .iter()
+            .enumerate()
+            .map(|(id, (genre, _))| format!("{}\t{}", id, genre))
+            .collect();
+
+        GroupDb::build(GENRES, rows, groups, encoder)
+    }
+
+    /// Render the rows of the groups, which are assigned IDs in the order given.
+    fn build<K>(
+        files: GroupFiles,
+        rows: Vec<String>,
+        groups: Vec<(K, Vec<u32>)>,
+        encoder: &mut Encoder,
+    ) -> Result<Self, DbError> {
+        let (contents, group_offsets) =
+            render_rows(rows.into_iter(), files.db, encoder, Encoder::encode_row)?;
 
-        // first, write the count as a u32 at the beginning of the file
-        let count = self.track_offsets.len() as u32;
+        Ok(GroupDb {
+            files,
+            contents,
+            group_offsets,
+            groups: groups
+                .into_iter()
+                .enumerate()
+                .map(|(id, (_, track_offsets))| GroupDbIndex {
+                    id: id as u32,
+                    track_offsets,
+                })
+                .collect(),
+        })
+    }
 
-        f.write(&count.to_le_bytes())
-            .expect("unable to write count");
+    /// Replace the database, its index, and the index of each group in the given directory.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Result<(), DbError> {
+        let mut staged = StagedFiles::new();
+        self.stage(dir.as_ref(), &mut staged)?;
+        staged.commit()
+    }
 
-        for offset in &self.track_offsets {
-            // write each offset in order into the file
-            f.write(&offset.to_le_bytes())
-                .expect("unable to write offset");
+    fn stage(&self, dir: &Path, staged: &mut StagedFiles) -> Result<(), DbError> {
+        staged.stage(dir.join(self.files.db), &self.contents)?;
+        staged.stage(dir.join(self.files.idx), &index_bytes(&self.group_offsets))?;
+
+        // indexes of groups which no longer exist would otherwise linger
+        let groups_dir = dir.join(self.files.dir);
+        staged.prune(&groups_dir);
+
+        for group in &self.groups {
+            staged.stage(
+                groups_dir.join(format!("{}.idx", group.id)),
+                &index_bytes(&group.track_offsets),
+            )?;
         }
 
-        // critical to flush always
-        f.flush().expect("unable to flush data to disk");
+        Ok(())
     }
 }
 
-pub struct ArtistsDbIndex {
+/// The index of a single group's tracks in the tracks CSV file, to be rendered to disk as
+/// `<id>.idx` in the directory of its database, such as `artists/`.
+pub struct GroupDbIndex {
     pub id: u32,
     pub track_offsets: Vec<u32>,
 }
+
+impl GroupDbIndex {
+    /// Replace the given file with this database index.
+    pub fn write<P: AsRef<Path>>(&self, dest: P) -> Result<(), DbError> {
+        let mut staged = StagedFiles::new();
+        staged.stage(
+            dest.as_ref().to_path_buf(),
+            &index_bytes(&self.track_offsets),
+        )?;
+        staged.commit()
+    }
+}
+
+/// The complete set of databases the DMS uses to browse the media library.
+pub struct Database {
+    pub tracks: TracksDb,
+    pub artists: GroupDb,
+    pub albums: GroupDb,
+    pub genres: GroupDb,
+    /// Every field which couldn't be represented exactly in the output encoding.
+    pub encoding_report: EncodingReport,
+}
+
+impl Database {
+    /// Build every database from loaded metadata in the given encoding, with tracks in the order
+    /// given.
+    pub fn build(tracks: &[MediaMetadata], encoding: OutputEncoding) -> Result<Self, DbError> {
+        let mut encoder = Encoder::new(encoding);
+        let tracks_db = TracksDb::build(tracks, &mut encoder)?;
+
+        let database = Database {
+            artists: GroupDb::artists(tracks, &tracks_db.index, &mut encoder)?,
+            albums: GroupDb::albums(tracks, &tracks_db.index, &mut encoder)?,
+            genres: GroupDb::genres(tracks, &tracks_db.index, &mut encoder)?,
+            tracks: tracks_db,
+            encoding_report: encoder.into_report(),
+        };
+
+        for conversion in &database.encoding_report.conversions {
+            warn!(
+                "Unable to represent {:?} in {} for {}, wrote {:?}",
+                conversion.original, encoding, conversion.database, conversion.converted
+            );
+        }
+
+        Ok(database)
+    }
+
+    /// Replace every database and index in the given directory.
+    ///
+    /// Nothing is replaced until every file has been written and synced to disk, so a failure part
+    /// way through leaves the previous set of databases in place.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Result<(), DbError> {
+        let dir = dir.as_ref();
+        let mut staged = StagedFiles::new();
+
+        self.tracks.stage(dir, &mut staged)?;
+        self.artists.stage(dir, &mut staged)?;
+        self.albums.stage(dir, &mut staged)?;
+        self.genres.stage(dir, &mut staged)?;
+
+        staged.commit()
+    }
+}
+
+/// Group tracks by the given key, visiting them in the given order.
+///
+/// Keys should be sanitized as they're written, so that values which are written the same are
+/// grouped together.
+///
+/// Groups are returned in order of their first track, each with the offsets of its tracks into the
+/// tracks database.
+fn group_tracks<'a, K, F>(
+    tracks: &'a [MediaMetadata],
+    tracks_index: &TracksDbIndex,
+    order: fn(&MediaMetadata, &MediaMetadata) -> Ordering,
+    key: F,
+) -> Vec<(K, Vec<u32>)>
+where
+    K: Clone + Eq + Hash,
+    F: Fn(&'a MediaMetadata) -> K,
+{
+    let mut sorted: Vec<usize> = (0..tracks.len()).collect();
+    sorted.sort_by(|&a, &b| order(&tracks[a], &tracks[b]));
+
+    let mut ids: HashMap<K, usize> = HashMap::new();
+    let mut groups: Vec<(K, Vec<u32>)> = Vec::new();
+
+    for i in sorted {
+        let key = key(&tracks[i]);
+        let id = *ids.entry(key.clone()).or_insert_with(|| {
+            groups.push((key, Vec::new()));
+            groups.len() - 1
+        });
+
+        groups[id].1.push(tracks_index.track_offsets[i]);
+    }
+
+    groups
+}
+
+/// Render newline-terminated rows into a database in the encoder's encoding with the given
+/// encoding function, returning its contents along with the byte offset of each row.
+///
+/// Offsets are 32 bits, so a database whose rows start beyond 4GiB can't be indexed.
+fn render_rows<I: Iterator<Item = String>>(
+    rows: I,
+    name: &str,
+    encoder: &mut Encoder,
+    encode: fn(&mut Encoder, &str, u32, &str) -> Vec<u8>,
+) -> Result<(Vec<u8>, Vec<u32>), DbError> {
+    let mut contents = Vec::new();
+    let mut offsets = Vec::new();
+
+    for row in rows {
+        let offset = u32::try_from(contents.len()).map_err(|_| DbError::TooLarge {
+            database: name.to_string(),
+        })?;
+
+        offsets.push(offset);
+        contents.extend_from_slice(&encode(encoder, name, offset, &row));
+        contents.push(b'\n');
+    }
+
+    Ok((contents, offsets))
+}
+
+/// Render a list of offsets as an index: a little-endian u32 count followed by each little-endian
+/// u32 offset.
+fn index_bytes(offsets: &[u32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 * (offsets.len() + 1));
+
+    // first, write the count as a u32 at the beginning of the file
+    bytes.extend_from_slice(&(offsets.len() as u32).to_le_bytes());
+
+    for offset in offsets {
+        // write each offset in order into the file
+        bytes.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    bytes
+}
+
+/// Database files written to temporary files alongside their destinations, so that a whole set
+/// can be renamed into place once every file is safely on disk.
+///
+/// Temporary files which haven't been committed are removed when this is dropped.
+struct StagedFiles {
+    files: VecDeque<(PathBuf, PathBuf)>,
+    pruned_dirs: Vec<PathBuf>,
+}
+
+impl StagedFiles {
+    fn new() -> Self {
+        StagedFiles {
+            files: VecDeque::new(),
+            pruned_dirs: Vec::new(),
+        }
+    }
+
+    /// Write the contents of a file to a temporary file next to its destination and sync it.
+    fn stage(&mut self, dest: PathBuf, contents: &[u8]) -> Result<(), DbError> {
+        let parent = dest.parent().unwrap_or_else(|| Path::new("."));
+
+        if !parent.is_dir() {
+            fs::create_dir_all(parent).map_err(|err| DbError::io(parent, err))?;
+        }
+
+        // the temporary file must be on the same filesystem for the rename to be atomic
+        let temp = parent.join(format!(
+            ".{}.tmp",
+            dest.file_name().unwrap_or_default().to_string_lossy()
+        ));
+
+        self.files.push_back((temp.clone(), dest));
+
+        let mut f = File::create(&temp).map_err(|err| DbError::io(&temp, err))?;
+
+        f.write_all(contents)
+            .and_then(|_| f.sync_all())
+            .map_err(|err| DbError::io(&temp, err))
+    }
+
+    /// Remove any index files in the given directory which aren't part of this set on commit.
+    fn prune(&mut self, dir: &Path) {
+        self.pruned_dirs.push(dir.to_path_buf());
+    }
+
+    /// Rename every staged file into place, then sync the directories holding them.
+    ///
+    /// The files being replaced are kept as backups until every rename has succeeded, so that a
+    /// failure part way through restores the previous set rather than leaving a mix of both.
+    fn commit(mut self) -> Result<(), DbError> {
+        let mut dirs: BTreeSet<PathBuf> = self.pruned_dirs.iter().cloned().collect();
+        let mut replaced: Vec<(PathBuf, Option<PathBuf>)> = Vec::new();
+
+        while let Some((temp, dest)) = self.files.pop_front() {
+            if let Err(err) = replace(&temp, &dest, &mut replaced) {
+                fs::remove_file(&temp).ok();
+                rollback(&replaced);
+                return Err(err);
+            }
+
+            if let Some(parent) = dest.parent() {
+                dirs.insert(parent.to_path_buf());
+            }
+        }
+
+        for backup in replaced.iter().filter_map(|(_, backup)| backup.as_ref()) {
+            fs::remove_file(backup).map_err(|err| DbError::io(backup, err))?;
+        }
+
+        let committed: HashSet<PathBuf> = replaced.into_iter().map(|(dest, _)| dest).collect();
+
+        for dir in &self.pruned_dirs {
+            let stale = fs::read_dir(dir)
+                .map_err(|err| DbError::io(dir, err))?
+                .filter_map(Result::ok)
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|e| e == "idx"))
+                .filter(|p| !committed.contains(p));
+
+            for path in stale {
+                debug!("Removing stale index {}", path.display());
+                fs::remove_file(&path).map_err(|err| DbError::io(&path, err))?;
+            }
+        }
+
+        // the renames themselves are only durable once the directories are synced
+        for dir in &dirs {
+            sync_dir(dir).map_err(|err| DbError::io(dir, err))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Rename a staged file over its destination, first moving any file it replaces to a backup.
+///
+/// The destination is recorded in `replaced` as soon as it's touched, so that it can be rolled
+/// back.
+fn replace(
+    temp: &Path,
+    dest: &Path,
+    replaced: &mut Vec<(PathBuf, Option<PathBuf>)>,
+) -> Result<(), DbError> {
+    let backup = if dest.is_file() {
+        let backup = dest.with_file_name(format!(
+            ".{}.bak",
+            dest.file_name().unwrap_or_default().to_string_lossy()
+        ));
+
+        fs::rename(dest, &backup).map_err(|err| DbError::io(dest, err))?;
+        Some(backup)
+    } else {
+        None
+    };
+
+    replaced.push((dest.to_path_buf(), backup));
+
+    fs::rename(temp, dest).map_err(|err| DbError::io(dest, err))
+}
+
+/// Restore the files replaced by a failed commit from their backups, removing the new files
+/// which didn't replace anything.
+fn rollback(replaced: &[(PathBuf, Option<PathBuf>)]) {
+    for (dest, backup) in replaced.iter().rev() {
+        let restored = match backup {
+            Some(backup) => fs::rename(backup, dest),
+            None => fs::remove_file(dest).or_else(|err| match err.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(err),
+            }),
+        };
+
+        if let Err(err) = restored {
+            warn!("Unable to restore {}: {}", dest.display(), err);
+        }
+    }
+}
+
+impl Drop for StagedFiles {
+    fn drop(&mut self) {
+        for (temp, _) in &self.files {
+            fs::remove_file(temp).ok();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    IOError { path: PathBuf, err: io::Error },
+    TooLarge { database: String },
+}
+
+impl DbError {
+    fn io(path: &Path, err: io::Error) -> Self {
+        DbError::IOError {
+            path: path.to_path_buf(),
+            err,
+        }
+    }
+}
+
+impl Error for DbError {
+    fn description(&self) -> &str {
+        "Unable to write DMS database."
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DbError::IOError { ref path, ref err } => {
+                write!(f, "Unable to write {}: {}", path.display(), err)
+            }
+            DbError::TooLarge { ref database } => write!(
+                f,
+                "Unable to write {}: it exceeds the 4GiB the DMS can index",
+                database
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use crate::utils::StringPool;
+
+    pub fn load_fixtures(pool: &StringPool) -> Vec<MediaMetadata> {
+        let base = Path::new("test/fixtures");
+        let mut tracks: Vec<MediaMetadata> = fs::read_dir(base.join("flac"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .map(|p| MediaMetadata::load(&p, base, pool).unwrap())
+            .collect();
+
+        tracks.sort_by(MediaMetadata::by_artist);
+        tracks
+    }
+
+    #[test]
+    fn test_tracks_db_offsets() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let db = TracksDb::build(&tracks, &mut Encoder::default()).unwrap();
+
+        assert_eq!(tracks.len(), db.index.track_offsets.len());
+        assert_eq!(0, db.index.track_offsets[0]);
+
+        // every offset should point at the start of the corresponding track's row
+        for (track, offset) in tracks.iter().zip(&db.index.track_offsets) {
+            let row = &db.contents[*offset as usize..];
+            let row = &row[..row.iter().position(|&b| b == b'\n').unwrap()];
+
+            assert_eq!(track.to_csv().as_bytes(), row);
+        }
+    }
+
+    #[test]
+    fn test_tracks_db_write() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let dir = tempfile::tempdir().unwrap();
+        let db = TracksDb::build(&tracks, &mut Encoder::default()).unwrap();
+
+        db.write(dir.path()).unwrap();
+
+        assert_eq!(db.contents, fs::read(dir.path().join(TRACKS_DB)).unwrap());
+
+        let idx = fs::read(dir.path().join(TRACKS_IDX)).unwrap();
+        assert_eq!(4 * (tracks.len() + 1), idx.len());
+        assert_eq!(&(tracks.len() as u32).to_le_bytes(), &idx[..4]);
+
+        for (i, offset) in db.index.track_offsets.iter().enumerate() {
+            assert_eq!(&offset.to_le_bytes(), &idx[4 * (i + 1)..4 * (i + 2)]);
+        }
+    }
+
+    #[test]
+    fn test_artists_db() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let tracks_db = TracksDb::build(&tracks, &mut Encoder::default()).unwrap();
+        let db = GroupDb::artists(&tracks, &tracks_db.index, &mut Encoder::default()).unwrap();
+
+        let rows: Vec<&str> = std::str::from_utf8(&db.contents).unwrap().lines().collect();
+
+        // artists should be unique and in sorted order with sequential IDs
+        assert_eq!(
+            vec![
+                "0\tAlbum Artist",
+                "1\tArtist",
+                "2\tComposer",
+                "3\tUnknown Artist"
+            ],
+            rows
+        );
+        assert_eq!(rows.len(), db.groups.len());
+
+        for (id, (artist, offset)) in db.groups.iter().zip(&db.group_offsets).enumerate() {
+            assert_eq!(id as u32, artist.id);
+            assert!(db.contents[*offset as usize..].starts_with(format!("{}\t", id).as_bytes()));
+        }
+
+        // every track should belong to exactly one artist, pointing at a row for that artist
+        assert_eq!(
+            tracks.len(),
+            db.groups
+                .iter()
+                .map(|a| a.track_offsets.len())
+                .sum::<usize>()
+        );
+
+        let unknown = &db.groups[3];
+        assert_eq!(4, unknown.track_offsets.len());
+
+        for offset in &unknown.track_offsets {
+            let i = tracks_db
+                .index
+                .track_offsets
+                .iter()
+                .position(|o| o == offset)
+                .unwrap();
+
+            assert_eq!("Unknown Artist", &*tracks[i].artist);
+        }
+    }
+
+    #[test]
+    fn test_artists_db_write() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let dir = tempfile::tempdir().unwrap();
+        let db = GroupDb::artists(
+            &tracks,
+            &TracksDb::build(&tracks, &mut Encoder::default())
+                .unwrap()
+                .index,
+            &mut Encoder::default(),
+        )
+        .unwrap();
+
+        db.write(dir.path()).unwrap();
+
+        assert_eq!(db.contents, fs::read(dir.path().join(ARTISTS.db)).unwrap());
+        assert_eq!(
+            4 * (db.groups.len() + 1),
+            fs::read(dir.path().join(ARTISTS.idx)).unwrap().len()
+        );
+
+        for artist in &db.groups {
+            let idx = fs::read(
+                dir.path()
+                    .join(ARTISTS.dir)
+                    .join(format!("{}.idx", artist.id)),
+            )
+            .unwrap();
+
+            assert_eq!(
+                &(artist.track_offsets.len() as u32).to_le_bytes(),
+                &idx[..4]
+            );
+            assert_eq!(4 * (artist.track_offsets.len() + 1), idx.len());
+        }
+    }
+
+    #[test]
+    fn test_albums_db() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let tracks_db = TracksDb::build(&tracks, &mut Encoder::default()).unwrap();
+        let db = GroupDb::albums(&tracks, &tracks_db.index, &mut Encoder::default()).unwrap();
+
+        let rows: Vec<&str> = std::str::from_utf8(&db.contents).unwrap().lines().collect();
+
+        // the same album by different artists should be distinct
+        assert_eq!(
+            vec![
+                "0\tUnknown Album\tAlbum Artist",
+                "1\tUnknown Album\tArtist",
+                "2\tUnknown Album\tComposer",
+                "3\tAlbum\tUnknown Artist",
+                "4\tUnknown Album\tUnknown Artist",
+            ],
+            rows
+        );
+        assert_eq!(rows.len(), db.group_offsets.len());
+        assert_eq!(1, db.groups[3].track_offsets.len());
+        assert_eq!(3, db.groups[4].track_offsets.len());
+    }
+
+    #[test]
+    fn test_genres_db() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let tracks_db = TracksDb::build(&tracks, &mut Encoder::default()).unwrap();
+        let db = GroupDb::genres(&tracks, &tracks_db.index, &mut Encoder::default()).unwrap();
+
+        let rows: Vec<&str> = std::str::from_utf8(&db.contents).unwrap().lines().collect();
+
+        assert_eq!(vec!["0\tGenre", "1\tStyle 1", "2\tUnknown Genre"], rows);
+        assert_eq!(
+            vec![1, 1, 5],
+            db.groups
+                .iter()
+                .map(|g| g.track_offsets.len())
+                .collect::<Vec<usize>>()
+        );
+
+        // tracks within a genre should follow by_genre ordering
+        let unknown: Vec<&MediaMetadata> = db.groups[2]
+            .track_offsets
+            .iter()
+            .map(|o| {
+                let i = tracks_db.index.track_offsets.iter().position(|t| t == o);
+                &tracks[i.unwrap()]
+            })
+            .collect();
+
+        for pair in unknown.windows(2) {
+            assert_ne!(Ordering::Greater, MediaMetadata::by_genre(pair[0], pair[1]));
+        }
+    }
+
+    #[test]
+    fn test_database_write() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::build(&tracks, OutputEncoding::Utf8).unwrap();
+
+        db.write(dir.path()).unwrap();
+
+        for name in &[
+            TRACKS_DB,
+            TRACKS_IDX,
+            ARTISTS.db,
+            ARTISTS.idx,
+            ALBUMS.db,
+            ALBUMS.idx,
+            GENRES.db,
+            GENRES.idx,
+        ] {
+            assert!(dir.path().join(name).is_file(), "{} is missing", name);
+        }
+
+        assert_eq!(
+            db.albums.contents,
+            fs::read(dir.path().join(ALBUMS.db)).unwrap()
+        );
+        assert_eq!(
+            db.genres.contents,
+            fs::read(dir.path().join(GENRES.db)).unwrap()
+        );
+        assert_eq!(
+            db.albums.groups.len(),
+            fs::read_dir(dir.path().join(ALBUMS.dir)).unwrap().count()
+        );
+        assert_eq!(
+            db.genres.groups.len(),
+            fs::read_dir(dir.path().join(GENRES.dir)).unwrap().count()
+        );
+    }
+
+    #[test]
+    fn test_database_write_prunes_stale_indexes() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let dir = tempfile::tempdir().unwrap();
+
+        Database::build(&tracks, OutputEncoding::Utf8)
+            .unwrap()
+            .write(dir.path())
+            .unwrap();
+
+        // with a single track left there is only one artist, album, and genre
+        Database::build(&tracks[..1], OutputEncoding::Utf8)
+            .unwrap()
+            .write(dir.path())
+            .unwrap();
+
+        for group_dir in &[ARTISTS.dir, ALBUMS.dir, GENRES.dir] {
+            let names: Vec<String> = fs::read_dir(dir.path().join(group_dir))
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+
+            assert_eq!(vec!["0.idx"], names);
+        }
+    }
+
+    #[test]
+    fn test_database_write_failure_keeps_previous_set() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let dir = tempfile::tempdir().unwrap();
+
+        Database::build(&tracks[..1], OutputEncoding::Utf8)
+            .unwrap()
+            .write(dir.path())
+            .unwrap();
+        let before = fs::read(dir.path().join(TRACKS_DB)).unwrap();
+
+        // a file in place of the genres directory fails the write after the rest are staged
+        fs::remove_dir_all(dir.path().join(GENRES.dir)).unwrap();
+        fs::write(dir.path().join(GENRES.dir), b"").unwrap();
+
+        let err = Database::build(&tracks, OutputEncoding::Utf8)
+            .unwrap()
+            .write(dir.path())
+            .unwrap_err();
+        assert!(err.to_string().contains(GENRES.dir));
+
+        assert_eq!(before, fs::read(dir.path().join(TRACKS_DB)).unwrap());
+        assert_eq!(
+            1,
+            fs::read_dir(dir.path().join(ARTISTS.dir)).unwrap().count()
+        );
+
+        // no temporary files should be left behind
+        for entry in walkdir::WalkDir::new(dir.path()) {
+            let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+            assert!(!name.ends_with(".tmp"), "{} was left behind", name);
+        }
+    }
+
+    #[test]
+    fn test_commit_failure_restores_previous_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join(TRACKS_DB), dir.path().join(TRACKS_IDX));
+        fs::write(&first, b"old tracks").unwrap();
+        fs::write(&second, b"old index").unwrap();
+
+        let mut staged = StagedFiles::new();
+        staged.stage(first.clone(), b"new tracks").unwrap();
+        staged.stage(second.clone(), b"new index").unwrap();
+        staged
+            .stage(dir.path().join(ARTISTS.db), b"new artists")
+            .unwrap();
+
+        // losing the second temporary file fails its rename after the first is in place
+        fs::remove_file(&staged.files[1].0).unwrap();
+
+        let err = staged.commit().unwrap_err();
+        assert!(err.to_string().contains(TRACKS_IDX));
+
+        assert_eq!(b"old tracks".to_vec(), fs::read(&first).unwrap());
+        assert_eq!(b"old index".to_vec(), fs::read(&second).unwrap());
+
+        // nothing else should be left behind
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(vec![TRACKS_DB, TRACKS_IDX], names);
+    }
+
+    #[test]
+    fn test_database_pathological_tags() {
+        let pool = StringPool::new();
+        let mut tracks = load_fixtures(&pool);
+
+        tracks[0].title = "Tab\tIn\r\nTitle\0".to_string();
+        tracks[0].artist = pool.get("Artist\nWith Newline");
+        tracks[1].album = pool.get("\tAlbum\u{1b}");
+        tracks[2].genre = pool.get(&"Genre ".repeat(100));
+
+        let db = Database::build(&tracks, OutputEncoding::Utf8).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        db.write(dir.path()).unwrap();
+
+        // every row should still have the right shape, so the databases must read back cleanly
+        let contents = reader::DatabaseContents::read(dir.path(), OutputEncoding::Utf8).unwrap();
+
+        assert_eq!(tracks.len(), contents.tracks.len());
+        assert_eq!(db.tracks.index.track_offsets, contents.tracks_index);
+        assert_eq!("Tab In Title", contents.tracks[0].title);
+        assert_eq!("Artist With Newline", contents.tracks[0].artist);
+        assert_eq!("Album", contents.tracks[1].album);
+        assert_eq!(
+            crate::utils::sanitize::MAX_FIELD_LENGTH,
+            contents.tracks[2].genre.chars().count()
+        );
+        assert!(contents
+            .artists
+            .unwrap()
+            .rows
+            .iter()
+            .any(|r| r.name == "Artist With Newline"));
+    }
+
+    #[test]
+    fn test_database_groups_sanitized_values() {
+        let pool = StringPool::new();
+        let mut tracks = load_fixtures(&pool);
+
+        // written the same once sanitized, so they must share a row
+        for track in tracks.iter_mut() {
+            track.artist = pool.get("Artist");
+            track.album = pool.get("Album");
+        }
+        tracks[0].artist = pool.get("Artist\n");
+        tracks[1].album = pool.get("\tAlbum");
+        tracks[0].path = tracks[0].base.join("flac/Tab\tName.flac");
+
+        let db = Database::build(&tracks, OutputEncoding::Utf8).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        db.write(dir.path()).unwrap();
+
+        let contents = reader::DatabaseContents::read(dir.path(), OutputEncoding::Utf8).unwrap();
+
+        assert_eq!(1, db.artists.groups.len());
+        assert_eq!(1, db.albums.groups.len());
+        assert_eq!(
+            vec!["Artist"],
+            contents
+                .artists
+                .unwrap()
+                .rows
+                .iter()
+                .map(|r| r.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("/dos/data/flac/TabName.flac", contents.tracks[0].location);
+    }
+
+    #[test]
+    fn test_database_encoding_report() {
+        let pool = StringPool::new();
+        let mut tracks = load_fixtures(&pool);
+
+        tracks[0].artist = pool.get("Mêlée");
+        tracks[1].title = "Ægir \u{2192} Łódź".to_string();
+
+        let utf8 = Database::build(&tracks, OutputEncoding::Utf8).unwrap();
+        assert!(utf8.encoding_report.is_lossless());
+
+        let latin1 = Database::build(&tracks, OutputEncoding::Latin1).unwrap();
+        let conversions = &latin1.encoding_report.conversions;
+
+        // latin-1 holds "Mêlée" and "Æ" but not the arrow or the Polish letters
+        assert_eq!(1, conversions.len());
+        assert_eq!(TRACKS_DB, conversions[0].database);
+        assert_eq!(latin1.tracks.index.track_offsets[1], conversions[0].offset);
+        assert_eq!("Ægir - Lódz", conversions[0].converted);
+
+        // offsets must account for the narrower encoding
+        assert!(latin1.tracks.contents.len() < utf8.tracks.contents.len());
+
+        for (i, offset) in latin1.tracks.index.track_offsets.iter().enumerate().skip(1) {
+            assert_eq!(
+                b'\n',
+                latin1.tracks.contents[*offset as usize - 1],
+                "row {}",
+                i
+            );
+        }
+    }
+}
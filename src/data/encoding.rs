@@ -0,0 +1,403 @@
+//! Character encodings for the DMS databases.
+//!
+//! It isn't known which encoding the head unit firmware expects, so the encoding is configurable.
+//! Characters which can't be represented in the chosen encoding are transliterated, and every such
+//! lossy conversion is recorded so that it can be reported. File locations are never
+//! transliterated, as they have to match the files on the DMS.
+
+use deunicode::deunicode_char;
+
+use log::warn;
+
+use serde::{Deserialize, Serialize};
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// The replacement for a character which can be neither encoded nor transliterated.
+const REPLACEMENT: &str = "?";
+
+/// The characters encoded as 0x80 through 0x9F in Windows-1252. Undefined positions map to the C1
+/// control character of the same value, as in the WHATWG encoding standard.
+static CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum OutputEncoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "latin-1")]
+    Latin1,
+    #[serde(rename = "cp1252")]
+    Cp1252,
+}
+
+impl OutputEncoding {
+    /// Encode a single character, if it can be represented in this encoding.
+    fn encode_char(self, c: char, dest: &mut Vec<u8>) -> bool {
+        match self {
+            OutputEncoding::Utf8 => {
+                dest.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                true
+            }
+            OutputEncoding::Latin1 if (c as u32) < 0x100 => {
+                dest.push(c as u8);
+                true
+            }
+            OutputEncoding::Latin1 => false,
+            OutputEncoding::Cp1252 => {
+                let byte = match c as u32 {
+                    0x80..=0x9F => None,
+                    value if value < 0x100 => Some(value as u8),
+                    _ => CP1252_HIGH
+                        .iter()
+                        .position(|&h| h == c)
+                        .map(|i| 0x80 + i as u8),
+                };
+
+                byte.map(|b| dest.push(b)).is_some()
+            }
+        }
+    }
+
+    /// Encode text, transliterating any characters this encoding can't represent.
+    ///
+    /// Returns the encoded bytes, along with the text as converted if the conversion was lossy.
+    pub fn encode(self, text: &str) -> (Vec<u8>, Option<String>) {
+        let mut encoded = Vec::with_capacity(text.len());
+        let mut converted: Option<String> = None;
+
+        for (i, c) in text.char_indices() {
+            if self.encode_char(c, &mut encoded) {
+                if let Some(ref mut converted) = converted {
+                    converted.push(c);
+                }
+
+                continue;
+            }
+
+            let replacement = match deunicode_char(c) {
+                Some(ascii) if !ascii.is_empty() => ascii,
+                _ => REPLACEMENT,
+            };
+
+            // transliterations are plain ASCII, which every supported encoding represents as-is
+            encoded.extend_from_slice(replacement.as_bytes());
+            converted
+                .get_or_insert_with(|| text[..i].to_string())
+                .push_str(replacement);
+        }
+
+        (encoded, converted)
+    }
+
+    /// Encode text only if every character can be represented in this encoding.
+    pub fn encode_exact(self, text: &str) -> Option<Vec<u8>> {
+        let mut encoded = Vec::with_capacity(text.len());
+
+        if text.chars().all(|c| self.encode_char(c, &mut encoded)) {
+            Some(encoded)
+        } else {
+            None
+        }
+    }
+
+    /// Encode the location of a file, which is never transliterated as it has to match the file. A
+    /// location which can't be represented in this encoding is left as UTF-8, unchanged.
+    pub fn encode_location(self, location: &str) -> Vec<u8> {
+        self.encode_exact(location).unwrap_or_else(|| {
+            warn!(
+                "Unable to represent location {:?} in {}, wrote it unchanged",
+                location, self
+            );
+            location.as_bytes().to_vec()
+        })
+    }
+
+    /// Decode bytes written in this encoding.
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            OutputEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            OutputEncoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+            OutputEncoding::Cp1252 => bytes
+                .iter()
+                .map(|&b| match b {
+                    0x80..=0x9F => CP1252_HIGH[(b - 0x80) as usize],
+                    _ => b as char,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for OutputEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OutputEncoding::Utf8 => write!(f, "utf-8"),
+            OutputEncoding::Latin1 => write!(f, "latin-1"),
+            OutputEncoding::Cp1252 => write!(f, "cp1252"),
+        }
+    }
+}
+
+impl FromStr for OutputEncoding {
+    type Err = UnknownEncodingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(OutputEncoding::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Ok(OutputEncoding::Latin1),
+            "cp1252" | "windows-1252" => Ok(OutputEncoding::Cp1252),
+            _ => Err(UnknownEncodingError {
+                name: s.to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownEncodingError {
+    name: String,
+}
+
+impl Error for UnknownEncodingError {
+    fn description(&self) -> &str {
+        "Unknown output encoding."
+    }
+}
+
+impl fmt::Display for UnknownEncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown output encoding {:?}, expected utf-8, latin-1, or cp1252",
+            self.name
+        )
+    }
+}
+
+/// A field which couldn't be represented exactly in the output encoding.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LossyConversion {
+    /// The file name of the database containing the field.
+    pub database: String,
+    /// The byte offset of the row containing the field.
+    pub offset: u32,
+    pub original: String,
+    pub converted: String,
+}
+
+/// Every lossy conversion made while encoding a set of databases.
+#[derive(Debug, Default, Serialize)]
+pub struct EncodingReport {
+    pub encoding: OutputEncoding,
+    pub conversions: Vec<LossyConversion>,
+}
+
+impl EncodingReport {
+    pub fn is_lossless(&self) -> bool {
+        self.conversions.is_empty()
+    }
+}
+
+/// Encodes database rows, keeping track of every lossy conversion.
+pub struct Encoder {
+    report: EncodingReport,
+}
+
+impl Encoder {
+    pub fn new(encoding: OutputEncoding) -> Self {
+        Encoder {
+            report: EncodingReport {
+                encoding,
+                conversions: Vec::new(),
+            },
+        }
+    }
+
+    pub fn encoding(&self) -> OutputEncoding {
+        self.report.encoding
+    }
+
+    /// Encode a tab-separated row which will be written at the given offset of a database,
+    /// recording each field which couldn't be encoded exactly.
+    pub fn encode_row(&mut self, database: &str, offset: u32, row: &str) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(row.len());
+        self.encode_fields(database, offset, row.split('\t'), &mut encoded);
+        encoded
+    }
+
+    /// Encode a tab-separated row whose first field is the location of a file on the DMS, which is
+    /// encoded with `OutputEncoding::encode_location`.
+    pub fn encode_location_row(&mut self, database: &str, offset: u32, row: &str) -> Vec<u8> {
+        let mut fields = row.split('\t');
+        let location = fields.next().unwrap_or_default();
+
+        let mut encoded = self.report.encoding.encode_location(location);
+
+        if row.contains('\t') {
+            encoded.push(b'\t');
+            self.encode_fields(database, offset, fields, &mut encoded);
+        }
+
+        encoded
+    }
+
+    fn encode_fields<'a, I: Iterator<Item = &'a str>>(
+        &mut self,
+        database: &str,
+        offset: u32,
+        fields: I,
+        encoded: &mut Vec<u8>,
+    ) {
+        for (i, field) in fields.enumerate() {
+            if i > 0 {
+                encoded.push(b'\t');
+            }
+
+            let (bytes, converted) = self.report.encoding.encode(field);
+            encoded.extend_from_slice(&bytes);
+
+            if let Some(converted) = converted {
+                self.report.conversions.push(LossyConversion {
+                    database: database.to_string(),
+                    offset,
+                    original: field.to_string(),
+                    converted,
+                });
+            }
+        }
+    }
+
+    pub fn report(&self) -> &EncodingReport {
+        &self.report
+    }
+
+    pub fn into_report(self) -> EncodingReport {
+        self.report
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Encoder::new(OutputEncoding::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_utf8() {
+        assert_eq!(
+            ("Mêlée".as_bytes().to_vec(), None),
+            OutputEncoding::Utf8.encode("Mêlée")
+        );
+    }
+
+    #[test]
+    fn test_encode_latin1() {
+        assert_eq!(
+            (b"M\xEAl\xE9e".to_vec(), None),
+            OutputEncoding::Latin1.encode("Mêlée")
+        );
+
+        // the euro sign and curly quotes are outside of latin-1 and get transliterated
+        assert_eq!(
+            (b"EUR5 'Hits'".to_vec(), Some("EUR5 'Hits'".to_string())),
+            OutputEncoding::Latin1.encode("€5 \u{2018}Hits\u{2019}")
+        );
+    }
+
+    #[test]
+    fn test_encode_cp1252() {
+        assert_eq!(
+            (b"\x805 \x91Hits\x92 M\xEAl\xE9e".to_vec(), None),
+            OutputEncoding::Cp1252.encode("€5 \u{2018}Hits\u{2019} Mêlée")
+        );
+
+        // only the characters outside of cp1252 are transliterated
+        assert_eq!(
+            (b"L\xF3dz".to_vec(), Some("Lódz".to_string())),
+            OutputEncoding::Cp1252.encode("Łódź")
+        );
+    }
+
+    #[test]
+    fn test_encode_untransliterable() {
+        // private use characters have no transliteration at all
+        assert_eq!(
+            (b"?".to_vec(), Some("?".to_string())),
+            OutputEncoding::Latin1.encode("\u{E000}")
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        for encoding in &[
+            OutputEncoding::Utf8,
+            OutputEncoding::Latin1,
+            OutputEncoding::Cp1252,
+        ] {
+            let (bytes, _) = encoding.encode("Mêlée");
+            assert_eq!("Mêlée", encoding.decode(&bytes));
+        }
+
+        assert_eq!("€‘’", OutputEncoding::Cp1252.decode(b"\x80\x91\x92"));
+    }
+
+    #[test]
+    fn test_encoder_report() {
+        let mut encoder = Encoder::new(OutputEncoding::Latin1);
+
+        encoder.encode_row("artists.csv", 0, "0\tMêlée");
+        assert!(encoder.report().is_lossless());
+
+        let row = encoder.encode_row("artists.csv", 9, "1\t坂本龍一");
+        assert!(row.starts_with(b"1\t"));
+
+        assert_eq!(
+            vec![LossyConversion {
+                database: "artists.csv".to_string(),
+                offset: 9,
+                original: "坂本龍一".to_string(),
+                converted: String::from_utf8(row[2..].to_vec()).unwrap(),
+            }],
+            encoder.into_report().conversions
+        );
+    }
+
+    #[test]
+    fn test_encoder_keeps_locations() {
+        let mut encoder = Encoder::new(OutputEncoding::Latin1);
+
+        let row = encoder.encode_location_row("tracks.csv", 0, "/dos/data/Mêlée.mp3\tMêlée");
+        assert_eq!(b"/dos/data/M\xEAl\xE9e.mp3\tM\xEAl\xE9e".to_vec(), row);
+
+        // a location outside of the encoding is written as-is, only the other fields are converted
+        let row = encoder.encode_location_row("tracks.csv", 30, "/dos/data/坂本龍一.mp3\t€5");
+        assert_eq!(
+            ["/dos/data/坂本龍一.mp3".as_bytes(), b"\tEUR5"].concat(),
+            row
+        );
+
+        let conversions = encoder.into_report().conversions;
+        assert_eq!(1, conversions.len());
+        assert_eq!("€5", conversions[0].original);
+    }
+
+    #[test]
+    fn test_parse_encoding() {
+        assert_eq!(OutputEncoding::Utf8, "UTF-8".parse().unwrap());
+        assert_eq!(OutputEncoding::Latin1, "iso-8859-1".parse().unwrap());
+        assert_eq!(OutputEncoding::Cp1252, "windows-1252".parse().unwrap());
+        assert!("ebcdic".parse::<OutputEncoding>().is_err());
+    }
+}
@@ -0,0 +1,435 @@
+//! Parsing of DMS databases, either from a mounted DMS or from a copy of its database directory.
+//!
+//! The reader only checks that each file is well-formed on its own; whether the files agree with
+//! one another is left to the caller.
+
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::data::encoding::OutputEncoding;
+use crate::data::{GroupFiles, ALBUMS, ARTISTS, GENRES, TRACKS_DB, TRACKS_IDX};
+
+/// The number of tab-separated fields in each row of the tracks database.
+pub(crate) const TRACK_FIELDS: usize = 9;
+
+/// A row of the tracks database.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrackRow {
+    /// The byte offset of the row in the tracks database.
+    pub offset: u32,
+    /// The location of the file relative to the DMS root, ie `/dos/data/...`.
+    pub location: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub genre: String,
+    /// The duration of the track in whole seconds.
+    pub duration: u64,
+    pub track_number: u16,
+    pub artwork: String,
+}
+
+/// A row of the artists, albums, or genres database.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GroupRow {
+    /// The byte offset of the row in its database.
+    pub offset: u32,
+    pub id: u32,
+    pub name: String,
+    /// The artist of an album; always `None` for artists and genres.
+    pub artist: Option<String>,
+}
+
+/// The contents of an artists, albums, or genres database along with its indexes.
+#[derive(Debug, Serialize)]
+pub struct GroupDbContents {
+    pub rows: Vec<GroupRow>,
+    /// The offsets into the database read from its `.idx` file.
+    pub index: Vec<u32>,
+    /// The offsets into the tracks database for each group, by group ID.
+    pub groups: BTreeMap<u32, Vec<u32>>,
+}
+
+/// The parsed contents of a DMS database directory.
+///
+/// The artists, albums, and genres databases are `None` when absent, as older databases only
+/// contain the tracks database.
+#[derive(Debug, Serialize)]
+pub struct DatabaseContents {
+    pub tracks: Vec<TrackRow>,
+    /// The offsets into the tracks database read from `tracks.idx`.
+    pub tracks_index: Vec<u32>,
+    pub artists: Option<GroupDbContents>,
+    pub albums: Option<GroupDbContents>,
+    pub genres: Option<GroupDbContents>,
+}
+
+impl DatabaseContents {
+    /// Read every database and index from the given directory, decoding text in the given
+    /// encoding.
+    pub fn read<P: AsRef<Path>>(dir: P, encoding: OutputEncoding) -> Result<Self, DbReadError> {
+        let dir = dir.as_ref();
+        let groups = |files, with_artist| read_group_db(dir, files, with_artist, encoding);
+
+        Ok(DatabaseContents {
+            tracks: parse_tracks(&read_file(&dir.join(TRACKS_DB))?, encoding)
+                .map_err(|e| e.in_file(TRACKS_DB))?,
+            tracks_index: read_index(&dir.join(TRACKS_IDX))?,
+            artists: groups(ARTISTS, false)?,
+            albums: groups(ALBUMS, true)?,
+            genres: groups(GENRES, false)?,
+        })
+    }
+
+    /// Find the track whose row starts at the given offset.
+    pub fn track_at(&self, offset: u32) -> Option<&TrackRow> {
+        self.tracks
+            .binary_search_by_key(&offset, |t| t.offset)
+            .ok()
+            .map(|i| &self.tracks[i])
+    }
+}
+
+#[derive(Debug)]
+pub enum DbReadError {
+    IOError { path: PathBuf, err: io::Error },
+    Malformed { file: String, reason: String },
+}
+
+impl DbReadError {
+    fn malformed<S: Into<String>>(reason: S) -> Self {
+        DbReadError::Malformed {
+            file: String::new(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Attribute a malformed-data error to the given file.
+    fn in_file(self, name: &str) -> Self {
+        match self {
+            DbReadError::Malformed { reason, .. } => DbReadError::Malformed {
+                file: name.to_string(),
+                reason,
+            },
+            e => e,
+        }
+    }
+}
+
+impl Error for DbReadError {
+    fn description(&self) -> &str {
+        "Unable to read DMS database."
+    }
+}
+
+impl fmt::Display for DbReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DbReadError::IOError { ref path, ref err } => {
+                write!(f, "Unable to read {}: {}", path.display(), err)
+            }
+            DbReadError::Malformed {
+                ref file,
+                ref reason,
+            } => write!(f, "Malformed DMS database {}: {}", file, reason),
+        }
+    }
+}
+
+/// Read an index file, which is a little-endian u32 count followed by that many little-endian u32
+/// offsets.
+pub fn read_index(path: &Path) -> Result<Vec<u32>, DbReadError> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    parse_index(&read_file(path)?).map_err(|e| e.in_file(&name))
+}
+
+/// Parse the contents of an index file.
+pub fn parse_index(bytes: &[u8]) -> Result<Vec<u32>, DbReadError> {
+    let (count, offsets) = split_index(bytes).ok_or_else(|| {
+        DbReadError::malformed(format!(
+            "index length {} is not a whole number of offsets",
+            bytes.len()
+        ))
+    })?;
+
+    if count as usize != offsets.len() {
+        return Err(DbReadError::malformed(format!(
+            "index declares {} offsets but contains {}",
+            count,
+            offsets.len()
+        )));
+    }
+
+    Ok(offsets)
+}
+
+/// Split the contents of an index file into the count it declares and the offsets it contains,
+/// without checking that they agree. Returns `None` if it isn't a whole number of offsets.
+pub fn split_index(bytes: &[u8]) -> Option<(u32, Vec<u32>)> {
+    if bytes.len() < 4 || !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut words = bytes
+        .chunks(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]));
+
+    let count = words.next().unwrap();
+    Some((count, words.collect()))
+}
+
+/// Parse the contents of the tracks database.
+pub fn parse_tracks(bytes: &[u8], encoding: OutputEncoding) -> Result<Vec<TrackRow>, DbReadError> {
+    rows(bytes, encoding)
+        .map(|(offset, line)| {
+            let fields: Vec<&str> = line.split('\t').collect();
+
+            if fields.len() != TRACK_FIELDS {
+                return Err(DbReadError::malformed(format!(
+                    "track at offset {} has {} fields, expected {}",
+                    offset,
+                    fields.len(),
+                    TRACK_FIELDS
+                )));
+            }
+
+            Ok(TrackRow {
+                offset,
+                location: fields[0].to_string(),
+                title: fields[1].to_string(),
+                artist: fields[2].to_string(),
+                album: fields[3].to_string(),
+                genre: fields[4].to_string(),
+                duration: parse_number(fields[5], "duration", offset)?,
+                track_number: parse_number(fields[7], "track number", offset)?,
+                artwork: fields[8].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Parse the contents of an artists, albums, or genres database. Album rows carry the album's
+/// artist as a third field.
+pub fn parse_groups(
+    bytes: &[u8],
+    with_artist: bool,
+    encoding: OutputEncoding,
+) -> Result<Vec<GroupRow>, DbReadError> {
+    let expected = if with_artist { 3 } else { 2 };
+
+    rows(bytes, encoding)
+        .map(|(offset, line)| {
+            let fields: Vec<&str> = line.split('\t').collect();
+
+            if fields.len() != expected {
+                return Err(DbReadError::malformed(format!(
+                    "row at offset {} has {} fields, expected {}",
+                    offset,
+                    fields.len(),
+                    expected
+                )));
+            }
+
+            Ok(GroupRow {
+                offset,
+                id: parse_number(fields[0], "ID", offset)?,
+                name: fields[1].to_string(),
+                artist: fields.get(2).map(|a| a.to_string()),
+            })
+        })
+        .collect()
+}
+
+fn read_group_db(
+    dir: &Path,
+    files: GroupFiles,
+    with_artist: bool,
+    encoding: OutputEncoding,
+) -> Result<Option<GroupDbContents>, DbReadError> {
+    if !dir.join(files.db).is_file() {
+        return Ok(None);
+    }
+
+    let rows = parse_groups(&read_file(&dir.join(files.db))?, with_artist, encoding)
+        .map_err(|e| e.in_file(files.db))?;
+    let mut groups = BTreeMap::new();
+
+    for row in &rows {
+        let path = dir.join(files.dir).join(format!("{}.idx", row.id));
+
+        if path.is_file() {
+            groups.insert(row.id, read_index(&path)?);
+        }
+    }
+
+    Ok(Some(GroupDbContents {
+        rows,
+        index: read_index(&dir.join(files.idx))?,
+        groups,
+    }))
+}
+
+/// Split a database into its rows, yielding the byte offset of each row along with its decoded
+/// text.
+pub(crate) fn rows(
+    bytes: &[u8],
+    encoding: OutputEncoding,
+) -> impl Iterator<Item = (u32, String)> + '_ {
+    let mut offset = 0;
+
+    bytes.split(|&b| b == b'\n').filter_map(move |line| {
+        let start = offset;
+        offset += line.len() + 1;
+
+        if line.is_empty() {
+            None
+        } else {
+            Some((start as u32, encoding.decode(line)))
+        }
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(
+    value: &str,
+    field: &str,
+    offset: u32,
+) -> Result<T, DbReadError> {
+    value.parse().map_err(|_| {
+        DbReadError::malformed(format!(
+            "invalid {} {:?} in row at offset {}",
+            field, value, offset
+        ))
+    })
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, DbReadError> {
+    fs::read(path).map_err(|err| DbReadError::IOError {
+        path: path.to_path_buf(),
+        err,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::data::encoding::Encoder;
+    use crate::data::test::load_fixtures;
+    use crate::data::{Database, TracksDbIndex};
+    use crate::utils::StringPool;
+
+    #[test]
+    fn test_round_trip() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::build(&tracks, OutputEncoding::Utf8).unwrap();
+
+        db.write(dir.path()).unwrap();
+
+        let contents = DatabaseContents::read(dir.path(), OutputEncoding::Utf8).unwrap();
+
+        assert_eq!(db.tracks.index.track_offsets, contents.tracks_index);
+        assert_eq!(tracks.len(), contents.tracks.len());
+
+        for (track, row) in tracks.iter().zip(&contents.tracks) {
+            assert_eq!(track.title, row.title);
+            assert_eq!(&*track.artist, row.artist);
+            assert_eq!(track.duration, row.duration);
+            assert!(row.location.starts_with("/dos/data/flac/"));
+        }
+
+        let albums = contents.albums.as_ref().unwrap();
+        assert_eq!(db.albums.group_offsets, albums.index);
+        assert_eq!(Some("Unknown Artist"), albums.rows[3].artist.as_deref());
+
+        let artists = contents.artists.as_ref().unwrap();
+        assert!(artists.rows.iter().all(|r| r.artist.is_none()));
+
+        for artist in &db.artists.groups {
+            assert_eq!(artist.track_offsets, artists.groups[&artist.id]);
+
+            for offset in &artists.groups[&artist.id] {
+                let track = contents.track_at(*offset).unwrap();
+                assert_eq!(artists.rows[artist.id as usize].name, track.artist);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tracks_only() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let dir = tempfile::tempdir().unwrap();
+
+        crate::data::TracksDb::build(&tracks, &mut Encoder::default())
+            .unwrap()
+            .write(dir.path())
+            .unwrap();
+
+        let contents = DatabaseContents::read(dir.path(), OutputEncoding::Utf8).unwrap();
+
+        assert_eq!(tracks.len(), contents.tracks.len());
+        assert!(contents.artists.is_none());
+        assert!(contents.albums.is_none());
+        assert!(contents.genres.is_none());
+    }
+
+    #[test]
+    fn test_parse_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tracks.idx");
+
+        TracksDbIndex {
+            track_offsets: vec![0, 17, 1024],
+        }
+        .write(&path)
+        .unwrap();
+
+        assert_eq!(vec![0, 17, 1024], read_index(&path).unwrap());
+
+        // truncated and inconsistent indexes should be rejected
+        assert!(parse_index(&[1, 0, 0]).is_err());
+        assert!(parse_index(&[2, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert_eq!(Vec::<u32>::new(), parse_index(&[0, 0, 0, 0]).unwrap());
+    }
+
+    #[test]
+    fn test_parse_malformed_tracks() {
+        let err = parse_tracks(b"/dos/data/a.mp3\tTitle\n", OutputEncoding::Utf8).unwrap_err();
+        assert!(err.to_string().contains("2 fields"));
+
+        let err = parse_tracks(
+            b"/dos/data/a.mp3\tT\tA\tB\tG\tlong\t\t1\tNotFound.jpg\n",
+            OutputEncoding::Utf8,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid duration"));
+    }
+
+    #[test]
+    fn test_round_trip_latin1() {
+        let pool = StringPool::new();
+        let mut tracks = load_fixtures(&pool);
+        let dir = tempfile::tempdir().unwrap();
+
+        tracks[0].artist = pool.get("Mêlée");
+        Database::build(&tracks, OutputEncoding::Latin1)
+            .unwrap()
+            .write(dir.path())
+            .unwrap();
+
+        let contents = DatabaseContents::read(dir.path(), OutputEncoding::Latin1).unwrap();
+        assert_eq!("Mêlée", contents.tracks[0].artist);
+    }
+}
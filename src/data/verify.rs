@@ -0,0 +1,394 @@
+//! Consistency checks for the databases on a DMS, to track down why the head unit shows garbled
+//! entries.
+//!
+//! Unlike the reader, the verifier doesn't stop at the first problem: it works on the raw bytes of
+//! each file and collects every issue it finds into a report.
+
+use serde::Serialize;
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::data::encoding::OutputEncoding;
+use crate::data::reader::{rows, split_index, TRACK_FIELDS};
+use crate::data::{ALBUMS, ARTISTS, GENRES, TRACKS_DB, TRACKS_IDX};
+use crate::dms::DMS_DATA_ROOT;
+
+/// A single problem found in a DMS database.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    /// A database or index file which should exist does not.
+    MissingFile { file: String },
+    /// A database or index file exists but could not be read.
+    UnreadableFile { file: String, error: String },
+    /// An index whose length isn't a count followed by a whole number of offsets.
+    TruncatedIndex { file: String, length: usize },
+    /// An index whose leading count doesn't match the number of offsets it contains.
+    CountMismatch {
+        file: String,
+        declared: u32,
+        actual: usize,
+    },
+    /// An index entry which doesn't point at the start of a row in the database it indexes.
+    MisalignedOffset {
+        file: String,
+        position: usize,
+        offset: u32,
+    },
+    /// A row of the tracks database with the wrong number of tab-separated fields.
+    WrongFieldCount {
+        file: String,
+        offset: u32,
+        fields: usize,
+        expected: usize,
+    },
+    /// A track whose media file doesn't exist on the DMS.
+    MissingMedia { offset: u32, location: String },
+}
+
+/// The result of verifying a DMS database directory.
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub database: PathBuf,
+    pub media_root: PathBuf,
+    pub tracks: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Verify the databases in the given directory, resolving `/dos/data/...` paths against the given
+/// media root, which is usually the DMS mount point. The databases are decoded in the given
+/// encoding.
+pub fn verify<P: AsRef<Path>, Q: AsRef<Path>>(
+    database: P,
+    media_root: Q,
+    encoding: OutputEncoding,
+) -> VerifyReport {
+    let (database, media_root) = (database.as_ref(), media_root.as_ref());
+    let mut report = VerifyReport {
+        database: database.to_path_buf(),
+        media_root: media_root.to_path_buf(),
+        ..Default::default()
+    };
+
+    let tracks = match read(database, TRACKS_DB, &mut report.issues) {
+        Some(tracks) => tracks,
+        None => return report,
+    };
+
+    // every index into the tracks database may only point at the start of a row
+    let mut row_starts = HashSet::new();
+
+    for (offset, line) in rows(&tracks, encoding) {
+        row_starts.insert(offset);
+        report.tracks += 1;
+
+        let fields: Vec<&str> = line.split('\t').collect();
+
+        if fields.len() != TRACK_FIELDS {
+            report.issues.push(Issue::WrongFieldCount {
+                file: TRACKS_DB.to_string(),
+                offset,
+                fields: fields.len(),
+                expected: TRACK_FIELDS,
+            });
+        }
+
+        let location = fields[0];
+
+        if !media_exists(media_root, location) {
+            report.issues.push(Issue::MissingMedia {
+                offset,
+                location: location.to_string(),
+            });
+        }
+    }
+
+    if let Some(offsets) = read_index(database, TRACKS_IDX, &mut report.issues) {
+        check_offsets(TRACKS_IDX, &offsets, &row_starts, &mut report.issues);
+    }
+
+    for files in &[ARTISTS, ALBUMS, GENRES] {
+        if !database.join(files.db).is_file() {
+            continue;
+        }
+
+        // the index of a group database points into the group database itself
+        if let Some(groups) = read(database, files.db, &mut report.issues) {
+            let group_starts: HashSet<u32> =
+                rows(&groups, encoding).map(|(offset, _)| offset).collect();
+
+            if let Some(offsets) = read_index(database, files.idx, &mut report.issues) {
+                check_offsets(files.idx, &offsets, &group_starts, &mut report.issues);
+            }
+        }
+
+        // while the per-group indexes point into the tracks database
+        for name in group_indexes(&database.join(files.dir)) {
+            let file = Path::new(files.dir).join(&name);
+
+            if let Some(offsets) = read_index(database, &file, &mut report.issues) {
+                let file = file.to_string_lossy();
+                check_offsets(&file, &offsets, &row_starts, &mut report.issues);
+            }
+        }
+    }
+
+    report
+}
+
+fn check_offsets(file: &str, offsets: &[u32], row_starts: &HashSet<u32>, issues: &mut Vec<Issue>) {
+    for (position, &offset) in offsets.iter().enumerate() {
+        if !row_starts.contains(&offset) {
+            issues.push(Issue::MisalignedOffset {
+                file: file.to_string(),
+                position,
+                offset,
+            });
+        }
+    }
+}
+
+/// Check whether the media file at the given DMS location exists under the media root.
+fn media_exists(media_root: &Path, location: &str) -> bool {
+    match location
+        .strip_prefix(DMS_DATA_ROOT)
+        .and_then(|location| location.strip_prefix('/'))
+    {
+        Some(relative) if !relative.is_empty() => media_root.join(relative).is_file(),
+        _ => false,
+    }
+}
+
+/// List the names of the per-group index files in the given directory, in a stable order.
+fn group_indexes(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .filter(|n| n.ends_with(".idx"))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    names.sort();
+    names
+}
+
+fn read<P: AsRef<Path>>(database: &Path, file: P, issues: &mut Vec<Issue>) -> Option<Vec<u8>> {
+    let file = file.as_ref();
+
+    match fs::read(database.join(file)) {
+        Ok(bytes) => Some(bytes),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            issues.push(Issue::MissingFile {
+                file: file.to_string_lossy().into_owned(),
+            });
+            None
+        }
+        Err(e) => {
+            issues.push(Issue::UnreadableFile {
+                file: file.to_string_lossy().into_owned(),
+                error: e.to_string(),
+            });
+            None
+        }
+    }
+}
+
+/// Read the offsets from an index, reporting a truncated index or a count mismatch. Offsets are
+/// still returned after a count mismatch so that they can be checked too.
+fn read_index<P: AsRef<Path>>(
+    database: &Path,
+    file: P,
+    issues: &mut Vec<Issue>,
+) -> Option<Vec<u32>> {
+    let file = file.as_ref();
+    let bytes = read(database, file, issues)?;
+
+    let (declared, offsets) = match split_index(&bytes) {
+        Some(index) => index,
+        None => {
+            issues.push(Issue::TruncatedIndex {
+                file: file.to_string_lossy().into_owned(),
+                length: bytes.len(),
+            });
+            return None;
+        }
+    };
+
+    if declared as usize != offsets.len() {
+        issues.push(Issue::CountMismatch {
+            file: file.to_string_lossy().into_owned(),
+            declared,
+            actual: offsets.len(),
+        });
+    }
+
+    Some(offsets)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::data::test::load_fixtures;
+    use crate::data::Database;
+    use crate::utils::StringPool;
+
+    use tempfile::TempDir;
+
+    /// Write the databases for the fixtures into a fresh directory; the fixtures themselves live
+    /// under `test/fixtures`, which serves as the media root.
+    fn write_fixture_database() -> TempDir {
+        let pool = StringPool::new();
+        let dir = tempfile::tempdir().unwrap();
+
+        Database::build(&load_fixtures(&pool), OutputEncoding::Utf8)
+            .unwrap()
+            .write(dir.path())
+            .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_verify_ok() {
+        let dir = write_fixture_database();
+        let report = verify(dir.path(), "test/fixtures", OutputEncoding::Utf8);
+
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(7, report.tracks);
+    }
+
+    #[test]
+    fn test_verify_missing_media() {
+        let dir = write_fixture_database();
+        let report = verify(dir.path(), dir.path(), OutputEncoding::Utf8);
+
+        assert_eq!(7, report.issues.len());
+        assert!(report
+            .issues
+            .iter()
+            .all(|i| matches!(i, Issue::MissingMedia { .. })));
+    }
+
+    #[test]
+    fn test_verify_bad_index() {
+        let dir = write_fixture_database();
+        let idx = dir.path().join(TRACKS_IDX);
+        let mut bytes = fs::read(&idx).unwrap();
+
+        // claim an extra offset, and point the second offset into the middle of a row
+        bytes[0] += 1;
+        bytes[8] += 1;
+        fs::write(&idx, &bytes).unwrap();
+
+        let report = verify(dir.path(), "test/fixtures", OutputEncoding::Utf8);
+
+        assert_eq!(
+            vec![
+                Issue::CountMismatch {
+                    file: TRACKS_IDX.to_string(),
+                    declared: 8,
+                    actual: 7,
+                },
+                Issue::MisalignedOffset {
+                    file: TRACKS_IDX.to_string(),
+                    position: 1,
+                    offset: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+                },
+            ],
+            report.issues
+        );
+
+        fs::write(&idx, &bytes[..6]).unwrap();
+
+        assert_eq!(
+            vec![Issue::TruncatedIndex {
+                file: TRACKS_IDX.to_string(),
+                length: 6,
+            }],
+            verify(dir.path(), "test/fixtures", OutputEncoding::Utf8).issues
+        );
+    }
+
+    #[test]
+    fn test_verify_bad_group_index() {
+        let dir = write_fixture_database();
+        let idx = dir.path().join(ARTISTS.idx);
+        let mut bytes = fs::read(&idx).unwrap();
+
+        // point the second artist into the middle of the first artist's row
+        bytes[8] -= 1;
+        fs::write(&idx, &bytes).unwrap();
+
+        let report = verify(dir.path(), "test/fixtures", OutputEncoding::Utf8);
+
+        assert_eq!(
+            vec![Issue::MisalignedOffset {
+                file: ARTISTS.idx.to_string(),
+                position: 1,
+                offset: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            }],
+            report.issues
+        );
+    }
+
+    #[test]
+    fn test_verify_wrong_field_count() {
+        let dir = write_fixture_database();
+        let db = dir.path().join(TRACKS_DB);
+        let mut contents = fs::read(&db).unwrap();
+
+        // a stray tab in the last row's title adds a field without moving any row starts
+        let last_row = contents[..contents.len() - 1]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .unwrap()
+            + 1;
+        let title = last_row
+            + contents[last_row..]
+                .iter()
+                .position(|&b| b == b'\t')
+                .unwrap();
+        contents.insert(title + 1, b'\t');
+        fs::write(&db, &contents).unwrap();
+
+        let report = verify(dir.path(), "test/fixtures", OutputEncoding::Utf8);
+
+        assert_eq!(
+            vec![Issue::WrongFieldCount {
+                file: TRACKS_DB.to_string(),
+                offset: last_row as u32,
+                fields: 10,
+                expected: TRACK_FIELDS,
+            }],
+            report.issues
+        );
+    }
+
+    #[test]
+    fn test_verify_missing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = verify(dir.path(), dir.path(), OutputEncoding::Utf8);
+
+        assert_eq!(
+            vec![Issue::MissingFile {
+                file: TRACKS_DB.to_string()
+            }],
+            report.issues
+        );
+        assert!(serde_json::to_string(&report)
+            .unwrap()
+            .contains(r#""kind":"missing_file""#));
+    }
+}
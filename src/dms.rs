@@ -7,6 +7,9 @@ use std::io;
 use std::io::BufRead;
 use std::path::{Path, PathBuf};
 
+/// The directory on the head unit under which the DMS media library is mounted.
+pub static DMS_DATA_ROOT: &str = "/dos/data";
+
 lazy_static! {
     static ref PROC_MOUNT_LINE: Regex =
         Regex::new(r"(?i)^(?P<device>[^\s]+)\s+(?P<mount>[^\s]+)").unwrap();
@@ -35,11 +38,7 @@ pub fn get_dms_mount_point() -> Option<PathBuf> {
     let f = fs::File::open(Path::new("/proc/mounts")).ok()?;
     let buffer = io::BufReader::new(f);
 
-    for line in buffer
-        .lines()
-        .filter_map(|s| s.ok())
-        .collect::<Vec<String>>()
-    {
+    for line in buffer.lines().map_while(Result::ok) {
         if line.starts_with(device.to_str()?) {
             return PROC_MOUNT_LINE
                 .captures(&line)?
@@ -50,3 +49,9 @@ pub fn get_dms_mount_point() -> Option<PathBuf> {
 
     None
 }
+
+/// Get the location of a media file as seen by the head unit, given the base directory of the
+/// library holding it.
+pub fn dms_location(path: &Path, base: &Path) -> PathBuf {
+    Path::new(DMS_DATA_ROOT).join(path.strip_prefix(base).unwrap())
+}
@@ -16,8 +16,8 @@ pub struct MediaFile {
     base: Arc<PathBuf>,
 }
 
-static SPACE_CHARS: &'static [char] = &['\t'];
-static DELETE_CHARS: &'static [char] = &[':'];
+static SPACE_CHARS: &[char] = &['\t'];
+static DELETE_CHARS: &[char] = &[':'];
 
 impl MediaFile {
     pub fn new<P1, P2>(path: P1, base: P2) -> MediaFile
@@ -38,7 +38,7 @@ impl MediaFile {
             .to_string_lossy()
             .chars()
             //  delete illegal characters
-            .filter(|c| !DELETE_CHARS.contains(&c))
+            .filter(|c| !DELETE_CHARS.contains(c))
             // replace certain characters with a single space character
             .map(|c| if SPACE_CHARS.contains(&c) { ' ' } else { c })
             //  bounce down to lowercase
@@ -127,11 +127,7 @@ pub fn is_allowed_dms_path(base: &Path, entry: &DirEntry) -> bool {
         .flat_map(|c| c.case_fold())
         .collect::<String>();
 
-    match intersection.as_str() {
-        "profiles" => false,
-        "tts" => false,
-        _ => true,
-    }
+    !matches!(intersection.as_str(), "profiles" | "tts")
 }
 
 #[test]
@@ -6,5 +6,6 @@ pub mod dms;
 pub mod fsync;
 pub mod library;
 pub mod metadata;
+pub mod playlist;
 pub mod sync;
 pub mod utils;
@@ -1,6 +1,9 @@
+use log::warn;
+
 use crate::dms;
 use crate::utils;
 
+use std::borrow::Cow;
 use std::cmp::Eq;
 use std::cmp::Ord;
 use std::cmp::Ordering;
@@ -9,6 +12,7 @@ use std::cmp::PartialOrd;
 use std::collections::BTreeSet;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::io;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -31,10 +35,10 @@ pub struct LibraryFile {
 impl LibraryFile {
     pub fn new(path: &Path, base: &Path, source: LibrarySource) -> Self {
         LibraryFile {
-            id: LibraryFile::gen_id(&path, &base),
+            id: LibraryFile::gen_id(path, base),
             path: path.to_path_buf(),
             base: base.to_path_buf(),
-            source: source,
+            source,
         }
     }
 
@@ -48,7 +52,7 @@ impl LibraryFile {
             .to_string_lossy()
             .chars()
             //  delete illegal characters
-            .filter(|c| !utils::FAT32_DELETE_CHARS.contains(&c))
+            .filter(|c| !utils::FAT32_DELETE_CHARS.contains(c))
             // replace certain characters with a single space character
             .map(|c| {
                 if utils::FAT32_HYPHENIZE_CHARS.contains(&c) {
@@ -63,12 +67,30 @@ impl LibraryFile {
             .collect()
     }
 
-    pub fn debase(&self) -> &str {
-        self.path
-            .strip_prefix(&self.base)
-            .unwrap()
-            .to_str()
-            .unwrap()
+    /// Get the location of the file as seen by the head unit.
+    pub fn dms_location(&self) -> PathBuf {
+        dms::dms_location(&self.path, &self.base)
+    }
+
+    /// The path of the file relative to its library root.
+    pub fn relative_path(&self) -> &Path {
+        self.path.strip_prefix(&self.base).unwrap()
+    }
+
+    /// The path of the file relative to its library root, for display and as a key. Names which
+    /// aren't valid UTF-8 are converted lossily.
+    pub fn debase(&self) -> Cow<'_, str> {
+        self.relative_path().to_string_lossy()
+    }
+
+    /// Check whether the file is, or is under, the given unreadable path.
+    pub fn is_under(&self, unreadable: &UnreadablePath) -> bool {
+        unreadable.id.is_empty()
+            || self.id == unreadable.id
+            || self
+                .id
+                .strip_prefix(unreadable.id.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
     }
 }
 
@@ -87,8 +109,10 @@ impl Hash for LibraryFile {
 }
 
 impl Ord for LibraryFile {
+    /// Files are ordered by ID, consistently with equality, so that set operations between the
+    /// local and DMS libraries match files by ID rather than by their differing absolute paths.
     fn cmp(&self, other: &Self) -> Ordering {
-        self.path.cmp(&other.path)
+        self.id.cmp(&other.id)
     }
 }
 
@@ -105,20 +129,120 @@ pub fn get_local_media_library(base: &Path) -> BTreeSet<LibraryFile> {
         .collect()
 }
 
+/// A file or directory under a library root which couldn't be read while listing the library, so
+/// the files under it are unknown.
+#[derive(Debug)]
+pub struct UnreadablePath {
+    pub path: PathBuf,
+    /// The ID the path would have as a library file.
+    pub id: String,
+    pub err: io::Error,
+}
+
+/// Merge the media libraries under several roots. Where the same file exists under more than one
+/// root, the one under the earliest root wins.
+pub fn get_merged_media_library(bases: &[PathBuf]) -> BTreeSet<LibraryFile> {
+    let (library, unreadable) = scan_merged_media_library(bases);
+
+    for path in unreadable {
+        warn!("Unable to read media library: {}", path.err);
+    }
+
+    library
+}
+
+/// Merge the media libraries under several roots like `get_merged_media_library`, also returning
+/// everything under the roots which couldn't be read.
+pub fn scan_merged_media_library(
+    bases: &[PathBuf],
+) -> (BTreeSet<LibraryFile>, Vec<UnreadablePath>) {
+    let mut library = BTreeSet::new();
+    let mut unreadable = Vec::new();
+
+    for base in bases {
+        let (files, errors) = utils::media::walk_media_library(base);
+
+        unreadable.extend(errors.into_iter().map(|err| {
+            let path = err.path().unwrap_or(base).to_path_buf();
+
+            UnreadablePath {
+                id: LibraryFile::gen_id(&path, base),
+                path,
+                err: err.into(),
+            }
+        }));
+
+        for file in files
+            .iter()
+            .map(|p| LibraryFile::new(p, base, LibrarySource::Local))
+        {
+            if library.contains(&file) {
+                warn!(
+                    "Ignoring {} as another library root has the same file",
+                    file.path.display()
+                );
+                continue;
+            }
+
+            library.insert(file);
+        }
+    }
+
+    (library, unreadable)
+}
+
 pub fn get_dms_media_library() -> BTreeSet<LibraryFile> {
     match dms::get_dms_mount_point() {
-        Some(base) => utils::media::get_media_library(&base)
-            .iter()
-            .map(|p| LibraryFile::new(p, &base, LibrarySource::DMS))
-            .collect(),
+        Some(base) => get_dms_media_library_at(&base),
         None => BTreeSet::new(),
     }
 }
 
+/// Get the media library of a DMS, or of a directory standing in for one, at the given path.
+pub fn get_dms_media_library_at(base: &Path) -> BTreeSet<LibraryFile> {
+    utils::media::get_media_library(base)
+        .iter()
+        .map(|p| LibraryFile::new(p, base, LibrarySource::DMS))
+        .collect()
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
 
+    fn library(base: &str, paths: &[&str], source: LibrarySource) -> BTreeSet<LibraryFile> {
+        let base = Path::new(base);
+
+        paths
+            .iter()
+            .map(|p| LibraryFile::new(&base.join(p), base, source))
+            .collect()
+    }
+
+    #[test]
+    fn test_library_difference() {
+        let local = library(
+            "/home/user/Music",
+            &["A/01.mp3", "A/02.mp3", "Z/01.mp3"],
+            LibrarySource::Local,
+        );
+        let dms = library(
+            "/mnt/dms",
+            &["a/01.MP3", "B/03.mp3", "Z/01.mp3"],
+            LibrarySource::DMS,
+        );
+
+        let ids = |files: Vec<&LibraryFile>| files.iter().map(|f| f.id.clone()).collect::<Vec<_>>();
+
+        // files are matched by ID, not by their absolute paths under different bases
+        assert_eq!(vec!["a/02.mp3"], ids(local.difference(&dms).collect()));
+        assert_eq!(vec!["b/03.mp3"], ids(dms.difference(&local).collect()));
+        assert_eq!(
+            dms.get(local.iter().next().unwrap()).map(|f| &f.path),
+            Some(&PathBuf::from("/mnt/dms/a/01.MP3"))
+        );
+    }
+
     #[test]
     fn test_media_file_identity() {
         let base = Path::new("Music");
@@ -164,4 +288,65 @@ pub mod test {
             .id
         );
     }
+
+    #[test]
+    fn test_non_utf8_file_name() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let base = Path::new("/music");
+        let path = base.join(OsStr::from_bytes(b"Artist/Caf\xe9.mp3"));
+        let file = LibraryFile::new(&path, base, LibrarySource::Local);
+
+        assert_eq!("Artist/Caf\u{fffd}.mp3", file.debase());
+        assert_eq!(
+            Path::new(OsStr::from_bytes(b"Artist/Caf\xe9.mp3")),
+            file.relative_path()
+        );
+    }
+
+    #[test]
+    fn test_is_under() {
+        let base = Path::new("/music");
+        let unreadable = |path: &str| UnreadablePath {
+            path: base.join(path),
+            id: LibraryFile::gen_id(&base.join(path), base),
+            err: io::Error::from(io::ErrorKind::PermissionDenied),
+        };
+        let file = LibraryFile::new(&base.join("Artist/Album/01.mp3"), base, LibrarySource::DMS);
+
+        assert!(file.is_under(&unreadable("artist")));
+        assert!(file.is_under(&unreadable("Artist/Album")));
+        assert!(file.is_under(&unreadable("Artist/Album/01.mp3")));
+        assert!(file.is_under(&unreadable("")));
+        assert!(!file.is_under(&unreadable("Art")));
+        assert!(!file.is_under(&unreadable("Other")));
+    }
+
+    #[test]
+    fn test_merged_media_library() {
+        let (first, second) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+
+        for (dir, file) in &[
+            (&first, "A/01.mp3"),
+            (&second, "a/01.MP3"),
+            (&second, "B/01.mp3"),
+        ] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+
+        let library =
+            get_merged_media_library(&[first.path().to_path_buf(), second.path().to_path_buf()]);
+
+        // the first root takes precedence over the second for the same file
+        assert_eq!(
+            vec![
+                first.path().join("A/01.mp3"),
+                second.path().join("B/01.mp3")
+            ],
+            library.iter().map(|f| f.path.clone()).collect::<Vec<_>>()
+        );
+    }
 }
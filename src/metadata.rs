@@ -1,16 +1,24 @@
+pub mod asf;
+pub mod cache;
+pub mod mp3;
+pub mod ogg;
 #[cfg(test)]
 mod test;
 
 use id3;
 
-use log::debug;
+use log::{debug, warn};
 
 use regex::Regex;
 
+use serde::{Deserialize, Serialize};
+
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::convert::From;
 use std::error::Error;
 use std::fmt;
+use std::fs;
 use std::iter::Iterator;
 use std::path::Path;
 use std::path::PathBuf;
@@ -21,15 +29,24 @@ use std::time::Duration;
 
 use metaflac;
 
-use mp3_duration;
-
+use crate::dms;
+use crate::metadata::asf::{AsfError, AsfTag};
+use crate::metadata::mp3::{
+    ChannelMode, Mp3Analysis, Mp3BitrateMode, Mp3Error, MpegLayer, MpegVersion, Uncertainty,
+};
+use crate::metadata::ogg::{OggError, OggVorbis, VorbisComments};
+use crate::utils::sanitize::{sanitize_field, sanitize_location};
 use crate::utils::StringPool;
 
-static DEFAULT_ARTIST: &'static str = "Unknown Artist";
-static DEFAULT_ALBUM: &'static str = "Unknown Album";
-static DEFAULT_GENRE: &'static str = "Unknown Genre";
-static DEFAULT_TITLE: &'static str = "Unknown Title";
-static DEFAULT_TRACK_NUMBER: &'static str = "0";
+static DEFAULT_ARTIST: &str = "Unknown Artist";
+static DEFAULT_ALBUM: &str = "Unknown Album";
+static DEFAULT_GENRE: &str = "Unknown Genre";
+static DEFAULT_TITLE: &str = "Unknown Title";
+static DEFAULT_TRACK_NUMBER: &str = "0";
+
+// Vorbis comment fields to search in order of preference, shared between FLAC and Ogg Vorbis
+static VORBIS_ARTIST_FIELDS: &[&str] = &["ALBUMARTIST", "ARTIST", "COMPOSER"];
+static VORBIS_GENRE_FIELDS: &[&str] = &["GENRE", "STYLE"];
 
 lazy_static! {
     static ref TRACK_NUMBER: Regex =
@@ -45,15 +62,74 @@ pub struct MediaMetadata {
     pub genre: Arc<str>,
     pub title: String,
     pub track_number: u16,
+    /// The duration of the track in whole seconds, as written to the tracks database.
     pub duration: u64,
+    pub properties: AudioProperties,
+    /// Reasons that the duration may be inaccurate; empty if it is believed to be exact.
+    pub uncertainties: Vec<Uncertainty>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Codec {
+    MP3,
+    FLAC,
+    Vorbis,
+    WMA,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum BitrateMode {
+    Constant,
+    Variable,
+    Unknown,
+}
+
+/// The technical properties of a track's audio stream.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AudioProperties {
+    pub codec: Codec,
+    /// The average bitrate in kbit/s.
+    pub bitrate: u32,
+    pub bitrate_mode: BitrateMode,
+    pub sample_rate: u32,
+    pub channels: u8,
+    /// The channel mode of an MPEG stream.
+    pub channel_mode: Option<ChannelMode>,
+    pub mpeg_version: Option<MpegVersion>,
+    pub mpeg_layer: Option<MpegLayer>,
+    pub duration: Duration,
+}
+
+impl AudioProperties {
+    /// The duration of the track in milliseconds.
+    pub fn duration_ms(&self) -> u64 {
+        self.duration.as_millis() as u64
+    }
 }
 
 impl MediaMetadata {
     pub fn load(path: &Path, base: &Path, pool: &StringPool) -> Result<Self, MediaParsingError> {
         debug!("Loading metadata from file {}...", path.display());
-        match path.extension() {
-            Some(extension) if extension == "mp3" => {
+
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+
+        match extension.as_deref() {
+            Some("mp3") => {
                 let tag = id3::Tag::read_from_path(path)?;
+                let (properties, uncertainties) = match get_analysis_mp3(path) {
+                    Ok(analysis) => (get_properties_mp3(&analysis), analysis.uncertainties),
+                    Err(Mp3Error::NoFrames) => {
+                        warn!(
+                            "No MPEG audio frames found in {}, its duration is unknown.",
+                            path.display()
+                        );
+                        (unknown_properties_mp3(), Vec::new())
+                    }
+                    Err(e) => return Err(e.into()),
+                };
 
                 Ok(MediaMetadata {
                     path: path.to_path_buf(),
@@ -63,7 +139,58 @@ impl MediaMetadata {
                     genre: pool.get(&get_genre_id3(&tag)),
                     title: get_title_id3(&tag),
                     track_number: get_track_number_id3(&tag),
-                    duration: get_duration_mp3(&path).as_secs(),
+                    duration: properties.duration.as_secs(),
+                    properties,
+                    uncertainties,
+                })
+            }
+            Some("flac") => {
+                let tag = metaflac::Tag::read_from_path(path)?;
+                let properties = get_properties_flac(&tag, path)?;
+
+                Ok(MediaMetadata {
+                    path: path.to_path_buf(),
+                    base: base.to_path_buf(),
+                    artist: pool.get(&get_artist_flac(&tag)),
+                    album: pool.get(&get_album_flac(&tag)),
+                    genre: pool.get(&get_genre_flac(&tag)),
+                    title: get_title_flac(&tag),
+                    track_number: get_track_number_flac(&tag),
+                    duration: properties.duration.as_secs(),
+                    properties,
+                    uncertainties: Vec::new(),
+                })
+            }
+            Some("ogg") => {
+                let vorbis = OggVorbis::read_from_path(path)?;
+
+                Ok(MediaMetadata {
+                    path: path.to_path_buf(),
+                    base: base.to_path_buf(),
+                    artist: pool.get(&get_artist_vorbis(&vorbis.comments)),
+                    album: pool.get(&get_album_vorbis(&vorbis.comments)),
+                    genre: pool.get(&get_genre_vorbis(&vorbis.comments)),
+                    title: get_title_vorbis(&vorbis.comments),
+                    track_number: get_track_number_vorbis(&vorbis.comments),
+                    duration: vorbis.duration().as_secs(),
+                    properties: get_properties_vorbis(&vorbis),
+                    uncertainties: Vec::new(),
+                })
+            }
+            Some("wma") => {
+                let tag = AsfTag::read_from_path(path)?;
+
+                Ok(MediaMetadata {
+                    path: path.to_path_buf(),
+                    base: base.to_path_buf(),
+                    artist: pool.get(&get_artist_asf(&tag)),
+                    album: pool.get(&get_album_asf(&tag)),
+                    genre: pool.get(&get_genre_asf(&tag)),
+                    title: get_title_asf(&tag),
+                    track_number: get_track_number_asf(&tag),
+                    duration: tag.play_duration.as_secs(),
+                    properties: get_properties_asf(&tag),
+                    uncertainties: Vec::new(),
                 })
             }
             _ => Err(MediaParsingError::UnrecognizedFormat),
@@ -129,8 +256,8 @@ impl MediaMetadata {
     }
 
     /// Get the location of a file relative to the DMS root.
-    fn dms_location(&self) -> PathBuf {
-        Path::new("/dos/data").join(self.path.strip_prefix(&self.base).unwrap())
+    pub fn dms_location(&self) -> PathBuf {
+        dms::dms_location(&self.path, &self.base)
     }
 
     /// Converts a MediaMetadata instance into the CSV format expected by PhatNoise for artists,
@@ -140,17 +267,52 @@ impl MediaMetadata {
     pub fn to_csv(&self) -> String {
         format!(
             "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-            self.dms_location().display(),
-            self.title,
-            self.artist,
-            self.album,
-            self.genre,
+            self.sanitized_location(),
+            self.sanitized("title", &self.title),
+            self.sanitized("artist", &self.artist),
+            self.sanitized("album", &self.album),
+            self.sanitized("genre", &self.genre),
             self.duration,
             "",
             self.track_number,
             "NotFound.jpg"
         )
     }
+
+    /// Sanitizes a tag value for the databases, logging when the value had to be altered.
+    fn sanitized<'a>(&self, field: &str, value: &'a str) -> Cow<'a, str> {
+        let sanitized = sanitize_field(value);
+
+        if let Cow::Owned(ref altered) = sanitized {
+            warn!(
+                "Sanitized {} of {}: {:?} -> {:?}",
+                field,
+                self.path.display(),
+                value,
+                altered
+            );
+        }
+
+        sanitized
+    }
+
+    /// The location of the file on the DMS, sanitized for the databases.
+    fn sanitized_location(&self) -> String {
+        let location = self.dms_location().display().to_string();
+
+        match sanitize_location(&location) {
+            Cow::Borrowed(_) => location,
+            Cow::Owned(altered) => {
+                warn!(
+                    "Sanitized location of {}: {:?} -> {:?}",
+                    self.path.display(),
+                    location,
+                    altered
+                );
+                altered
+            }
+        }
+    }
 }
 
 impl fmt::Display for MediaMetadata {
@@ -161,8 +323,11 @@ impl fmt::Display for MediaMetadata {
 
 #[derive(Debug)]
 pub enum MediaParsingError {
+    ASFError { err: AsfError },
     FLACError { err: metaflac::Error },
     ID3Error { err: id3::Error },
+    MP3Error { err: Mp3Error },
+    OggError { err: OggError },
     UnrecognizedFormat,
 }
 
@@ -174,14 +339,23 @@ impl Error for MediaParsingError {
 
 impl fmt::Display for MediaParsingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &MediaParsingError::FLACError { ref err } => {
+        match *self {
+            MediaParsingError::ASFError { ref err } => {
+                write!(f, "Unable to load metadata from media file: {}", err)
+            }
+            MediaParsingError::FLACError { ref err } => {
+                write!(f, "Unable to load metadata from media file: {}", err)
+            }
+            MediaParsingError::ID3Error { ref err } => {
                 write!(f, "Unable to load metadata from media file: {}", err)
             }
-            &MediaParsingError::ID3Error { ref err } => {
+            MediaParsingError::MP3Error { ref err } => {
                 write!(f, "Unable to load metadata from media file: {}", err)
             }
-            &MediaParsingError::UnrecognizedFormat => write!(
+            MediaParsingError::OggError { ref err } => {
+                write!(f, "Unable to load metadata from media file: {}", err)
+            }
+            MediaParsingError::UnrecognizedFormat => write!(
                 f,
                 "Unable to load metadata from media file, unrecognized format."
             ),
@@ -189,59 +363,159 @@ impl fmt::Display for MediaParsingError {
     }
 }
 
+impl From<AsfError> for MediaParsingError {
+    fn from(e: AsfError) -> Self {
+        MediaParsingError::ASFError { err: e }
+    }
+}
+
 impl From<id3::Error> for MediaParsingError {
     fn from(e: id3::Error) -> Self {
         MediaParsingError::ID3Error { err: e }
     }
 }
 
-fn get_artist_flac(path: &Path) -> Result<String, metaflac::Error> {
-    let tag = metaflac::Tag::read_from_path(path)?;
+impl From<metaflac::Error> for MediaParsingError {
+    fn from(e: metaflac::Error) -> Self {
+        MediaParsingError::FLACError { err: e }
+    }
+}
+
+impl From<Mp3Error> for MediaParsingError {
+    fn from(e: Mp3Error) -> Self {
+        MediaParsingError::MP3Error { err: e }
+    }
+}
 
-    for tag_name in &["ALBUMARTIST", "ARTIST", "COMPOSER"] {
-        // FIXME this is garbage horse trash
-        if let Some(entities) = tag.get_vorbis(tag_name) {
-            for entity in entities {
-                if entity.len() > 0 {
-                    return Ok(entity.to_string());
-                }
-            }
-        }
+impl From<OggError> for MediaParsingError {
+    fn from(e: OggError) -> Self {
+        MediaParsingError::OggError { err: e }
+    }
+}
+
+/// Find the first non-empty value among the given Vorbis comment fields, in order of preference.
+fn first_vorbis_value<'a, F, I>(lookup: F, fields: &[&str]) -> Option<String>
+where
+    F: Fn(&str) -> Option<I>,
+    I: Iterator<Item = &'a str>,
+{
+    fields
+        .iter()
+        .filter_map(|field| lookup(field))
+        .flatten()
+        .find(|value| !value.is_empty())
+        .map(|value| value.to_string())
+}
+
+fn get_artist_flac(tag: &metaflac::Tag) -> String {
+    first_vorbis_value(|f| tag.get_vorbis(f), VORBIS_ARTIST_FIELDS)
+        .unwrap_or_else(|| DEFAULT_ARTIST.to_string())
+}
+
+fn get_album_flac(tag: &metaflac::Tag) -> String {
+    first_vorbis_value(|f| tag.get_vorbis(f), &["ALBUM"])
+        .unwrap_or_else(|| DEFAULT_ALBUM.to_string())
+}
+
+fn get_genre_flac(tag: &metaflac::Tag) -> String {
+    first_vorbis_value(|f| tag.get_vorbis(f), VORBIS_GENRE_FIELDS)
+        .unwrap_or_else(|| DEFAULT_GENRE.to_string())
+}
+
+fn get_title_flac(tag: &metaflac::Tag) -> String {
+    first_vorbis_value(|f| tag.get_vorbis(f), &["TITLE"])
+        .unwrap_or_else(|| DEFAULT_TITLE.to_string())
+}
+
+fn get_track_number_flac(tag: &metaflac::Tag) -> u16 {
+    first_vorbis_value(|f| tag.get_vorbis(f), &["TRACKNUMBER"])
+        .as_deref()
+        .map(parse_track_number)
+        .unwrap_or(0)
+}
+
+fn get_streaminfo_flac(
+    tag: &metaflac::Tag,
+) -> Result<&metaflac::block::StreamInfo, metaflac::Error> {
+    // the STREAMINFO block is mandatory, so a file without one isn't a valid FLAC stream
+    tag.get_streaminfo().ok_or_else(|| {
+        metaflac::Error::new(
+            metaflac::ErrorKind::InvalidInput,
+            "missing STREAMINFO block",
+        )
+    })
+}
+
+fn get_duration_flac(info: &metaflac::block::StreamInfo) -> Duration {
+    if info.sample_rate == 0 {
+        return Duration::new(0, 0);
     }
 
-    Ok(DEFAULT_ARTIST.to_string())
+    Duration::from_nanos(
+        (u128::from(info.total_samples) * 1_000_000_000 / u128::from(info.sample_rate)) as u64,
+    )
+}
+
+fn get_artist_vorbis(comments: &VorbisComments) -> String {
+    first_vorbis_value(|f| comments.get(f), VORBIS_ARTIST_FIELDS)
+        .unwrap_or_else(|| DEFAULT_ARTIST.to_string())
+}
+
+fn get_album_vorbis(comments: &VorbisComments) -> String {
+    first_vorbis_value(|f| comments.get(f), &["ALBUM"]).unwrap_or_else(|| DEFAULT_ALBUM.to_string())
+}
+
+fn get_genre_vorbis(comments: &VorbisComments) -> String {
+    first_vorbis_value(|f| comments.get(f), VORBIS_GENRE_FIELDS)
+        .unwrap_or_else(|| DEFAULT_GENRE.to_string())
+}
+
+fn get_title_vorbis(comments: &VorbisComments) -> String {
+    first_vorbis_value(|f| comments.get(f), &["TITLE"]).unwrap_or_else(|| DEFAULT_TITLE.to_string())
 }
 
-fn get_album_flac(path: &Path) -> Result<String, metaflac::Error> {
-    let tag = metaflac::Tag::read_from_path(path)?;
-    let metadata = tag.vorbis_comments().unwrap();
+fn get_track_number_vorbis(comments: &VorbisComments) -> u16 {
+    first_vorbis_value(|f| comments.get(f), &["TRACKNUMBER"])
+        .as_deref()
+        .map(parse_track_number)
+        .unwrap_or(0)
+}
 
-    // find the first non-empty tag, or return DEFAULT_ALBUM
-    Ok(metadata
-        .album()
+/// Find the first non-empty textual value among the given ASF attributes, in order of preference.
+fn first_asf_value(tag: &AsfTag, attributes: &[&str]) -> Option<String> {
+    attributes
         .iter()
-        .flat_map(|&v| v)
-        .map(|s| s.to_string())
-        .filter(|f| f.len() > 0)
-        .nth(0)
-        .unwrap_or(DEFAULT_ALBUM.to_string()))
-}
-
-fn get_genre_flac(path: &Path) -> Result<String, metaflac::Error> {
-    let tag = metaflac::Tag::read_from_path(path)?;
-
-    for tag_name in &["GENRE", "STYLE"] {
-        // FIXME this is garbage horse trash
-        if let Some(entities) = tag.get_vorbis(tag_name) {
-            for entity in entities {
-                if entity.len() > 0 {
-                    return Ok(entity.to_string());
-                }
-            }
-        }
+        .filter_map(|name| tag.get(name).and_then(|v| v.as_text()))
+        .find(|value| !value.is_empty())
+}
+
+fn get_artist_asf(tag: &AsfTag) -> String {
+    first_asf_value(tag, &["WM/AlbumArtist", "Author", "WM/Composer"])
+        .unwrap_or_else(|| DEFAULT_ARTIST.to_string())
+}
+
+fn get_album_asf(tag: &AsfTag) -> String {
+    first_asf_value(tag, &["WM/AlbumTitle"]).unwrap_or_else(|| DEFAULT_ALBUM.to_string())
+}
+
+fn get_genre_asf(tag: &AsfTag) -> String {
+    first_asf_value(tag, &["WM/Genre"]).unwrap_or_else(|| DEFAULT_GENRE.to_string())
+}
+
+fn get_title_asf(tag: &AsfTag) -> String {
+    first_asf_value(tag, &["Title"]).unwrap_or_else(|| DEFAULT_TITLE.to_string())
+}
+
+fn get_track_number_asf(tag: &AsfTag) -> u16 {
+    // WM/TrackNumber is one-based, whereas the deprecated WM/Track is zero-based
+    if let Some(track) = first_asf_value(tag, &["WM/TrackNumber"]) {
+        return parse_track_number(&track);
     }
 
-    Ok(DEFAULT_GENRE.to_string())
+    tag.get("WM/Track")
+        .and_then(|v| v.as_u64())
+        .map(|track| (track + 1).min(u64::from(u16::MAX)) as u16)
+        .unwrap_or(0)
 }
 
 fn get_artist_id3(tag: &id3::Tag) -> String {
@@ -276,22 +550,133 @@ fn get_track_number_id3(tag: &id3::Tag) -> u16 {
     let track = tag
         .track()
         .map(|i| format!("{}", i))
-        .unwrap_or(DEFAULT_TRACK_NUMBER.to_string());
+        .unwrap_or_else(|| DEFAULT_TRACK_NUMBER.to_string());
+
+    parse_track_number(&track)
+}
 
-    // the above value is a string of the format (\d+)(?:/(\d+))?, deconstruct and parse into an int
+/// Parse a track number of the format `(\d+)(?:/(\d+))?`, returning zero if it can't be parsed.
+fn parse_track_number(value: &str) -> u16 {
     TRACK_NUMBER
-        .captures(&track)
-        .unwrap()
-        .name("track")
-        .map(|s| u16::from_str(s.as_str()).unwrap())
-        .unwrap()
+        .captures(value.trim())
+        .and_then(|c| c.name("track"))
+        .and_then(|s| u16::from_str(s.as_str()).ok())
+        .unwrap_or(0)
 }
 
 fn get_title_id3(tag: &id3::Tag) -> String {
     tag.title().unwrap_or(DEFAULT_TITLE).to_string()
 }
 
-fn get_duration_mp3(path: &Path) -> Duration {
-    // some of my tracks panic when trying to get the duration, so failover to 0
-    mp3_duration::from_path(path).unwrap_or(Duration::new(0, 0))
+fn get_analysis_mp3(path: &Path) -> Result<Mp3Analysis, Mp3Error> {
+    let analysis = mp3::analyse_path(path)?;
+
+    for uncertainty in &analysis.uncertainties {
+        warn!(
+            "Duration of {} may be inaccurate: {}",
+            path.display(),
+            uncertainty
+        );
+    }
+
+    Ok(analysis)
+}
+
+fn get_properties_mp3(analysis: &Mp3Analysis) -> AudioProperties {
+    let header = &analysis.header;
+
+    AudioProperties {
+        codec: Codec::MP3,
+        bitrate: analysis.average_bitrate(),
+        bitrate_mode: match analysis.bitrate_mode {
+            Mp3BitrateMode::Constant => BitrateMode::Constant,
+            Mp3BitrateMode::Variable => BitrateMode::Variable,
+        },
+        sample_rate: header.sample_rate,
+        channels: if header.channel_mode == ChannelMode::Mono {
+            1
+        } else {
+            2
+        },
+        channel_mode: Some(header.channel_mode),
+        mpeg_version: Some(header.version),
+        mpeg_layer: Some(header.layer),
+        duration: analysis.duration,
+    }
+}
+
+/// The properties of an MP3 whose audio couldn't be found, which is still listed with its tags.
+fn unknown_properties_mp3() -> AudioProperties {
+    AudioProperties {
+        codec: Codec::MP3,
+        bitrate: 0,
+        bitrate_mode: BitrateMode::Unknown,
+        sample_rate: 0,
+        channels: 0,
+        channel_mode: None,
+        mpeg_version: None,
+        mpeg_layer: None,
+        duration: Duration::from_secs(0),
+    }
+}
+
+fn get_properties_flac(
+    tag: &metaflac::Tag,
+    path: &Path,
+) -> Result<AudioProperties, metaflac::Error> {
+    let info = get_streaminfo_flac(tag)?;
+    let duration = get_duration_flac(info);
+
+    // FLAC has no notion of a bitrate, so derive an average from the size of the file
+    let bitrate = match (fs::metadata(path), duration.as_millis()) {
+        (Ok(meta), millis) if millis > 0 => (u128::from(meta.len()) * 8 / millis) as u32,
+        _ => 0,
+    };
+
+    Ok(AudioProperties {
+        codec: Codec::FLAC,
+        bitrate,
+        bitrate_mode: BitrateMode::Variable,
+        sample_rate: info.sample_rate,
+        channels: info.num_channels,
+        channel_mode: None,
+        mpeg_version: None,
+        mpeg_layer: None,
+        duration,
+    })
+}
+
+fn get_properties_vorbis(vorbis: &OggVorbis) -> AudioProperties {
+    AudioProperties {
+        codec: Codec::Vorbis,
+        bitrate: vorbis.nominal_bitrate / 1000,
+        bitrate_mode: BitrateMode::Variable,
+        sample_rate: vorbis.sample_rate,
+        channels: vorbis.channels,
+        channel_mode: None,
+        mpeg_version: None,
+        mpeg_layer: None,
+        duration: vorbis.duration(),
+    }
+}
+
+fn get_properties_asf(tag: &AsfTag) -> AudioProperties {
+    let audio = tag.audio.unwrap_or(asf::AudioStream {
+        codec_id: 0,
+        channels: 0,
+        sample_rate: 0,
+        bitrate: 0,
+    });
+
+    AudioProperties {
+        codec: Codec::WMA,
+        bitrate: audio.bitrate / 1000,
+        bitrate_mode: BitrateMode::Unknown,
+        sample_rate: audio.sample_rate,
+        channels: audio.channels.min(u16::from(u8::MAX)) as u8,
+        channel_mode: None,
+        mpeg_version: None,
+        mpeg_layer: None,
+        duration: tag.play_duration,
+    }
 }
@@ -0,0 +1,473 @@
+//! A minimal reader for the header of ASF containers, as used by WMA files.
+//!
+//! Only the top-level header objects are parsed: the File Properties object for the duration, the
+//! Stream Properties object for the audio format, the Content Description object for the title and
+//! author, and the Extended Content Description object for the `WM/` attributes.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+
+const HEADER_OBJECT: [u8; 16] = [
+    0x30, 0x26, 0xb2, 0x75, 0x8e, 0x66, 0xcf, 0x11, 0xa6, 0xd9, 0x00, 0xaa, 0x00, 0x62, 0xce, 0x6c,
+];
+const FILE_PROPERTIES_OBJECT: [u8; 16] = [
+    0xa1, 0xdc, 0xab, 0x8c, 0x47, 0xa9, 0xcf, 0x11, 0x8e, 0xe4, 0x00, 0xc0, 0x0c, 0x20, 0x53, 0x65,
+];
+const STREAM_PROPERTIES_OBJECT: [u8; 16] = [
+    0x91, 0x07, 0xdc, 0xb7, 0xb7, 0xa9, 0xcf, 0x11, 0x8e, 0xe6, 0x00, 0xc0, 0x0c, 0x20, 0x53, 0x65,
+];
+const AUDIO_MEDIA: [u8; 16] = [
+    0x40, 0x9e, 0x69, 0xf8, 0x4d, 0x5b, 0xcf, 0x11, 0xa8, 0xfd, 0x00, 0x80, 0x5f, 0x5c, 0x44, 0x2b,
+];
+const CONTENT_DESCRIPTION_OBJECT: [u8; 16] = [
+    0x33, 0x26, 0xb2, 0x75, 0x8e, 0x66, 0xcf, 0x11, 0xa6, 0xd9, 0x00, 0xaa, 0x00, 0x62, 0xce, 0x6c,
+];
+const EXTENDED_CONTENT_DESCRIPTION_OBJECT: [u8; 16] = [
+    0x40, 0xa4, 0xd0, 0xd2, 0x07, 0xe3, 0xd2, 0x11, 0x97, 0xf0, 0x00, 0xa0, 0xc9, 0x5e, 0xa8, 0x50,
+];
+
+/// The size of an object's GUID and size fields.
+const OBJECT_HEADER_SIZE: usize = 24;
+/// The header object is read into memory whole, so refuse anything unreasonably large.
+const MAX_HEADER_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The tags and duration read from an ASF header.
+#[derive(Debug, Default)]
+pub struct AsfTag {
+    attributes: HashMap<String, AttributeValue>,
+    pub play_duration: Duration,
+    /// The format of the first audio stream, if any.
+    pub audio: Option<AudioStream>,
+}
+
+/// The format of an audio stream, from its `WAVEFORMATEX` structure.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AudioStream {
+    pub codec_id: u16,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// The average bitrate in bit/s.
+    pub bitrate: u32,
+}
+
+impl AsfTag {
+    pub fn read_from_path<P: AsRef<Path>>(path: P) -> Result<Self, AsfError> {
+        AsfTag::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, AsfError> {
+        let mut header = [0u8; OBJECT_HEADER_SIZE + 6];
+        reader.read_exact(&mut header)?;
+
+        if header[..16] != HEADER_OBJECT {
+            return Err(AsfError::Malformed {
+                reason: "missing ASF header object",
+            });
+        }
+
+        let size = read_u64(&header[16..24]);
+
+        if size < header.len() as u64 || size > MAX_HEADER_SIZE {
+            return Err(AsfError::Malformed {
+                reason: "header object has an invalid size",
+            });
+        }
+
+        let mut data = vec![0u8; size as usize - header.len()];
+        reader.read_exact(&mut data)?;
+
+        let mut tag = AsfTag::default();
+        let mut cursor = &data[..];
+
+        while cursor.len() >= OBJECT_HEADER_SIZE {
+            let size = read_u64(&cursor[16..24]);
+
+            if size < OBJECT_HEADER_SIZE as u64 || size > cursor.len() as u64 {
+                return Err(AsfError::Malformed {
+                    reason: "header child object has an invalid size",
+                });
+            }
+
+            let (object, rest) = cursor.split_at(size as usize);
+            let (guid, body) = (&object[..16], &object[OBJECT_HEADER_SIZE..]);
+
+            if guid == FILE_PROPERTIES_OBJECT {
+                tag.play_duration = parse_file_properties(body)?;
+            } else if guid == STREAM_PROPERTIES_OBJECT {
+                if tag.audio.is_none() {
+                    tag.audio = parse_stream_properties(body)?;
+                }
+            } else if guid == CONTENT_DESCRIPTION_OBJECT {
+                parse_content_description(body, &mut tag.attributes)?;
+            } else if guid == EXTENDED_CONTENT_DESCRIPTION_OBJECT {
+                parse_extended_content_description(body, &mut tag.attributes)?;
+            }
+
+            cursor = rest;
+        }
+
+        Ok(tag)
+    }
+
+    /// Get the value of the given attribute, which is matched case-insensitively. The title and
+    /// author from the Content Description object are available as `Title` and `Author`.
+    pub fn get(&self, name: &str) -> Option<&AttributeValue> {
+        self.attributes.get(&name.to_lowercase())
+    }
+}
+
+/// The value of an ASF attribute.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Binary(Vec<u8>),
+    Bool(bool),
+    DWord(u32),
+    QWord(u64),
+    Word(u16),
+}
+
+impl AttributeValue {
+    /// Render the value as text, if it has a textual representation.
+    pub fn as_text(&self) -> Option<String> {
+        match *self {
+            AttributeValue::String(ref s) => Some(s.clone()),
+            AttributeValue::Binary(_) => None,
+            AttributeValue::Bool(b) => Some(b.to_string()),
+            AttributeValue::DWord(v) => Some(v.to_string()),
+            AttributeValue::QWord(v) => Some(v.to_string()),
+            AttributeValue::Word(v) => Some(v.to_string()),
+        }
+    }
+
+    /// Interpret the value as an integer, parsing it if it is a string.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            AttributeValue::String(ref s) => s.trim().parse().ok(),
+            AttributeValue::Binary(_) | AttributeValue::Bool(_) => None,
+            AttributeValue::DWord(v) => Some(u64::from(v)),
+            AttributeValue::QWord(v) => Some(v),
+            AttributeValue::Word(v) => Some(u64::from(v)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AsfError {
+    IOError { err: io::Error },
+    Malformed { reason: &'static str },
+}
+
+impl Error for AsfError {
+    fn description(&self) -> &str {
+        "Unable to read ASF header."
+    }
+}
+
+impl fmt::Display for AsfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AsfError::IOError { ref err } => write!(f, "Unable to read ASF header: {}", err),
+            AsfError::Malformed { reason } => write!(f, "Malformed ASF header: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for AsfError {
+    fn from(e: io::Error) -> Self {
+        AsfError::IOError { err: e }
+    }
+}
+
+fn parse_file_properties(body: &[u8]) -> Result<Duration, AsfError> {
+    if body.len() < 64 {
+        return Err(AsfError::Malformed {
+            reason: "file properties object is truncated",
+        });
+    }
+
+    // the play duration is in 100ns units and includes the preroll, which is in milliseconds
+    let play_duration = Duration::from_nanos(read_u64(&body[40..48]).saturating_mul(100));
+    let preroll = Duration::from_millis(read_u64(&body[56..64]));
+
+    Ok(play_duration
+        .checked_sub(preroll)
+        .unwrap_or_else(|| Duration::new(0, 0)))
+}
+
+fn parse_stream_properties(body: &[u8]) -> Result<Option<AudioStream>, AsfError> {
+    if body.len() < 54 {
+        return Err(AsfError::Malformed {
+            reason: "stream properties object is truncated",
+        });
+    }
+
+    if body[..16] != AUDIO_MEDIA {
+        return Ok(None);
+    }
+
+    let length = read_u32(&body[40..44]) as usize;
+    let format = &body[54..];
+
+    if length < 12 || format.len() < 12 {
+        return Err(AsfError::Malformed {
+            reason: "audio stream format is truncated",
+        });
+    }
+
+    Ok(Some(AudioStream {
+        codec_id: read_u16(&format[0..]),
+        channels: read_u16(&format[2..]),
+        sample_rate: read_u32(&format[4..]),
+        bitrate: read_u32(&format[8..]).saturating_mul(8),
+    }))
+}
+
+fn parse_content_description(
+    body: &[u8],
+    attributes: &mut HashMap<String, AttributeValue>,
+) -> Result<(), AsfError> {
+    let mut cursor = body;
+    let lengths = take(&mut cursor, 10)?;
+
+    for (index, name) in ["Title", "Author", "Copyright", "Description", "Rating"]
+        .iter()
+        .enumerate()
+    {
+        let length = read_u16(&lengths[index * 2..]) as usize;
+        let value = read_utf16(take(&mut cursor, length)?);
+
+        if !value.is_empty() {
+            attributes.insert(name.to_lowercase(), AttributeValue::String(value));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_extended_content_description(
+    body: &[u8],
+    attributes: &mut HashMap<String, AttributeValue>,
+) -> Result<(), AsfError> {
+    let mut cursor = body;
+    let count = read_u16(take(&mut cursor, 2)?);
+
+    for _ in 0..count {
+        let name_length = read_u16(take(&mut cursor, 2)?) as usize;
+        let name = read_utf16(take(&mut cursor, name_length)?);
+        let value_type = read_u16(take(&mut cursor, 2)?);
+        let value_length = read_u16(take(&mut cursor, 2)?) as usize;
+        let value = take(&mut cursor, value_length)?;
+
+        let value = match value_type {
+            0 => AttributeValue::String(read_utf16(value)),
+            1 => AttributeValue::Binary(value.to_vec()),
+            // booleans are stored as four bytes in this object
+            2 if value.len() >= 4 => AttributeValue::Bool(read_u32(value) != 0),
+            3 if value.len() >= 4 => AttributeValue::DWord(read_u32(value)),
+            4 if value.len() >= 8 => AttributeValue::QWord(read_u64(value)),
+            5 if value.len() >= 2 => AttributeValue::Word(read_u16(value)),
+            _ => {
+                return Err(AsfError::Malformed {
+                    reason: "attribute has an invalid type or length",
+                })
+            }
+        };
+
+        attributes.insert(name.to_lowercase(), value);
+    }
+
+    Ok(())
+}
+
+fn take<'a>(cursor: &mut &'a [u8], count: usize) -> Result<&'a [u8], AsfError> {
+    if cursor.len() < count {
+        return Err(AsfError::Malformed {
+            reason: "object is shorter than its contents",
+        });
+    }
+
+    let (head, tail) = cursor.split_at(count);
+    *cursor = tail;
+    Ok(head)
+}
+
+/// Decode a UTF-16LE string, dropping the trailing null terminator(s).
+fn read_utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+
+    String::from_utf16_lossy(&units)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    pub fn utf16(value: &str) -> Vec<u8> {
+        value
+            .encode_utf16()
+            .chain(Some(0))
+            .flat_map(|u| u.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    pub fn object(guid: &[u8; 16], body: &[u8]) -> Vec<u8> {
+        let mut object = guid.to_vec();
+        object.extend_from_slice(&((body.len() + OBJECT_HEADER_SIZE) as u64).to_le_bytes());
+        object.extend_from_slice(body);
+        object
+    }
+
+    pub fn file_properties(duration_100ns: u64, preroll_ms: u64) -> Vec<u8> {
+        let mut body = vec![0u8; 80];
+        body[40..48].copy_from_slice(&duration_100ns.to_le_bytes());
+        body[56..64].copy_from_slice(&preroll_ms.to_le_bytes());
+        object(&FILE_PROPERTIES_OBJECT, &body)
+    }
+
+    pub fn audio_stream_properties(channels: u16, sample_rate: u32, bitrate: u32) -> Vec<u8> {
+        let mut format = Vec::new();
+        format.extend_from_slice(&0x0161u16.to_le_bytes());
+        format.extend_from_slice(&channels.to_le_bytes());
+        format.extend_from_slice(&sample_rate.to_le_bytes());
+        format.extend_from_slice(&(bitrate / 8).to_le_bytes());
+        format.extend_from_slice(&[0u8; 6]);
+
+        let mut body = AUDIO_MEDIA.to_vec();
+        body.extend_from_slice(&[0u8; 24]);
+        body.extend_from_slice(&(format.len() as u32).to_le_bytes());
+        body.extend_from_slice(&[0u8; 10]);
+        body.extend(format);
+        object(&STREAM_PROPERTIES_OBJECT, &body)
+    }
+
+    pub fn content_description(title: &str, author: &str) -> Vec<u8> {
+        let (title, author) = (utf16(title), utf16(author));
+        let mut body = Vec::new();
+
+        for length in &[title.len(), author.len(), 0, 0, 0] {
+            body.extend_from_slice(&(*length as u16).to_le_bytes());
+        }
+
+        body.extend(title);
+        body.extend(author);
+        object(&CONTENT_DESCRIPTION_OBJECT, &body)
+    }
+
+    /// Render an extended content description object from (name, type, value) descriptors.
+    pub fn extended_content_description(descriptors: &[(&str, u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = (descriptors.len() as u16).to_le_bytes().to_vec();
+
+        for (name, value_type, value) in descriptors {
+            let name = utf16(name);
+            body.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            body.extend(name);
+            body.extend_from_slice(&value_type.to_le_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            body.extend_from_slice(value);
+        }
+
+        object(&EXTENDED_CONTENT_DESCRIPTION_OBJECT, &body)
+    }
+
+    pub fn header(objects: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = (objects.len() as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(&[1, 2]);
+
+        for object in objects {
+            body.extend_from_slice(object);
+        }
+
+        object(&HEADER_OBJECT, &body)
+    }
+
+    #[test]
+    fn test_read_asf() {
+        let data = header(&[
+            file_properties(1_234_000_000 + 30_000_000, 3_000),
+            audio_stream_properties(2, 44100, 128_000),
+            content_description("Title", "Author"),
+            extended_content_description(&[
+                ("WM/AlbumTitle", 0, utf16("Album")),
+                ("WM/TrackNumber", 3, 7u32.to_le_bytes().to_vec()),
+                ("WM/Picture", 1, vec![0xff; 16]),
+            ]),
+        ]);
+
+        let tag = AsfTag::read_from(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(Duration::from_millis(123_400), tag.play_duration);
+        assert_eq!(
+            Some(AudioStream {
+                codec_id: 0x0161,
+                channels: 2,
+                sample_rate: 44100,
+                bitrate: 128_000,
+            }),
+            tag.audio
+        );
+        assert_eq!(
+            Some("Title".to_string()),
+            tag.get("Title").and_then(|v| v.as_text())
+        );
+        assert_eq!(
+            Some("Author".to_string()),
+            tag.get("author").and_then(|v| v.as_text())
+        );
+        assert_eq!(
+            Some("Album".to_string()),
+            tag.get("WM/AlbumTitle").and_then(|v| v.as_text())
+        );
+        assert_eq!(Some(7), tag.get("WM/TrackNumber").and_then(|v| v.as_u64()));
+        assert_eq!(None, tag.get("WM/Picture").and_then(|v| v.as_text()));
+        assert!(tag.get("WM/Genre").is_none());
+    }
+
+    #[test]
+    fn test_read_not_asf() {
+        let data = b"ID3\x04\x00\x00\x00\x00\x00\x00 and some more bytes to fill".to_vec();
+
+        match AsfTag::read_from(&mut Cursor::new(data)) {
+            Err(AsfError::Malformed { .. }) => (),
+            other => panic!("expected a malformed header error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_truncated_object() {
+        let mut data = header(&[content_description("Title", "Author")]);
+        // claim that the child object is larger than the header
+        data[30 + 16] = 0xff;
+
+        assert!(AsfTag::read_from(&mut Cursor::new(data)).is_err());
+    }
+}
@@ -0,0 +1,428 @@
+//! A persistent cache of `MediaMetadata`, so that unchanged files needn't be parsed on every run.
+//!
+//! Entries are keyed on the absolute path of the media file and are only trusted while the file's
+//! size and modification time match those recorded when it was parsed.
+
+use log::{debug, info, warn};
+
+use rayon::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::metadata::mp3::Uncertainty;
+use crate::metadata::{AudioProperties, MediaMetadata};
+use crate::utils::fs::sync_dir;
+use crate::utils::StringPool;
+
+/// Bump this whenever the layout of `CacheEntry` changes to discard old caches.
+const CACHE_VERSION: u32 = 2;
+
+static CACHE_FILE_NAME: &str = "metadata.json";
+
+/// The size and modification time of a file, used to decide whether a cache entry is stale.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+}
+
+impl FileStamp {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let meta = fs::metadata(path)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Ok(FileStamp {
+            size: meta.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheEntry {
+    stamp: FileStamp,
+    artist: String,
+    album: String,
+    genre: String,
+    title: String,
+    track_number: u16,
+    properties: AudioProperties,
+    uncertainties: Vec<Uncertainty>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CacheFile {
+    version: u32,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+pub struct MetadataCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+impl MetadataCache {
+    /// The default location of the cache, under `$XDG_CACHE_HOME` or `$HOME/.cache`.
+    pub fn default_path() -> Option<PathBuf> {
+        let cache_dir = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+
+        Some(cache_dir.join("phatnoise").join(CACHE_FILE_NAME))
+    }
+
+    /// Open the cache at the given path. A missing, unreadable, or outdated cache is treated as
+    /// empty and will be replaced on the next save.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+
+        let entries = match File::open(&path) {
+            Ok(f) => match serde_json::from_reader::<_, CacheFile>(BufReader::new(f)) {
+                Ok(cache) if cache.version == CACHE_VERSION => cache.entries,
+                Ok(cache) => {
+                    info!(
+                        "Discarding metadata cache with outdated version {}",
+                        cache.version
+                    );
+                    HashMap::new()
+                }
+                Err(e) => {
+                    warn!(
+                        "Discarding unreadable metadata cache {}: {}",
+                        path.display(),
+                        e
+                    );
+                    HashMap::new()
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("Unable to open metadata cache {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+
+        debug!("Loaded {} metadata cache entries", entries.len());
+
+        MetadataCache { path, entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the cached metadata for the given file, if it is present and the stamp still matches.
+    pub fn get(
+        &self,
+        path: &Path,
+        stamp: &FileStamp,
+        base: &Path,
+        pool: &StringPool,
+    ) -> Option<MediaMetadata> {
+        let entry = self.entries.get(path).filter(|e| e.stamp == *stamp)?;
+
+        Some(MediaMetadata {
+            path: path.to_path_buf(),
+            base: base.to_path_buf(),
+            artist: pool.get(&entry.artist),
+            album: pool.get(&entry.album),
+            genre: pool.get(&entry.genre),
+            title: entry.title.clone(),
+            track_number: entry.track_number,
+            duration: entry.properties.duration.as_secs(),
+            properties: entry.properties.clone(),
+            uncertainties: entry.uncertainties.clone(),
+        })
+    }
+
+    pub fn insert(&mut self, metadata: &MediaMetadata, stamp: FileStamp) {
+        self.entries.insert(
+            metadata.path.clone(),
+            CacheEntry {
+                stamp,
+                artist: metadata.artist.to_string(),
+                album: metadata.album.to_string(),
+                genre: metadata.genre.to_string(),
+                title: metadata.title.clone(),
+                track_number: metadata.track_number,
+                properties: metadata.properties.clone(),
+                uncertainties: metadata.uncertainties.clone(),
+            },
+        );
+    }
+
+    /// Drop the entries for files under the given roots which are not in the given set, and the
+    /// entries for files elsewhere which no longer exist, returning the number removed. Entries
+    /// for other libraries are kept, so that libraries loaded in turn share the cache.
+    pub fn prune(&mut self, roots: &[&Path], paths: &HashSet<&Path>) -> usize {
+        let before = self.entries.len();
+
+        self.entries.retain(|path, _| {
+            if roots.iter().any(|root| path.starts_with(root)) {
+                paths.contains(path.as_path())
+            } else {
+                path.exists()
+            }
+        });
+
+        before - self.entries.len()
+    }
+
+    /// Load metadata for all of the given files, taking it from the cache where possible and
+    /// parsing the rest in parallel. Entries for files no longer in the library are pruned.
+    pub fn load_all(
+        &mut self,
+        paths: &[PathBuf],
+        base: &Path,
+        pool: &StringPool,
+    ) -> Vec<MediaMetadata> {
+        self.prune_stale(&[base], paths.iter());
+        self.load_unpruned(paths, base, pool)
+    }
+
+    /// Load metadata for the files of several library roots, each given with the files beneath
+    /// it. Entries for files no longer in the roots are pruned.
+    pub fn load_roots(
+        &mut self,
+        roots: &[(PathBuf, Vec<PathBuf>)],
+        pool: &StringPool,
+    ) -> Vec<MediaMetadata> {
+        let bases: Vec<&Path> = roots.iter().map(|(base, _)| base.as_path()).collect();
+        self.prune_stale(&bases, roots.iter().flat_map(|(_, paths)| paths));
+
+        roots
+            .iter()
+            .flat_map(|(base, paths)| self.load_unpruned(paths, base, pool))
+            .collect()
+    }
+
+    fn prune_stale<'a, I: Iterator<Item = &'a PathBuf>>(&mut self, roots: &[&Path], paths: I) {
+        let pruned = self.prune(roots, &paths.map(|p| p.as_path()).collect());
+
+        if pruned > 0 {
+            debug!("Pruned {} stale metadata cache entries", pruned);
+        }
+    }
+
+    fn load_unpruned(
+        &mut self,
+        paths: &[PathBuf],
+        base: &Path,
+        pool: &StringPool,
+    ) -> Vec<MediaMetadata> {
+        let cache = &*self;
+
+        let results: Vec<(&PathBuf, Option<FileStamp>, Option<MediaMetadata>)> = paths
+            .par_iter()
+            .map(|path| {
+                let stamp = FileStamp::read(path).ok();
+                let cached = stamp.and_then(|s| cache.get(path, &s, base, pool));
+                (path, stamp, cached)
+            })
+            .collect();
+
+        let misses: Vec<(&PathBuf, Option<FileStamp>)> = results
+            .iter()
+            .filter(|(_, _, cached)| cached.is_none())
+            .map(|(path, stamp, _)| (*path, *stamp))
+            .collect();
+
+        info!(
+            "Metadata cache: {} hits, {} misses",
+            paths.len() - misses.len(),
+            misses.len()
+        );
+
+        let loaded: Vec<(MediaMetadata, Option<FileStamp>)> = misses
+            .into_par_iter()
+            .filter_map(
+                |(path, stamp)| match MediaMetadata::load(path, base, pool) {
+                    Ok(metadata) => Some((metadata, stamp)),
+                    Err(e) => {
+                        warn!("Unable to load metadata for {}: {}", path.display(), e);
+                        None
+                    }
+                },
+            )
+            .collect();
+
+        let mut files: Vec<MediaMetadata> = results.into_iter().filter_map(|(_, _, m)| m).collect();
+
+        for (metadata, stamp) in loaded {
+            if let Some(stamp) = stamp {
+                self.insert(&metadata, stamp);
+            }
+
+            files.push(metadata);
+        }
+
+        files
+    }
+
+    /// Write the cache to disk, replacing the previous cache file.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // write alongside and rename so that an interrupted save doesn't destroy the cache
+        let temp = self.path.with_extension("json.tmp");
+
+        {
+            let mut f = BufWriter::new(File::create(&temp)?);
+            serde_json::to_writer(
+                &mut f,
+                &CacheFileRef {
+                    version: CACHE_VERSION,
+                    entries: &self.entries,
+                },
+            )
+            .map_err(io::Error::from)?;
+            f.flush()?;
+            f.get_ref().sync_all()?;
+        }
+
+        fs::rename(&temp, &self.path)?;
+
+        match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => sync_dir(parent),
+            _ => sync_dir(Path::new(".")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CacheFileRef<'a> {
+    version: u32,
+    entries: &'a HashMap<PathBuf, CacheEntry>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::Path;
+
+    #[test]
+    fn test_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("cache.json");
+        let media = dir.path().join("album.flac");
+        fs::copy("test/fixtures/flac/album.flac", &media).unwrap();
+
+        let pool = StringPool::new();
+        let paths = vec![media.clone()];
+
+        let mut cache = MetadataCache::open(&cache_path);
+        assert!(cache.is_empty());
+
+        let files = cache.load_all(&paths, dir.path(), &pool);
+        assert_eq!(1, files.len());
+        assert_eq!(1, cache.len());
+        cache.save().unwrap();
+
+        // a fresh cache should serve the file without parsing it
+        let cache = MetadataCache::open(&cache_path);
+        let stamp = FileStamp::read(&media).unwrap();
+        let cached = cache.get(&media, &stamp, dir.path(), &pool).unwrap();
+
+        assert_eq!("Album", &*cached.album);
+        assert_eq!(files[0].properties, cached.properties);
+    }
+
+    #[test]
+    fn test_cache_invalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join("album.flac");
+        fs::copy("test/fixtures/flac/album.flac", &media).unwrap();
+
+        let pool = StringPool::new();
+        let mut cache = MetadataCache::open(dir.path().join("cache.json"));
+        cache.load_all(std::slice::from_ref(&media), dir.path(), &pool);
+
+        let stamp = FileStamp::read(&media).unwrap();
+        let touched = FileStamp {
+            mtime_secs: stamp.mtime_secs + 1,
+            ..stamp
+        };
+        let resized = FileStamp {
+            size: stamp.size + 1,
+            ..stamp
+        };
+
+        assert!(cache.get(&media, &stamp, dir.path(), &pool).is_some());
+        assert!(cache.get(&media, &touched, dir.path(), &pool).is_none());
+        assert!(cache.get(&media, &resized, dir.path(), &pool).is_none());
+    }
+
+    #[test]
+    fn test_cache_prunes_deleted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("a.flac"), dir.path().join("b.flac"));
+        fs::copy("test/fixtures/flac/album.flac", &first).unwrap();
+        fs::copy("test/fixtures/flac/genre.flac", &second).unwrap();
+
+        let pool = StringPool::new();
+        let mut cache = MetadataCache::open(dir.path().join("cache.json"));
+        cache.load_all(&[first.clone(), second.clone()], dir.path(), &pool);
+        assert_eq!(2, cache.len());
+
+        fs::remove_file(&second).unwrap();
+        let files = cache.load_all(std::slice::from_ref(&first), dir.path(), &pool);
+
+        assert_eq!(1, files.len());
+        assert_eq!(1, cache.len());
+    }
+
+    #[test]
+    fn test_cache_keeps_other_roots() {
+        let (first, second) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (kept, gone) = (first.path().join("a.flac"), first.path().join("b.flac"));
+        let other = second.path().join("c.flac");
+        fs::copy("test/fixtures/flac/album.flac", &kept).unwrap();
+        fs::copy("test/fixtures/flac/genre.flac", &gone).unwrap();
+        fs::copy("test/fixtures/flac/artist.flac", &other).unwrap();
+
+        let pool = StringPool::new();
+        let mut cache = MetadataCache::open(first.path().join("cache.json"));
+        cache.load_all(&[kept.clone(), gone.clone()], first.path(), &pool);
+        assert_eq!(2, cache.len());
+
+        // loading another library keeps the entries for the first while its files exist
+        fs::remove_file(&gone).unwrap();
+        cache.load_all(std::slice::from_ref(&other), second.path(), &pool);
+
+        let stamp = FileStamp::read(&kept).unwrap();
+        assert_eq!(2, cache.len());
+        assert!(cache.get(&kept, &stamp, first.path(), &pool).is_some());
+    }
+
+    #[test]
+    fn test_cache_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("cache.json");
+        fs::write(&cache_path, b"{ not json").unwrap();
+
+        assert!(MetadataCache::open(&cache_path).is_empty());
+        assert!(MetadataCache::open(Path::new("/nonexistent/cache.json")).is_empty());
+    }
+}
@@ -0,0 +1,817 @@
+//! Duration analysis for MPEG audio streams.
+//!
+//! The duration is taken from a Xing, Info or VBRI header in the first frame when one is present,
+//! which is exact and cheap. Otherwise every frame header in the stream is walked and the samples
+//! counted, without decoding any audio. Anything which makes the result less than exact, such as
+//! junk between frames or a truncated final frame, is reported alongside the duration.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// How much of the stream to read when looking for the first frame and its VBR header, and how
+/// much is read at a time when counting frames.
+const PROBE_SIZE: u64 = 64 * 1024;
+
+/// The longest possible frame: MPEG-2 Layer II at 160kbit/s and 8kHz, with padding.
+const MAX_FRAME_LENGTH: usize = 2881;
+
+/// How much of the stream past a possible frame header is needed to find the header following it.
+const LOOKAHEAD: usize = MAX_FRAME_LENGTH + 4;
+
+static BITRATES_V1_L1: [u32; 15] = [
+    0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+];
+static BITRATES_V1_L2: [u32; 15] = [
+    0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+];
+static BITRATES_V1_L3: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+static BITRATES_V2_L1: [u32; 15] = [
+    0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+];
+static BITRATES_V2_L23: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum MpegVersion {
+    V1,
+    V2,
+    V25,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum MpegLayer {
+    I,
+    II,
+    III,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ChannelMode {
+    Stereo,
+    JointStereo,
+    DualChannel,
+    Mono,
+}
+
+/// A parsed MPEG audio frame header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameHeader {
+    pub version: MpegVersion,
+    pub layer: MpegLayer,
+    /// The bitrate of the frame in kbit/s.
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub padding: bool,
+    pub channel_mode: ChannelMode,
+}
+
+impl FrameHeader {
+    /// Parse a frame header, returning `None` if the bytes are not a valid header. Free-format
+    /// frames are rejected, as their length can't be known from the header alone.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 4 || bytes[0] != 0xff || bytes[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+
+        let version = match (bytes[1] >> 3) & 0b11 {
+            0b00 => MpegVersion::V25,
+            0b10 => MpegVersion::V2,
+            0b11 => MpegVersion::V1,
+            _ => return None,
+        };
+
+        let layer = match (bytes[1] >> 1) & 0b11 {
+            0b01 => MpegLayer::III,
+            0b10 => MpegLayer::II,
+            0b11 => MpegLayer::I,
+            _ => return None,
+        };
+
+        let bitrate_index = (bytes[2] >> 4) as usize;
+
+        if bitrate_index == 0 || bitrate_index == 0b1111 {
+            return None;
+        }
+
+        let bitrate = match (version, layer) {
+            (MpegVersion::V1, MpegLayer::I) => BITRATES_V1_L1[bitrate_index],
+            (MpegVersion::V1, MpegLayer::II) => BITRATES_V1_L2[bitrate_index],
+            (MpegVersion::V1, MpegLayer::III) => BITRATES_V1_L3[bitrate_index],
+            (_, MpegLayer::I) => BITRATES_V2_L1[bitrate_index],
+            (_, _) => BITRATES_V2_L23[bitrate_index],
+        };
+
+        let sample_rate = match ((bytes[2] >> 2) & 0b11, version) {
+            (0b11, _) => return None,
+            (index, MpegVersion::V1) => [44100, 48000, 32000][index as usize],
+            (index, MpegVersion::V2) => [22050, 24000, 16000][index as usize],
+            (index, MpegVersion::V25) => [11025, 12000, 8000][index as usize],
+        };
+
+        let channel_mode = match bytes[3] >> 6 {
+            0b00 => ChannelMode::Stereo,
+            0b01 => ChannelMode::JointStereo,
+            0b10 => ChannelMode::DualChannel,
+            _ => ChannelMode::Mono,
+        };
+
+        // the reserved emphasis value never occurs in a valid stream
+        if bytes[3] & 0b11 == 0b10 {
+            return None;
+        }
+
+        Some(FrameHeader {
+            version,
+            layer,
+            bitrate,
+            sample_rate,
+            padding: (bytes[2] >> 1) & 1 == 1,
+            channel_mode,
+        })
+    }
+
+    /// The number of audio samples (per channel) held in each frame.
+    pub fn samples_per_frame(&self) -> u32 {
+        match (self.layer, self.version) {
+            (MpegLayer::I, _) => 384,
+            (MpegLayer::II, _) => 1152,
+            (MpegLayer::III, MpegVersion::V1) => 1152,
+            (MpegLayer::III, _) => 576,
+        }
+    }
+
+    /// The length of the frame in bytes, including the header.
+    pub fn frame_length(&self) -> usize {
+        let bitrate = self.bitrate as usize * 1000;
+        let sample_rate = self.sample_rate as usize;
+        let padding = self.padding as usize;
+
+        match (self.layer, self.version) {
+            (MpegLayer::I, _) => (12 * bitrate / sample_rate + padding) * 4,
+            (MpegLayer::III, MpegVersion::V2) | (MpegLayer::III, MpegVersion::V25) => {
+                72 * bitrate / sample_rate + padding
+            }
+            (_, _) => 144 * bitrate / sample_rate + padding,
+        }
+    }
+
+    /// The offset of a Xing or Info header within the frame, which follows the side information.
+    fn xing_offset(&self) -> usize {
+        match (self.version, self.channel_mode) {
+            (MpegVersion::V1, ChannelMode::Mono) => 4 + 17,
+            (MpegVersion::V1, _) => 4 + 32,
+            (_, ChannelMode::Mono) => 4 + 9,
+            (_, _) => 4 + 17,
+        }
+    }
+
+    /// Whether another header could belong to the same stream as this one.
+    fn is_compatible(&self, other: &FrameHeader) -> bool {
+        self.version == other.version
+            && self.layer == other.layer
+            && self.sample_rate == other.sample_rate
+    }
+}
+
+/// Whether every frame of a stream has the same bitrate.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mp3BitrateMode {
+    Constant,
+    Variable,
+}
+
+/// Where the duration of a stream was taken from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DurationSource {
+    /// A Xing header, as written by VBR encoders.
+    Xing,
+    /// An Info header, which is a Xing header written for a CBR stream.
+    Info,
+    /// A VBRI header, as written by the Fraunhofer encoder.
+    VBRI,
+    /// Counting every frame in the stream.
+    FrameScan,
+}
+
+/// A reason that a duration may not be exact.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Uncertainty {
+    /// Data which wasn't a valid frame was skipped between frames.
+    Resynchronized { skipped_bytes: u64 },
+    /// The stream ended part-way through its final frame.
+    TruncatedFrame,
+}
+
+impl fmt::Display for Uncertainty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Uncertainty::Resynchronized { skipped_bytes } => write!(
+                f,
+                "skipped {} bytes of invalid data between frames",
+                skipped_bytes
+            ),
+            Uncertainty::TruncatedFrame => write!(f, "the final frame is truncated"),
+        }
+    }
+}
+
+/// The result of analysing an MPEG audio stream.
+#[derive(Clone, Debug)]
+pub struct Mp3Analysis {
+    /// The header of the first audio frame.
+    pub header: FrameHeader,
+    /// The number of audio frames in the stream.
+    pub frames: u64,
+    /// The size of the audio stream in bytes.
+    pub bytes: u64,
+    pub bitrate_mode: Mp3BitrateMode,
+    pub duration: Duration,
+    pub source: DurationSource,
+    /// Reasons that the duration may be inaccurate; empty if it is believed to be exact.
+    pub uncertainties: Vec<Uncertainty>,
+}
+
+impl Mp3Analysis {
+    pub fn is_exact(&self) -> bool {
+        self.uncertainties.is_empty()
+    }
+
+    /// The average bitrate of the stream in kbit/s.
+    pub fn average_bitrate(&self) -> u32 {
+        match self.duration.as_millis() {
+            0 => self.header.bitrate,
+            millis => (u128::from(self.bytes) * 8 / millis) as u32,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Mp3Error {
+    IOError {
+        err: io::Error,
+    },
+    /// No MPEG audio frames could be found in the stream.
+    NoFrames,
+}
+
+impl Error for Mp3Error {
+    fn description(&self) -> &str {
+        "Unable to analyse MPEG audio stream."
+    }
+}
+
+impl fmt::Display for Mp3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Mp3Error::IOError { ref err } => write!(f, "Unable to read MPEG audio stream: {}", err),
+            Mp3Error::NoFrames => write!(f, "No MPEG audio frames found in stream."),
+        }
+    }
+}
+
+impl From<io::Error> for Mp3Error {
+    fn from(e: io::Error) -> Self {
+        Mp3Error::IOError { err: e }
+    }
+}
+
+pub fn analyse_path<P: AsRef<Path>>(path: P) -> Result<Mp3Analysis, Mp3Error> {
+    analyse(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn analyse<R: Read + Seek>(reader: &mut R) -> Result<Mp3Analysis, Mp3Error> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    let start = id3v2_length(reader)?;
+    reader.seek(SeekFrom::Start(start))?;
+
+    let mut data = Vec::new();
+    reader.by_ref().take(PROBE_SIZE).read_to_end(&mut data)?;
+
+    if let Some(offset) = find_first_frame(&data) {
+        let header = FrameHeader::parse(&data[offset..]).unwrap();
+        let frame = &data[offset..data.len().min(offset + header.frame_length())];
+
+        // without a byte count in the VBR header, assume that the audio runs to the end of the file
+        let stream_bytes = length.saturating_sub(start + offset as u64);
+
+        if let Some(analysis) = read_vbr_header(&header, frame, stream_bytes) {
+            return Ok(analysis);
+        }
+    }
+
+    // no usable VBR header, so stream through the audio and count every frame
+    reader.seek(SeekFrom::Start(start))?;
+    scan_frames(reader)
+}
+
+/// Determine the length of a leading ID3v2 tag, if any, leaving the reader in an unknown position.
+fn id3v2_length<R: Read + Seek>(reader: &mut R) -> Result<u64, Mp3Error> {
+    let mut header = [0u8; 10];
+    reader.seek(SeekFrom::Start(0))?;
+
+    if reader.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        return Ok(0);
+    }
+
+    // the size is a 28-bit synchsafe integer which excludes the header and optional footer
+    let size = header[6..10]
+        .iter()
+        .fold(0u64, |size, &b| (size << 7) | u64::from(b & 0x7f));
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+
+    Ok(10 + size + footer)
+}
+
+/// Find the offset of the first frame header which is followed by another compatible header,
+/// which rules out most false syncs in junk data or album art.
+fn find_first_frame(data: &[u8]) -> Option<usize> {
+    find_frame(data, data.len())
+}
+
+/// Find the offset of the first frame header before the given limit which is followed by another
+/// compatible header. A frame with nothing after it in the data is accepted, as it may be the last.
+fn find_frame(data: &[u8], limit: usize) -> Option<usize> {
+    (0..limit).find(|&offset| match FrameHeader::parse(&data[offset..]) {
+        Some(header) => {
+            let next = offset + header.frame_length();
+
+            // accept a lone frame at the very end of the data
+            next >= data.len()
+                || FrameHeader::parse(&data[next..])
+                    .map(|h| h.is_compatible(&header))
+                    .unwrap_or(false)
+        }
+        None => false,
+    })
+}
+
+/// A window onto a stream, holding no more of it than is needed to walk the frames in it.
+struct Window<R> {
+    reader: R,
+    data: Vec<u8>,
+    offset: usize,
+    eof: bool,
+}
+
+impl<R: Read> Window<R> {
+    fn new(reader: R) -> Self {
+        Window {
+            reader,
+            data: Vec::new(),
+            offset: 0,
+            eof: false,
+        }
+    }
+
+    /// Buffer at least the given number of bytes past the current offset, unless the stream ends
+    /// first.
+    fn fill(&mut self, len: usize) -> io::Result<()> {
+        if self.data.len() - self.offset < len && !self.eof {
+            self.data.drain(..self.offset);
+            self.offset = 0;
+
+            let wanted = len.max(PROBE_SIZE as usize) - self.data.len();
+            let read = self
+                .reader
+                .by_ref()
+                .take(wanted as u64)
+                .read_to_end(&mut self.data)?;
+
+            self.eof = read < wanted;
+        }
+
+        Ok(())
+    }
+
+    /// Everything buffered from the current offset.
+    fn buffered(&self) -> &[u8] {
+        &self.data[self.offset..]
+    }
+
+    /// Skip to the next frame in the stream, returning the number of bytes skipped, and whether a
+    /// frame was found before the end of the stream.
+    fn skip_to_frame(&mut self) -> io::Result<(u64, bool)> {
+        let mut skipped = 0;
+
+        loop {
+            self.fill(PROBE_SIZE as usize)?;
+            let data = self.buffered();
+
+            // only search where there is enough data after a header to check the one following it
+            let limit = if self.eof {
+                data.len()
+            } else {
+                data.len() - LOOKAHEAD
+            };
+
+            if let Some(offset) = find_frame(data, limit) {
+                self.offset += offset;
+                return Ok((skipped + offset as u64, true));
+            }
+
+            skipped += limit as u64;
+            self.offset += limit;
+
+            if self.eof {
+                return Ok((skipped, false));
+            }
+        }
+    }
+}
+
+/// Read a Xing, Info or VBRI header from the given first frame.
+fn read_vbr_header(header: &FrameHeader, frame: &[u8], stream_bytes: u64) -> Option<Mp3Analysis> {
+    let xing = header.xing_offset();
+
+    if frame.len() >= xing + 8
+        && (&frame[xing..xing + 4] == b"Xing" || &frame[xing..xing + 4] == b"Info")
+    {
+        let source = if &frame[xing..xing + 4] == b"Xing" {
+            DurationSource::Xing
+        } else {
+            DurationSource::Info
+        };
+
+        let flags = read_u32_be(&frame[xing + 4..]);
+
+        // without a frame count the header is no use for the duration
+        if flags & 0x1 == 0 || frame.len() < xing + 12 {
+            return None;
+        }
+
+        let frames = u64::from(read_u32_be(&frame[xing + 8..]));
+
+        // nor is a count of no frames, as written by encoders which never filled it in
+        if frames == 0 {
+            return None;
+        }
+
+        let bytes = if flags & 0x2 != 0 && frame.len() >= xing + 16 {
+            u64::from(read_u32_be(&frame[xing + 12..]))
+        } else {
+            stream_bytes
+        };
+
+        // skip the optional byte count, table of contents and quality fields to find the LAME tag
+        let mut lame = xing + 12;
+        lame += if flags & 0x2 != 0 { 4 } else { 0 };
+        lame += if flags & 0x4 != 0 { 100 } else { 0 };
+        lame += if flags & 0x8 != 0 { 4 } else { 0 };
+
+        let samples = frames * u64::from(header.samples_per_frame());
+        let samples = samples.saturating_sub(lame_gapless_samples(&frame[lame.min(frame.len())..]));
+
+        return Some(Mp3Analysis {
+            header: *header,
+            frames,
+            bytes,
+            bitrate_mode: match source {
+                DurationSource::Info => Mp3BitrateMode::Constant,
+                _ => Mp3BitrateMode::Variable,
+            },
+            duration: samples_to_duration(samples, header.sample_rate),
+            source,
+            uncertainties: Vec::new(),
+        });
+    }
+
+    // the VBRI header always sits 32 bytes after the frame header
+    if frame.len() >= 4 + 32 + 18 && &frame[36..40] == b"VBRI" {
+        let bytes = u64::from(read_u32_be(&frame[36 + 10..]));
+        let frames = u64::from(read_u32_be(&frame[36 + 14..]));
+
+        if frames == 0 {
+            return None;
+        }
+
+        let samples = frames * u64::from(header.samples_per_frame());
+
+        return Some(Mp3Analysis {
+            header: *header,
+            frames,
+            bytes,
+            bitrate_mode: Mp3BitrateMode::Variable,
+            duration: samples_to_duration(samples, header.sample_rate),
+            source: DurationSource::VBRI,
+            uncertainties: Vec::new(),
+        });
+    }
+
+    None
+}
+
+/// Read the encoder delay and padding from a LAME tag, returning the number of samples they add.
+fn lame_gapless_samples(tag: &[u8]) -> u64 {
+    if tag.len() < 24 || (&tag[..4] != b"LAME" && &tag[..4] != b"Lavf" && &tag[..4] != b"Lavc") {
+        return 0;
+    }
+
+    // two 12-bit values packed into three bytes, 21 bytes into the tag
+    let delay = (u64::from(tag[21]) << 4) | (u64::from(tag[22]) >> 4);
+    let padding = (u64::from(tag[22] & 0x0f) << 8) | u64::from(tag[23]);
+
+    delay + padding
+}
+
+/// Walk every frame header in the stream, counting frames and samples.
+fn scan_frames<R: Read>(reader: R) -> Result<Mp3Analysis, Mp3Error> {
+    let mut window = Window::new(reader);
+
+    if !window.skip_to_frame()?.1 {
+        return Err(Mp3Error::NoFrames);
+    }
+
+    let first = FrameHeader::parse(window.buffered()).unwrap();
+
+    let (mut frames, mut samples, mut bytes, mut skipped) = (0u64, 0u64, 0u64, 0u64);
+    let mut bitrate_mode = Mp3BitrateMode::Constant;
+    let mut uncertainties = Vec::new();
+
+    loop {
+        window.fill(LOOKAHEAD)?;
+        let data = window.buffered();
+
+        if data.is_empty() {
+            break;
+        }
+
+        match FrameHeader::parse(data) {
+            Some(header) if header.is_compatible(&first) => {
+                let length = header.frame_length();
+
+                if length > data.len() {
+                    uncertainties.push(Uncertainty::TruncatedFrame);
+                    break;
+                }
+
+                if header.bitrate != first.bitrate {
+                    bitrate_mode = Mp3BitrateMode::Variable;
+                }
+
+                frames += 1;
+                samples += u64::from(header.samples_per_frame());
+                bytes += length as u64;
+                window.offset += length;
+            }
+            _ => {
+                // trailing ID3v1 and APEv2 tags end the stream
+                if is_trailing_tag(data) {
+                    break;
+                }
+
+                window.offset += 1;
+                let (junk, found) = window.skip_to_frame()?;
+                skipped += junk + 1;
+
+                if !found {
+                    break;
+                }
+            }
+        }
+    }
+
+    if skipped > 0 {
+        uncertainties.insert(
+            0,
+            Uncertainty::Resynchronized {
+                skipped_bytes: skipped,
+            },
+        );
+    }
+
+    Ok(Mp3Analysis {
+        header: first,
+        frames,
+        bytes,
+        bitrate_mode,
+        duration: samples_to_duration(samples, first.sample_rate),
+        source: DurationSource::FrameScan,
+        uncertainties,
+    })
+}
+
+fn is_trailing_tag(data: &[u8]) -> bool {
+    data.starts_with(b"TAG") || data.starts_with(b"APETAGEX") || data.starts_with(b"LYRICSBEGIN")
+}
+
+fn samples_to_duration(samples: u64, sample_rate: u32) -> Duration {
+    Duration::from_nanos((u128::from(samples) * 1_000_000_000 / u128::from(sample_rate)) as u64)
+}
+
+fn read_u32_be(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_be_bytes(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// MPEG-1 Layer III, 128kbit/s, 44.1kHz, joint stereo: 417 bytes per frame.
+    static HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0x64];
+
+    fn frame() -> Vec<u8> {
+        let mut frame = HEADER.to_vec();
+        frame.resize(417, 0);
+        frame
+    }
+
+    fn frames(count: usize) -> Vec<u8> {
+        (0..count).flat_map(|_| frame()).collect()
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let header = FrameHeader::parse(&HEADER).unwrap();
+
+        assert_eq!(MpegVersion::V1, header.version);
+        assert_eq!(MpegLayer::III, header.layer);
+        assert_eq!(128, header.bitrate);
+        assert_eq!(44100, header.sample_rate);
+        assert_eq!(ChannelMode::JointStereo, header.channel_mode);
+        assert_eq!(417, header.frame_length());
+        assert_eq!(1152, header.samples_per_frame());
+
+        // reserved version, layer, bitrate and sample rate
+        assert!(FrameHeader::parse(&[0xff, 0xeb, 0x90, 0x64]).is_none());
+        assert!(FrameHeader::parse(&[0xff, 0xf9, 0x90, 0x64]).is_none());
+        assert!(FrameHeader::parse(&[0xff, 0xfb, 0xf0, 0x64]).is_none());
+        assert!(FrameHeader::parse(&[0xff, 0xfb, 0x9c, 0x64]).is_none());
+        assert!(FrameHeader::parse(&[0xff, 0xfb]).is_none());
+    }
+
+    #[test]
+    fn test_scan_frames() {
+        // 100 frames of 1152 samples at 44.1kHz
+        let analysis = analyse(&mut Cursor::new(frames(100))).unwrap();
+
+        assert_eq!(DurationSource::FrameScan, analysis.source);
+        assert_eq!(100, analysis.frames);
+        assert_eq!(Duration::from_nanos(2_612_244_897), analysis.duration);
+        assert_eq!(Mp3BitrateMode::Constant, analysis.bitrate_mode);
+        assert_eq!(127, analysis.average_bitrate());
+        assert!(analysis.is_exact());
+    }
+
+    #[test]
+    fn test_scan_frames_with_tags() {
+        let mut data = b"ID3\x03\x00\x00\x00\x00\x00\x05hello".to_vec();
+        data.extend(frames(10));
+        data.extend_from_slice(b"TAG");
+        data.resize(data.len() + 125, b' ');
+
+        let analysis = analyse(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(10, analysis.frames);
+        assert!(analysis.is_exact());
+    }
+
+    #[test]
+    fn test_scan_frames_uncertain() {
+        let mut data = frames(5);
+        data.extend_from_slice(&[0x12; 50]);
+        data.extend(frames(5));
+        data.extend_from_slice(&frame()[..100]);
+
+        let analysis = analyse(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(10, analysis.frames);
+        assert_eq!(
+            vec![
+                Uncertainty::Resynchronized { skipped_bytes: 50 },
+                Uncertainty::TruncatedFrame
+            ],
+            analysis.uncertainties
+        );
+    }
+
+    #[test]
+    fn test_scan_frames_streamed() {
+        // junk straddling the end of the first chunk read
+        let mut data = frames(157);
+        data.extend_from_slice(&[0x12; 100]);
+        data.extend(frames(200));
+
+        let analysis = analyse(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(357, analysis.frames);
+        assert_eq!(
+            vec![Uncertainty::Resynchronized { skipped_bytes: 100 }],
+            analysis.uncertainties
+        );
+    }
+
+    #[test]
+    fn test_xing_header() {
+        let mut first = frame();
+        first[36..40].copy_from_slice(b"Xing");
+        first[40..44].copy_from_slice(&3u32.to_be_bytes());
+        first[44..48].copy_from_slice(&1000u32.to_be_bytes());
+        first[48..52].copy_from_slice(&4_000_000u32.to_be_bytes());
+        // LAME tag with an encoder delay of 576 and padding of 1152
+        first[52..56].copy_from_slice(b"LAME");
+        first[52 + 21..52 + 24].copy_from_slice(&[0x24, 0x04, 0x80]);
+
+        let mut data = first;
+        data.extend(frames(3));
+
+        let analysis = analyse(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(DurationSource::Xing, analysis.source);
+        assert_eq!(Mp3BitrateMode::Variable, analysis.bitrate_mode);
+        assert_eq!(1000, analysis.frames);
+        assert_eq!(4_000_000, analysis.bytes);
+        assert_eq!(
+            samples_to_duration(1000 * 1152 - 576 - 1152, 44100),
+            analysis.duration
+        );
+    }
+
+    #[test]
+    fn test_empty_vbr_header() {
+        let mut first = frame();
+        first[36..40].copy_from_slice(b"Xing");
+        first[40..44].copy_from_slice(&1u32.to_be_bytes());
+
+        let mut data = first;
+        data.extend(frames(3));
+
+        // a header counting no frames is ignored in favour of counting them
+        let analysis = analyse(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(DurationSource::FrameScan, analysis.source);
+        assert_eq!(4, analysis.frames);
+        assert_eq!(samples_to_duration(4 * 1152, 44100), analysis.duration);
+
+        let mut first = frame();
+        first[36..40].copy_from_slice(b"VBRI");
+
+        let mut data = first;
+        data.extend(frames(3));
+
+        let analysis = analyse(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(DurationSource::FrameScan, analysis.source);
+        assert_eq!(4, analysis.frames);
+    }
+
+    #[test]
+    fn test_vbri_header() {
+        let mut first = frame();
+        first[36..40].copy_from_slice(b"VBRI");
+        first[50..54].copy_from_slice(&441u32.to_be_bytes());
+
+        let mut data = first;
+        data.extend(frames(3));
+
+        let analysis = analyse(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(DurationSource::VBRI, analysis.source);
+        assert_eq!(441, analysis.frames);
+        assert_eq!(samples_to_duration(441 * 1152, 44100), analysis.duration);
+    }
+
+    #[test]
+    fn test_no_frames() {
+        match analyse(&mut Cursor::new(vec![0x55u8; 4096])) {
+            Err(Mp3Error::NoFrames) => (),
+            other => panic!("expected no frames, got {:?}", other),
+        }
+
+        match analyse(&mut Cursor::new(Vec::new())) {
+            Err(Mp3Error::NoFrames) => (),
+            other => panic!("expected no frames, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_garbage_never_panics() {
+        // pseudo-random data littered with sync bytes
+        let mut state = 0x2545_f491u32;
+        let data: Vec<u8> = (0..64 * 1024)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                if i % 7 == 0 {
+                    0xff
+                } else {
+                    state as u8
+                }
+            })
+            .collect();
+
+        let _ = analyse(&mut Cursor::new(data));
+    }
+}
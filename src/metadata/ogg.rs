@@ -0,0 +1,462 @@
+//! A minimal reader for Ogg Vorbis streams.
+//!
+//! Only as much of the container is understood as is needed to pull out the Vorbis
+//! identification and comment headers and the granule position of the final page, which is all
+//! that is required to build a `MediaMetadata`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+static CAPTURE_PATTERN: &[u8] = b"OggS";
+
+/// The fixed size of an Ogg page header, excluding the segment table.
+const PAGE_HEADER_SIZE: usize = 27;
+/// How far back from the end of the stream to look for the last page on each attempt.
+const TAIL_SEARCH_WINDOW: u64 = 64 * 1024;
+
+const VORBIS_IDENTIFICATION_HEADER: u8 = 0x01;
+const VORBIS_COMMENT_HEADER: u8 = 0x03;
+
+/// The properties and tags of an Ogg Vorbis stream.
+#[derive(Debug)]
+pub struct OggVorbis {
+    pub channels: u8,
+    pub sample_rate: u32,
+    pub nominal_bitrate: u32,
+    pub comments: VorbisComments,
+    pub total_samples: u64,
+}
+
+impl OggVorbis {
+    pub fn read_from_path<P: AsRef<Path>>(path: P) -> Result<Self, OggError> {
+        OggVorbis::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Self, OggError> {
+        let mut packets = PacketReader::new(reader);
+
+        let ident = packets.next_packet()?;
+
+        if ident.len() < 30 || ident[0] != VORBIS_IDENTIFICATION_HEADER || &ident[1..7] != b"vorbis"
+        {
+            return Err(OggError::Malformed {
+                reason: "first packet is not a Vorbis identification header",
+            });
+        }
+
+        let channels = ident[11];
+        let sample_rate = read_u32(&ident[12..16]);
+        let nominal_bitrate = read_u32(&ident[20..24]);
+
+        let comment = packets.next_packet()?;
+
+        if comment.len() < 7 || comment[0] != VORBIS_COMMENT_HEADER || &comment[1..7] != b"vorbis" {
+            return Err(OggError::Malformed {
+                reason: "second packet is not a Vorbis comment header",
+            });
+        }
+
+        let comments = VorbisComments::parse(&comment[7..])?;
+        let serial = packets.serial.unwrap_or_default();
+        let total_samples = last_granule_position(packets.reader, serial)?;
+
+        Ok(OggVorbis {
+            channels,
+            sample_rate,
+            // a nominal bitrate of zero or less means that it is unset
+            nominal_bitrate: if (nominal_bitrate as i32) > 0 {
+                nominal_bitrate
+            } else {
+                0
+            },
+            comments,
+            total_samples,
+        })
+    }
+
+    /// The duration of the stream, derived from the granule position of its last page.
+    pub fn duration(&self) -> Duration {
+        if self.sample_rate == 0 {
+            return Duration::new(0, 0);
+        }
+
+        Duration::from_nanos(
+            (u128::from(self.total_samples) * 1_000_000_000 / u128::from(self.sample_rate)) as u64,
+        )
+    }
+}
+
+/// The user comments of a Vorbis comment header, keyed by upper-case field name.
+#[derive(Debug, Default)]
+pub struct VorbisComments {
+    pub vendor: String,
+    comments: HashMap<String, Vec<String>>,
+}
+
+impl VorbisComments {
+    fn parse(data: &[u8]) -> Result<Self, OggError> {
+        let mut cursor = data;
+        let vendor = String::from_utf8_lossy(read_field(&mut cursor)?).into_owned();
+        let count = read_u32(take(&mut cursor, 4)?);
+
+        let mut comments: HashMap<String, Vec<String>> = HashMap::new();
+
+        for _ in 0..count {
+            let field = String::from_utf8_lossy(read_field(&mut cursor)?).into_owned();
+
+            // entries without a separator aren't valid comments, so skip them
+            if let Some(index) = field.find('=') {
+                comments
+                    .entry(field[..index].to_ascii_uppercase())
+                    .or_default()
+                    .push(field[index + 1..].to_string());
+            }
+        }
+
+        Ok(VorbisComments { vendor, comments })
+    }
+
+    /// Get the values of the given field, which is matched case-insensitively.
+    pub fn get<'a>(&'a self, key: &str) -> Option<impl Iterator<Item = &'a str> + 'a> {
+        self.comments
+            .get(&key.to_ascii_uppercase())
+            .map(|values| values.iter().map(|s| s.as_str()))
+    }
+}
+
+#[derive(Debug)]
+pub enum OggError {
+    IOError { err: io::Error },
+    Malformed { reason: &'static str },
+}
+
+impl Error for OggError {
+    fn description(&self) -> &str {
+        "Unable to read Ogg Vorbis stream."
+    }
+}
+
+impl fmt::Display for OggError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OggError::IOError { ref err } => write!(f, "Unable to read Ogg stream: {}", err),
+            OggError::Malformed { reason } => write!(f, "Malformed Ogg Vorbis stream: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for OggError {
+    fn from(e: io::Error) -> Self {
+        OggError::IOError { err: e }
+    }
+}
+
+/// The parsed fixed-size portion of an Ogg page header.
+struct PageHeader {
+    granule_position: u64,
+    serial: u32,
+    segments: Vec<u8>,
+}
+
+impl PageHeader {
+    fn read<R: Read>(reader: &mut R) -> Result<Self, OggError> {
+        let mut header = [0u8; PAGE_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+
+        if &header[0..4] != CAPTURE_PATTERN {
+            return Err(OggError::Malformed {
+                reason: "missing page capture pattern",
+            });
+        }
+
+        let mut segments = vec![0u8; header[26] as usize];
+        reader.read_exact(&mut segments)?;
+
+        Ok(PageHeader {
+            granule_position: read_u64(&header[6..14]),
+            serial: read_u32(&header[14..18]),
+            segments,
+        })
+    }
+}
+
+/// Reassembles packets of the first logical bitstream from a sequence of Ogg pages.
+struct PacketReader<'a, R> {
+    reader: &'a mut R,
+    serial: Option<u32>,
+    pending: Vec<u8>,
+    lacing: Vec<u8>,
+}
+
+impl<'a, R: Read> PacketReader<'a, R> {
+    fn new(reader: &'a mut R) -> Self {
+        PacketReader {
+            reader,
+            serial: None,
+            pending: Vec::new(),
+            lacing: Vec::new(),
+        }
+    }
+
+    fn next_packet(&mut self) -> Result<Vec<u8>, OggError> {
+        let mut packet = Vec::new();
+
+        loop {
+            // drain lacing values from the current page; a value under 255 ends the packet
+            while !self.lacing.is_empty() {
+                let size = self.lacing.remove(0) as usize;
+
+                if size > self.pending.len() {
+                    return Err(OggError::Malformed {
+                        reason: "page is shorter than its segment table",
+                    });
+                }
+
+                packet.extend(self.pending.drain(..size));
+
+                if size < 255 {
+                    return Ok(packet);
+                }
+            }
+
+            let page = PageHeader::read(self.reader)?;
+
+            if self.serial.is_none() {
+                self.serial = Some(page.serial);
+            } else if self.serial != Some(page.serial) {
+                // skip pages belonging to other multiplexed streams
+                let size: u64 = page.segments.iter().map(|&s| u64::from(s)).sum();
+                io::copy(&mut self.reader.by_ref().take(size), &mut io::sink())?;
+                continue;
+            }
+
+            let size: usize = page.segments.iter().map(|&s| s as usize).sum();
+            self.pending = vec![0u8; size];
+            self.reader.read_exact(&mut self.pending)?;
+            self.lacing = page.segments;
+        }
+    }
+}
+
+/// Find the granule position of the last page of the given stream, which for Vorbis is the total
+/// number of samples in the stream.
+fn last_granule_position<R: Read + Seek>(reader: &mut R, serial: u32) -> Result<u64, OggError> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    let mut end = length;
+
+    while end > 0 {
+        let start = end.saturating_sub(TAIL_SEARCH_WINDOW);
+        let mut window = vec![0u8; (end - start) as usize];
+
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(&mut window)?;
+
+        // walk backwards through every capture pattern in the window
+        let mut index = window.len().saturating_sub(CAPTURE_PATTERN.len());
+
+        loop {
+            if window[index..].starts_with(CAPTURE_PATTERN) {
+                reader.seek(SeekFrom::Start(start + index as u64))?;
+
+                if let Ok(page) = PageHeader::read(reader) {
+                    // a granule position of -1 means that no packet finishes on this page
+                    if page.serial == serial && page.granule_position != u64::MAX {
+                        return Ok(page.granule_position);
+                    }
+                }
+            }
+
+            if index == 0 {
+                break;
+            }
+
+            index -= 1;
+        }
+
+        // overlap windows slightly so that a capture pattern split across them isn't missed
+        end = if start == 0 {
+            0
+        } else {
+            start + CAPTURE_PATTERN.len() as u64 - 1
+        };
+    }
+
+    Err(OggError::Malformed {
+        reason: "unable to find a page with a granule position",
+    })
+}
+
+fn take<'a>(cursor: &mut &'a [u8], count: usize) -> Result<&'a [u8], OggError> {
+    if cursor.len() < count {
+        return Err(OggError::Malformed {
+            reason: "comment header is truncated",
+        });
+    }
+
+    let (head, tail) = cursor.split_at(count);
+    *cursor = tail;
+    Ok(head)
+}
+
+fn read_field<'a>(cursor: &mut &'a [u8]) -> Result<&'a [u8], OggError> {
+    let length = read_u32(take(cursor, 4)?) as usize;
+    take(cursor, length)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// Render a single Ogg page holding the given packets, which must each fit within the page.
+    pub fn page(serial: u32, granule: u64, packets: &[&[u8]]) -> Vec<u8> {
+        let mut segments = Vec::new();
+        let mut body = Vec::new();
+
+        for packet in packets {
+            let mut remaining = packet.len();
+
+            while remaining >= 255 {
+                segments.push(255u8);
+                remaining -= 255;
+            }
+
+            segments.push(remaining as u8);
+            body.extend_from_slice(packet);
+        }
+
+        let mut page = Vec::new();
+        page.extend_from_slice(b"OggS");
+        page.push(0);
+        page.push(0);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&0u32.to_le_bytes());
+        page.extend_from_slice(&0u32.to_le_bytes());
+        page.push(segments.len() as u8);
+        page.extend_from_slice(&segments);
+        page.extend_from_slice(&body);
+        page
+    }
+
+    pub fn identification_header(channels: u8, sample_rate: u32) -> Vec<u8> {
+        let mut header = vec![VORBIS_IDENTIFICATION_HEADER];
+        header.extend_from_slice(b"vorbis");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.push(channels);
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&160_000i32.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.push(0xb8);
+        header.push(1);
+        header
+    }
+
+    pub fn comment_header(comments: &[&str]) -> Vec<u8> {
+        let vendor = b"phatnoise";
+        let mut header = vec![VORBIS_COMMENT_HEADER];
+        header.extend_from_slice(b"vorbis");
+        header.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        header.extend_from_slice(vendor);
+        header.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+
+        for comment in comments {
+            header.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            header.extend_from_slice(comment.as_bytes());
+        }
+
+        header.push(1);
+        header
+    }
+
+    pub fn stream(comments: &[&str], total_samples: u64) -> Vec<u8> {
+        let mut data = page(1, 0, &[&identification_header(2, 44100)]);
+        data.extend(page(1, 0, &[&comment_header(comments)]));
+        data.extend(page(1, total_samples / 2, &[&[0u8; 64]]));
+        data.extend(page(1, total_samples, &[&[0u8; 64]]));
+        data
+    }
+
+    #[test]
+    fn test_read_vorbis() {
+        let data = stream(&["ARTIST=Artist", "album=Album", "GENRE=Jazz"], 441_000);
+        let vorbis = OggVorbis::read_from(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(2, vorbis.channels);
+        assert_eq!(44100, vorbis.sample_rate);
+        assert_eq!(160_000, vorbis.nominal_bitrate);
+        assert_eq!("phatnoise", vorbis.comments.vendor);
+        assert_eq!(
+            Some("Album"),
+            vorbis.comments.get("ALBUM").and_then(|mut v| v.next())
+        );
+        assert_eq!(
+            Some("Artist"),
+            vorbis.comments.get("artist").and_then(|mut v| v.next())
+        );
+        assert!(vorbis.comments.get("TITLE").is_none());
+        assert_eq!(Duration::from_secs(10), vorbis.duration());
+    }
+
+    #[test]
+    fn test_read_vorbis_spanning_pages() {
+        // comment header split across two pages
+        let title = format!("TITLE={}", "Long Title ".repeat(40));
+        let comment = comment_header(&[&title]);
+        let (head, tail) = comment.split_at(255);
+
+        let mut data = page(7, 0, &[&identification_header(1, 22050)]);
+        let mut continued = page(7, 0, &[head]);
+        // an exact multiple of 255 needs no terminating lacing value on this page
+        continued[26] -= 1;
+        continued.remove(PAGE_HEADER_SIZE + continued[26] as usize);
+        data.extend(continued);
+        data.extend(page(7, 22050, &[tail]));
+
+        let vorbis = OggVorbis::read_from(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(
+            Some(&title[6..]),
+            vorbis.comments.get("TITLE").and_then(|mut v| v.next())
+        );
+        assert_eq!(Duration::from_secs(1), vorbis.duration());
+    }
+
+    #[test]
+    fn test_read_not_vorbis() {
+        let data = page(1, 0, &[b"OpusHead\x01\x02"]);
+
+        match OggVorbis::read_from(&mut Cursor::new(data)) {
+            Err(OggError::Malformed { .. }) => (),
+            other => panic!("expected a malformed stream error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        let mut data = stream(&["ARTIST=Artist"], 1000);
+        data.truncate(40);
+
+        assert!(OggVorbis::read_from(&mut Cursor::new(data)).is_err());
+    }
+}
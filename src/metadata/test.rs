@@ -2,26 +2,28 @@ use std::path::Path;
 
 use super::*;
 
-use std::mem::size_of;
+fn flac_tag(path: &str) -> metaflac::Tag {
+    metaflac::Tag::read_from_path(Path::new(path)).unwrap()
+}
 
 #[test]
 fn test_get_artist_flac() {
     // should return default artist on blank flac
     assert_eq!(
         DEFAULT_ARTIST,
-        get_artist_flac(Path::new("test/fixtures/flac/blank.flac")).unwrap()
+        get_artist_flac(&flac_tag("test/fixtures/flac/blank.flac"))
     );
     assert_eq!(
         "Album Artist",
-        get_artist_flac(Path::new("test/fixtures/flac/albumartist.flac")).unwrap()
+        get_artist_flac(&flac_tag("test/fixtures/flac/albumartist.flac"))
     );
     assert_eq!(
         "Artist",
-        get_artist_flac(Path::new("test/fixtures/flac/artist.flac")).unwrap()
+        get_artist_flac(&flac_tag("test/fixtures/flac/artist.flac"))
     );
     assert_eq!(
         "Composer",
-        get_artist_flac(Path::new("test/fixtures/flac/composer.flac")).unwrap()
+        get_artist_flac(&flac_tag("test/fixtures/flac/composer.flac"))
     );
 }
 
@@ -30,11 +32,11 @@ fn test_get_album_flac() {
     // should return default album on blank flac
     assert_eq!(
         DEFAULT_ALBUM,
-        get_album_flac(Path::new("test/fixtures/flac/blank.flac")).unwrap()
+        get_album_flac(&flac_tag("test/fixtures/flac/blank.flac"))
     );
     assert_eq!(
         "Album",
-        get_album_flac(Path::new("test/fixtures/flac/album.flac")).unwrap()
+        get_album_flac(&flac_tag("test/fixtures/flac/album.flac"))
     );
 }
 
@@ -43,15 +45,220 @@ fn test_get_genre_flac() {
     // should return default genre on blank flac
     assert_eq!(
         DEFAULT_GENRE,
-        get_genre_flac(Path::new("test/fixtures/flac/blank.flac")).unwrap()
+        get_genre_flac(&flac_tag("test/fixtures/flac/blank.flac"))
+    );
+    assert_eq!(
+        "Genre",
+        get_genre_flac(&flac_tag("test/fixtures/flac/genre.flac"))
+    );
+    assert_eq!(
+        "Style 1",
+        get_genre_flac(&flac_tag("test/fixtures/flac/style.flac"))
+    );
+}
+
+#[test]
+fn test_get_title_flac() {
+    assert_eq!(
+        DEFAULT_TITLE,
+        get_title_flac(&flac_tag("test/fixtures/flac/blank.flac"))
+    );
+}
+
+#[test]
+fn test_get_duration_flac() {
+    // the fixtures hold 4410 samples at 44.1kHz
+    assert_eq!(
+        Duration::from_millis(100),
+        get_duration_flac(get_streaminfo_flac(&flac_tag("test/fixtures/flac/blank.flac")).unwrap())
+    );
+}
+
+#[test]
+fn test_get_properties_flac_without_streaminfo() {
+    let tag = metaflac::Tag::new();
+
+    assert!(get_streaminfo_flac(&tag).is_err());
+    assert!(get_properties_flac(&tag, Path::new("test/fixtures/flac/blank.flac")).is_err());
+}
+
+#[test]
+fn test_parse_track_number() {
+    assert_eq!(7, parse_track_number("7"));
+    assert_eq!(3, parse_track_number("03/12"));
+    assert_eq!(0, parse_track_number(""));
+    assert_eq!(0, parse_track_number("side a"));
+    assert_eq!(0, parse_track_number("99999999"));
+}
+
+#[test]
+fn test_load_flac() {
+    let pool = StringPool::new();
+    let metadata = MediaMetadata::load(
+        Path::new("test/fixtures/flac/albumartist.flac"),
+        Path::new("test/fixtures"),
+        &pool,
+    )
+    .unwrap();
+
+    assert_eq!("Album Artist", &*metadata.artist);
+    assert_eq!(DEFAULT_ALBUM, &*metadata.album);
+    assert_eq!(DEFAULT_TITLE, metadata.title);
+    assert_eq!(0, metadata.track_number);
+    assert_eq!(0, metadata.duration);
+    assert_eq!(Codec::FLAC, metadata.properties.codec);
+    assert_eq!(44100, metadata.properties.sample_rate);
+    assert_eq!(1, metadata.properties.channels);
+    assert_eq!(100, metadata.properties.duration_ms());
+    assert!(metadata.properties.mpeg_version.is_none());
+}
+
+#[test]
+fn test_load_mp3_properties() {
+    let pool = StringPool::new();
+    let metadata = MediaMetadata::load(
+        Path::new("test/fixtures/id3/talb.mp3"),
+        Path::new("test/fixtures"),
+        &pool,
+    )
+    .unwrap();
+    let properties = &metadata.properties;
+
+    assert_eq!("The Album", &*metadata.album);
+    assert_eq!(Codec::MP3, properties.codec);
+    assert_eq!(BitrateMode::Constant, properties.bitrate_mode);
+    assert_eq!(44100, properties.sample_rate);
+    assert_eq!(1, properties.channels);
+    assert_eq!(Some(ChannelMode::Mono), properties.channel_mode);
+    assert_eq!(Some(MpegVersion::V1), properties.mpeg_version);
+    assert_eq!(Some(MpegLayer::III), properties.mpeg_layer);
+    assert_eq!(100, properties.duration_ms());
+    assert_eq!(0, metadata.duration);
+}
+
+#[test]
+fn test_load_mp3_without_frames() {
+    let pool = StringPool::new();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tag-only.mp3");
+
+    // keep only the ID3 tag of the fixture
+    let data = std::fs::read("test/fixtures/id3/talb.mp3").unwrap();
+    let size = data[6..10]
+        .iter()
+        .fold(0usize, |size, &b| (size << 7) | usize::from(b & 0x7f));
+    std::fs::write(&path, &data[..10 + size]).unwrap();
+
+    let metadata = MediaMetadata::load(&path, dir.path(), &pool).unwrap();
+
+    assert_eq!("The Album", &*metadata.album);
+    assert_eq!(Codec::MP3, metadata.properties.codec);
+    assert_eq!(BitrateMode::Unknown, metadata.properties.bitrate_mode);
+    assert_eq!(0, metadata.properties.duration_ms());
+    assert_eq!(0, metadata.duration);
+}
+
+#[test]
+fn test_load_unrecognized() {
+    let pool = StringPool::new();
+
+    match MediaMetadata::load(Path::new("README.md"), Path::new("."), &pool) {
+        Err(MediaParsingError::UnrecognizedFormat) => (),
+        _ => panic!("expected an unrecognized format error"),
+    }
+}
+
+fn vorbis_comments(comments: &[&str]) -> VorbisComments {
+    let data = ogg::test::stream(comments, 44100);
+    OggVorbis::read_from(&mut std::io::Cursor::new(data))
+        .unwrap()
+        .comments
+}
+
+#[test]
+fn test_get_artist_vorbis() {
+    assert_eq!(DEFAULT_ARTIST, get_artist_vorbis(&vorbis_comments(&[])));
+    assert_eq!(
+        "Album Artist",
+        get_artist_vorbis(&vorbis_comments(&[
+            "ARTIST=Artist",
+            "ALBUMARTIST=Album Artist"
+        ]))
+    );
+    assert_eq!(
+        "Artist",
+        get_artist_vorbis(&vorbis_comments(&["ALBUMARTIST=", "ARTIST=Artist"]))
     );
+    assert_eq!(
+        "Composer",
+        get_artist_vorbis(&vorbis_comments(&["composer=Composer"]))
+    );
+}
+
+#[test]
+fn test_get_genre_vorbis() {
+    assert_eq!(DEFAULT_GENRE, get_genre_vorbis(&vorbis_comments(&[])));
     assert_eq!(
         "Genre",
-        get_genre_flac(Path::new("test/fixtures/flac/genre.flac")).unwrap()
+        get_genre_vorbis(&vorbis_comments(&["STYLE=Style 1", "GENRE=Genre"]))
     );
     assert_eq!(
         "Style 1",
-        get_genre_flac(Path::new("test/fixtures/flac/style.flac")).unwrap()
+        get_genre_vorbis(&vorbis_comments(&["STYLE=Style 1", "STYLE=Style 2"]))
+    );
+}
+
+#[test]
+fn test_get_track_number_vorbis() {
+    assert_eq!(0, get_track_number_vorbis(&vorbis_comments(&[])));
+    assert_eq!(
+        4,
+        get_track_number_vorbis(&vorbis_comments(&["TRACKNUMBER=4/10"]))
+    );
+}
+
+fn asf_tag(objects: &[Vec<u8>]) -> AsfTag {
+    AsfTag::read_from(&mut std::io::Cursor::new(asf::test::header(objects))).unwrap()
+}
+
+#[test]
+fn test_get_artist_asf() {
+    assert_eq!(DEFAULT_ARTIST, get_artist_asf(&asf_tag(&[])));
+    assert_eq!(
+        "Author",
+        get_artist_asf(&asf_tag(&[asf::test::content_description("", "Author")]))
+    );
+    assert_eq!(
+        "Album Artist",
+        get_artist_asf(&asf_tag(&[
+            asf::test::content_description("", "Author"),
+            asf::test::extended_content_description(&[(
+                "WM/AlbumArtist",
+                0,
+                asf::test::utf16("Album Artist")
+            )]),
+        ]))
+    );
+}
+
+#[test]
+fn test_get_track_number_asf() {
+    assert_eq!(0, get_track_number_asf(&asf_tag(&[])));
+    assert_eq!(
+        5,
+        get_track_number_asf(&asf_tag(&[asf::test::extended_content_description(&[(
+            "WM/TrackNumber",
+            0,
+            asf::test::utf16("5/12")
+        )])]))
+    );
+    assert_eq!(
+        3,
+        get_track_number_asf(&asf_tag(&[asf::test::extended_content_description(&[(
+            "WM/Track",
+            3,
+            2u32.to_le_bytes().to_vec()
+        )])]))
     );
 }
 
@@ -145,7 +352,26 @@ fn test_get_track_number_id3() {
 }
 
 #[test]
-#[ignore]
 fn test_get_duration_mp3() {
-    panic!("not implemented")
+    // the fixtures carry an Info header for five frames, less the LAME encoder delay and padding
+    assert_eq!(
+        Duration::from_millis(100),
+        get_analysis_mp3(Path::new("test/fixtures/id3/no-id3.mp3"))
+            .unwrap()
+            .duration
+    );
+    assert_eq!(
+        Duration::from_millis(100),
+        get_analysis_mp3(Path::new("test/fixtures/id3/blank.mp3"))
+            .unwrap()
+            .duration
+    );
+}
+
+#[test]
+fn test_get_duration_mp3_no_frames() {
+    match get_analysis_mp3(Path::new("test/fixtures/flac/blank.flac")) {
+        Err(Mp3Error::NoFrames) => (),
+        other => panic!("expected no frames, got {:?}", other),
+    }
 }
@@ -0,0 +1,1037 @@
+//! Synchronization of local playlists to the DMS.
+//!
+//! M3U, PLS, and XSPF playlists are found anywhere in the local music directory, and each of their
+//! entries is resolved against the local media library so that it can be rewritten to the location
+//! the head unit sees the file at once it has been copied to the DMS.
+
+pub mod m3u;
+pub mod pls;
+pub mod shuffle;
+pub mod smart;
+pub mod xspf;
+
+use log::{debug, warn};
+
+use percent_encoding::percent_decode_str;
+
+use regex::Regex;
+
+use serde::Serialize;
+
+use crate::data::encoding::OutputEncoding;
+use crate::library::{LibraryFile, LibrarySource};
+use crate::metadata::MediaMetadata;
+use crate::playlist::smart::SmartPlaylist;
+use crate::sync::temp_path;
+use crate::utils::fs::sync_dir;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use walkdir::WalkDir;
+
+/// The directory on the DMS, relative to its mount point, holding playlists.
+pub static DMS_PLAYLISTS_DIR: &str = "playlists";
+
+/// The extension of playlists written to the DMS.
+static DMS_PLAYLIST_EXTENSION: &str = "m3u";
+
+/// The separator used in place of directory separators when flattening a playlist's name.
+static NAME_SEPARATOR: &str = " - ";
+
+static FILE_URI_PREFIX: &str = "file://";
+
+lazy_static! {
+    static ref URI_SCHEME: Regex = Regex::new(r"^[a-zA-Z][a-zA-Z0-9+.-]*://").unwrap();
+}
+
+/// The formats playlists can be read from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PlaylistFormat {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    /// Detect the format of a playlist from its file name.
+    pub fn from_path(path: &Path) -> Option<PlaylistFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+
+        match extension.as_str() {
+            "m3u" | "m3u8" => Some(PlaylistFormat::M3u),
+            "pls" => Some(PlaylistFormat::Pls),
+            "xspf" => Some(PlaylistFormat::Xspf),
+            _ => None,
+        }
+    }
+}
+
+/// A playlist read from the local music directory.
+#[derive(Debug)]
+pub struct Playlist {
+    /// The name of the playlist on the DMS, derived from its path within the music directory.
+    pub name: String,
+    pub source: PathBuf,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+/// An entry of a playlist, as written in the playlist file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlaylistEntry {
+    pub location: String,
+    pub title: Option<String>,
+}
+
+/// Why a playlist entry couldn't be resolved to a file in the local library.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnresolvedReason {
+    /// The entry points outside of the local music directories, or at a remote URI, so it never
+    /// reaches the DMS.
+    OutsideLibrary,
+    /// The entry points into the music directory, but not at a known media file.
+    NotInLibrary,
+}
+
+impl fmt::Display for UnresolvedReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnresolvedReason::OutsideLibrary => write!(f, "outside of the music directory"),
+            UnresolvedReason::NotInLibrary => write!(f, "not in the media library"),
+        }
+    }
+}
+
+/// A playlist entry which was left out of the playlist written to the DMS.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UnresolvedEntry {
+    pub playlist: PathBuf,
+    pub location: String,
+    pub reason: UnresolvedReason,
+}
+
+/// Why a playlist entry couldn't be written exactly, so the head unit may not find its file.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnencodableReason {
+    /// The location isn't valid Unicode, so it was converted lossily.
+    NotUnicode,
+    /// The location can't be represented in the output encoding, so it was written as UTF-8.
+    NotRepresentable,
+}
+
+impl fmt::Display for UnencodableReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnencodableReason::NotUnicode => write!(f, "not valid unicode"),
+            UnencodableReason::NotRepresentable => {
+                write!(f, "not representable in the output encoding")
+            }
+        }
+    }
+}
+
+/// A playlist entry which was written to the DMS, but not exactly.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UnencodableEntry {
+    /// The name of the playlist on the DMS.
+    pub playlist: String,
+    /// The location as it was written.
+    pub location: String,
+    pub reason: UnencodableReason,
+}
+
+/// The outcome of synchronizing playlists to the DMS.
+#[derive(Debug, Default, Serialize)]
+pub struct PlaylistSyncReport {
+    /// The names of the playlists written to the DMS.
+    pub written: Vec<String>,
+    /// The names of the playlists removed from the DMS as they no longer exist locally.
+    pub removed: Vec<String>,
+    pub unresolved: Vec<UnresolvedEntry>,
+    pub unencodable: Vec<UnencodableEntry>,
+    /// The playlists which couldn't be read, written, or removed. Playlists which couldn't be read
+    /// or written are left on the DMS as they were.
+    #[serde(skip)]
+    pub errors: Vec<PlaylistError>,
+}
+
+#[derive(Debug)]
+pub enum PlaylistError {
+    IOError { path: PathBuf, err: io::Error },
+    ParseError { path: PathBuf, reason: String },
+}
+
+impl PlaylistError {
+    /// The playlist, or playlist directory, the error occurred on.
+    pub fn path(&self) -> &Path {
+        match *self {
+            PlaylistError::IOError { ref path, .. } => path,
+            PlaylistError::ParseError { ref path, .. } => path,
+        }
+    }
+}
+
+impl Error for PlaylistError {
+    fn description(&self) -> &str {
+        match *self {
+            PlaylistError::IOError { .. } => "I/O error while synchronizing playlists.",
+            PlaylistError::ParseError { .. } => "Unable to parse playlist.",
+        }
+    }
+}
+
+impl fmt::Display for PlaylistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PlaylistError::IOError { ref path, ref err } => {
+                write!(f, "I/O error on playlist {}: {}", path.display(), err)
+            }
+            PlaylistError::ParseError {
+                ref path,
+                ref reason,
+            } => write!(f, "Unable to parse playlist {}: {}", path.display(), reason),
+        }
+    }
+}
+
+impl Playlist {
+    /// Read the playlist at the given path, naming it after its location within the base directory.
+    pub fn read(path: &Path, base: &Path) -> Result<Playlist, PlaylistError> {
+        let bytes = fs::read(path).map_err(|err| PlaylistError::IOError {
+            path: path.to_path_buf(),
+            err,
+        })?;
+
+        let entries =
+            match PlaylistFormat::from_path(path) {
+                Some(PlaylistFormat::M3u) => m3u::parse(&decode(&bytes)),
+                Some(PlaylistFormat::Pls) => pls::parse(&decode(&bytes)),
+                // XML declares its own encoding, which is UTF-8 for every exporter we've seen
+                Some(PlaylistFormat::Xspf) => xspf::parse(&String::from_utf8_lossy(&bytes))
+                    .map_err(|e| PlaylistError::ParseError {
+                        path: path.to_path_buf(),
+                        reason: e.to_string(),
+                    })?,
+                None => {
+                    return Err(PlaylistError::ParseError {
+                        path: path.to_path_buf(),
+                        reason: "unknown playlist format".to_string(),
+                    })
+                }
+            };
+
+        Ok(Playlist {
+            name: playlist_name(path, base),
+            source: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// Resolve an entry to the local path it refers to. Relative entries are relative to the
+    /// directory holding the playlist, Windows-style separators are accepted, and `file://` URIs
+    /// are percent-decoded. Entries with any other URI scheme have no local path.
+    pub fn resolve_path(&self, entry: &PlaylistEntry) -> Option<PathBuf> {
+        let location = if let Some(uri) = entry.location.strip_prefix(FILE_URI_PREFIX) {
+            file_uri_path(uri)?
+        } else if has_scheme(&entry.location) {
+            return None;
+        } else {
+            PathBuf::from(entry.location.replace('\\', "/"))
+        };
+
+        let parent = self.source.parent().unwrap_or_else(|| Path::new(""));
+
+        Some(normalize(&parent.join(location)))
+    }
+}
+
+/// Decode the contents of a text playlist. `.m3u8` files are always UTF-8, but other playlists
+/// are frequently Latin-1, so fall back to that when the contents aren't valid UTF-8.
+pub fn decode(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// Check whether a playlist location is a URI rather than a path.
+pub(crate) fn has_scheme(location: &str) -> bool {
+    URI_SCHEME.is_match(location)
+}
+
+/// Get the path of a `file://` URI with the scheme removed. Only local hosts are accepted.
+fn file_uri_path(uri: &str) -> Option<PathBuf> {
+    let path = match uri.find('/') {
+        Some(0) => uri,
+        Some(i) if uri[..i].eq_ignore_ascii_case("localhost") => &uri[i..],
+        _ => return None,
+    };
+
+    // percent-encoded paths may hold bytes which aren't valid UTF-8
+    let bytes: Vec<u8> = percent_decode_str(path).collect();
+
+    Some(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
+/// Check whether the given path is a playlist which can be synchronized.
+pub fn is_playlist_filename(path: &Path) -> bool {
+    PlaylistFormat::from_path(path).is_some()
+}
+
+/// Find all playlists beneath the given directory, in a stable order.
+pub fn find_playlists(base: &Path) -> Vec<PathBuf> {
+    let mut playlists: Vec<PathBuf> = WalkDir::new(base)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| is_playlist_filename(e.path()))
+        .map(|e| e.path().to_owned())
+        .collect();
+
+    playlists.sort();
+    playlists
+}
+
+/// Write every playlist in the local music directories and every smart playlist to the DMS,
+/// rewriting each entry to the location of the file on the DMS, and remove playlists from the DMS
+/// which no longer exist locally. Smart playlists are evaluated against the given tracks, and take
+/// precedence over local playlists of the same name. Playlists are written in the given encoding,
+/// as the databases are.
+pub fn sync_playlists(
+    local_dirs: &[PathBuf],
+    local: &BTreeSet<LibraryFile>,
+    smart: &[SmartPlaylist],
+    tracks: &[MediaMetadata],
+    dms_dir: &Path,
+    encoding: OutputEncoding,
+) -> Result<PlaylistSyncReport, PlaylistError> {
+    let mut report = PlaylistSyncReport::default();
+
+    // index the library by ID, so that entries are matched the same way files are
+    let library: HashMap<&str, &LibraryFile> = local.iter().map(|f| (f.id.as_str(), f)).collect();
+    let dest_dir = dms_dir.join(DMS_PLAYLISTS_DIR);
+
+    fs::create_dir_all(&dest_dir).map_err(|err| PlaylistError::IOError {
+        path: dest_dir.clone(),
+        err,
+    })?;
+
+    let mut written = HashSet::new();
+    let smart_names: HashSet<String> = smart
+        .iter()
+        .map(|p| file_name(&p.name).to_lowercase())
+        .collect();
+
+    let playlists = local_dirs
+        .iter()
+        .flat_map(|dir| find_playlists(dir).into_iter().map(move |p| (dir, p)));
+
+    for (local_dir, path) in playlists {
+        let playlist = match Playlist::read(&path, local_dir) {
+            Ok(playlist) => playlist,
+            Err(e) => {
+                // keep whatever the DMS has for the playlist rather than removing it as stale
+                written.insert(file_name(&playlist_name(&path, local_dir)).to_lowercase());
+                report.errors.push(e);
+                continue;
+            }
+        };
+
+        if smart_names.contains(&file_name(&playlist.name).to_lowercase()) {
+            warn!(
+                "Skipping playlist {} as a smart playlist has the same name",
+                playlist.source.display()
+            );
+            continue;
+        }
+
+        // such as Party.m3u next to Party.pls, or playlists at the same place in two directories
+        if written.contains(&file_name(&playlist.name).to_lowercase()) {
+            warn!(
+                "Skipping playlist {} as another playlist is already named {}",
+                playlist.source.display(),
+                playlist.name
+            );
+            continue;
+        }
+
+        let mut locations = Vec::with_capacity(playlist.entries.len());
+
+        for entry in &playlist.entries {
+            match resolve(&playlist, entry, local_dirs, &library) {
+                Ok(file) => locations.push(file.dms_location()),
+                Err(reason) => report.unresolved.push(UnresolvedEntry {
+                    playlist: playlist.source.clone(),
+                    location: entry.location.clone(),
+                    reason,
+                }),
+            }
+        }
+
+        match write_playlist(
+            &dest_dir,
+            &playlist.name,
+            &locations,
+            encoding,
+            &mut written,
+        ) {
+            Ok(unencodable) => {
+                report.written.push(playlist.name);
+                report.unencodable.extend(unencodable);
+            }
+            Err(e) => report.errors.push(e),
+        }
+    }
+
+    for playlist in smart {
+        if written.contains(&file_name(&playlist.name).to_lowercase()) {
+            warn!(
+                "Skipping smart playlist {} as another smart playlist has the same name",
+                playlist.name
+            );
+            continue;
+        }
+
+        let locations: Vec<PathBuf> = playlist
+            .evaluate(tracks)
+            .iter()
+            .map(|t| t.dms_location())
+            .collect();
+
+        match write_playlist(
+            &dest_dir,
+            &playlist.name,
+            &locations,
+            encoding,
+            &mut written,
+        ) {
+            Ok(unencodable) => {
+                report.written.push(playlist.name.clone());
+                report.unencodable.extend(unencodable);
+            }
+            Err(e) => report.errors.push(e),
+        }
+    }
+
+    remove_stale_playlists(&dest_dir, &written, &mut report)?;
+
+    // the renames and removals are only durable once the directory is synced
+    if let Err(err) = sync_dir(&dest_dir) {
+        report.errors.push(PlaylistError::IOError {
+            path: dest_dir,
+            err,
+        });
+    }
+
+    Ok(report)
+}
+
+/// Write a playlist of head unit locations to the DMS playlist directory, recording its file name,
+/// and returning the entries which couldn't be written exactly.
+///
+/// The playlist is written to a temporary file which replaces the old playlist only once it's
+/// safely on disk, so an interrupted sync never leaves a truncated playlist behind.
+fn write_playlist(
+    dest_dir: &Path,
+    name: &str,
+    locations: &[PathBuf],
+    encoding: OutputEncoding,
+    written: &mut HashSet<String>,
+) -> Result<Vec<UnencodableEntry>, PlaylistError> {
+    let file_name = file_name(name);
+    let dest = dest_dir.join(&file_name);
+
+    debug!("Writing playlist {} to DMS at {}...", name, dest.display());
+
+    // whether or not the write succeeds, the playlist isn't stale
+    written.insert(file_name.to_lowercase());
+
+    let mut contents = format!("{}\n", m3u::EXTM3U_HEADER).into_bytes();
+    let mut unencodable = Vec::new();
+
+    for location in locations {
+        let text = location.to_string_lossy();
+
+        let mut reason = match location.to_str() {
+            Some(_) => None,
+            None => Some(UnencodableReason::NotUnicode),
+        };
+
+        // locations are never transliterated, as they have to match the file
+        match encoding.encode_exact(&text) {
+            Some(bytes) => contents.extend_from_slice(&bytes),
+            None => {
+                contents.extend_from_slice(text.as_bytes());
+                reason = Some(UnencodableReason::NotRepresentable);
+            }
+        }
+        contents.push(b'\n');
+
+        if let Some(reason) = reason {
+            unencodable.push(UnencodableEntry {
+                playlist: name.to_string(),
+                location: text.into_owned(),
+                reason,
+            });
+        }
+    }
+
+    if !unencodable.is_empty() {
+        warn!(
+            "Unable to write {} entries of playlist {} exactly in {}, they may not play",
+            unencodable.len(),
+            name,
+            encoding
+        );
+    }
+
+    let temp = temp_path(&dest);
+    let result = File::create(&temp)
+        .and_then(|mut f| f.write_all(&contents).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&temp, &dest));
+
+    match result {
+        Ok(()) => Ok(unencodable),
+        Err(err) => {
+            fs::remove_file(&temp).ok();
+            Err(PlaylistError::IOError { path: dest, err })
+        }
+    }
+}
+
+/// The file name of a playlist on the DMS.
+fn file_name(name: &str) -> String {
+    format!("{}.{}", name, DMS_PLAYLIST_EXTENSION)
+}
+
+/// Resolve a playlist entry to a file in the local library.
+fn resolve<'a>(
+    playlist: &Playlist,
+    entry: &PlaylistEntry,
+    local_dirs: &[PathBuf],
+    library: &HashMap<&str, &'a LibraryFile>,
+) -> Result<&'a LibraryFile, UnresolvedReason> {
+    let path = playlist
+        .resolve_path(entry)
+        .ok_or(UnresolvedReason::OutsideLibrary)?;
+
+    let local_dir = local_dirs
+        .iter()
+        .find(|dir| path.starts_with(dir) && &path != *dir)
+        .ok_or(UnresolvedReason::OutsideLibrary)?;
+
+    let id = LibraryFile::new(&path, local_dir, LibrarySource::Local).id;
+
+    library
+        .get(id.as_str())
+        .copied()
+        .ok_or(UnresolvedReason::NotInLibrary)
+}
+
+/// Remove the playlists in the DMS playlist directory which weren't just written, recording them in
+/// the report. The DMS is FAT, so names are compared case-insensitively.
+fn remove_stale_playlists(
+    dest_dir: &Path,
+    written: &HashSet<String>,
+    report: &mut PlaylistSyncReport,
+) -> Result<(), PlaylistError> {
+    let io_error = |err| PlaylistError::IOError {
+        path: dest_dir.to_path_buf(),
+        err,
+    };
+
+    for entry in fs::read_dir(dest_dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+
+        if !path.is_file() || !is_playlist_filename(&path) {
+            continue;
+        }
+
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+
+        if written.contains(&file_name.to_lowercase()) {
+            continue;
+        }
+
+        debug!("Deleting orphaned playlist from DMS {}", path.display());
+
+        match fs::remove_file(&path) {
+            Ok(()) => report
+                .removed
+                .push(path.file_stem().unwrap().to_string_lossy().into_owned()),
+            Err(err) => report.errors.push(PlaylistError::IOError { path, err }),
+        }
+    }
+
+    report.removed.sort();
+    Ok(())
+}
+
+/// Derive the name of a playlist on the DMS from its location within the base directory, so that
+/// playlists of the same name in different directories don't collide.
+fn playlist_name(path: &Path, base: &Path) -> String {
+    let relative = path.strip_prefix(base).unwrap_or(path).with_extension("");
+
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(NAME_SEPARATOR)
+}
+
+/// Lexically normalize a path, removing `.` components and resolving `..` components without
+/// touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            // `..` at the root is the root itself, as it is everywhere else
+            Component::ParentDir if result.has_root() && result.parent().is_none() => {}
+            Component::ParentDir => {
+                if !result.pop() {
+                    result.push(component);
+                }
+            }
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::data::test::load_fixtures;
+    use crate::library::{get_local_media_library, get_merged_media_library};
+    use crate::playlist::smart::SmartPlaylistConfig;
+    use crate::utils::StringPool;
+
+    use tempfile::TempDir;
+
+    /// Create a music directory with a few empty media files.
+    fn music_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+
+        for file in &[
+            "Andrew W. K./I Get Wet/02 - Party Hard.mp3",
+            "Mêlée/Everyday Behavior/01 - Got It All.flac",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            PathBuf::from("/music/a/c.mp3"),
+            normalize(Path::new("/music/./a/b/../c.mp3"))
+        );
+        assert_eq!(
+            PathBuf::from("/other.mp3"),
+            normalize(Path::new("/music/../../other.mp3"))
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!("Mêlée", decode("Mêlée".as_bytes()));
+        assert_eq!("Mêlée", decode(b"M\xEAl\xE9e"));
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let playlist = Playlist {
+            name: "Road Trip".to_string(),
+            source: PathBuf::from("/music/Playlists/Road Trip.xspf"),
+            entries: Vec::new(),
+        };
+        let resolve = |location: &str| {
+            playlist.resolve_path(&PlaylistEntry {
+                location: location.to_string(),
+                title: None,
+            })
+        };
+
+        assert_eq!(Some(PathBuf::from("/music/a/b.mp3")), resolve("../a/b.mp3"));
+        assert_eq!(
+            Some(PathBuf::from("/music/a/b.mp3")),
+            resolve("..\\a\\b.mp3")
+        );
+        assert_eq!(Some(PathBuf::from("/other/b.mp3")), resolve("/other/b.mp3"));
+        assert_eq!(
+            Some(PathBuf::from("/music/Mêlée/01 - Got It All.flac")),
+            resolve("file:///music/M%C3%AAl%C3%A9e/01%20-%20Got%20It%20All.flac")
+        );
+        assert_eq!(
+            Some(PathBuf::from("/music/a b.mp3")),
+            resolve("file://localhost/music/a%20b.mp3")
+        );
+
+        // percent-encoded bytes needn't be valid UTF-8
+        assert_eq!(
+            Some(PathBuf::from(OsStr::from_bytes(b"/music/M\xEAl\xE9e.mp3"))),
+            resolve("file:///music/M%EAl%E9e.mp3")
+        );
+
+        assert_eq!(None, resolve("file://server/music/a.mp3"));
+        assert_eq!(None, resolve("http://example.com/stream.mp3"));
+    }
+
+    #[test]
+    fn test_playlist_name() {
+        let base = Path::new("/music");
+
+        assert_eq!(
+            "Road Trip",
+            playlist_name(Path::new("/music/Road Trip.m3u8"), base)
+        );
+        assert_eq!(
+            "Playlists - Road Trip",
+            playlist_name(Path::new("/music/Playlists/Road Trip.m3u"), base)
+        );
+    }
+
+    #[test]
+    fn test_sync_playlists() {
+        let music = music_dir();
+        let dms = tempfile::tempdir().unwrap();
+        let local = get_local_media_library(music.path());
+
+        fs::create_dir(music.path().join("Playlists")).unwrap();
+        fs::write(
+            music.path().join("Playlists/Road Trip.m3u8"),
+            format!(
+                "#EXTM3U\n{}\n{}\n{}\n{}\n",
+                "../Andrew W. K./I Get Wet/02 - Party Hard.mp3",
+                music
+                    .path()
+                    .join("Mêlée/Everyday Behavior/01 - Got It All.flac")
+                    .display(),
+                "../Andrew W. K./I Get Wet/03 - Missing.mp3",
+                "/elsewhere/Track.mp3",
+            ),
+        )
+        .unwrap();
+        // windows-style separators and mismatched case still resolve
+        fs::write(
+            music.path().join("Party.m3u"),
+            "andrew w. k.\\i get wet\\02 - party hard.mp3\r\n",
+        )
+        .unwrap();
+
+        // a playlist left over from a previous sync
+        fs::create_dir(dms.path().join(DMS_PLAYLISTS_DIR)).unwrap();
+        fs::write(dms.path().join(DMS_PLAYLISTS_DIR).join("Old.m3u"), "").unwrap();
+
+        let report = sync_playlists(
+            &[music.path().to_path_buf()],
+            &local,
+            &[],
+            &[],
+            dms.path(),
+            OutputEncoding::Utf8,
+        )
+        .unwrap();
+
+        assert_eq!(vec!["Party", "Playlists - Road Trip"], report.written);
+        assert_eq!(vec!["Old"], report.removed);
+        assert_eq!(
+            vec![
+                UnresolvedEntry {
+                    playlist: music.path().join("Playlists/Road Trip.m3u8"),
+                    location: "../Andrew W. K./I Get Wet/03 - Missing.mp3".to_string(),
+                    reason: UnresolvedReason::NotInLibrary,
+                },
+                UnresolvedEntry {
+                    playlist: music.path().join("Playlists/Road Trip.m3u8"),
+                    location: "/elsewhere/Track.mp3".to_string(),
+                    reason: UnresolvedReason::OutsideLibrary,
+                },
+            ],
+            report.unresolved
+        );
+
+        let playlists = dms.path().join(DMS_PLAYLISTS_DIR);
+
+        assert_eq!(
+            "#EXTM3U\n\
+             /dos/data/Andrew W. K./I Get Wet/02 - Party Hard.mp3\n\
+             /dos/data/Mêlée/Everyday Behavior/01 - Got It All.flac\n",
+            fs::read_to_string(playlists.join("Playlists - Road Trip.m3u")).unwrap()
+        );
+        assert_eq!(
+            "#EXTM3U\n/dos/data/Andrew W. K./I Get Wet/02 - Party Hard.mp3\n",
+            fs::read_to_string(playlists.join("Party.m3u")).unwrap()
+        );
+        assert!(!playlists.join("Old.m3u").exists());
+    }
+
+    #[test]
+    fn test_sync_pls_and_xspf_playlists() {
+        let music = music_dir();
+        let dms = tempfile::tempdir().unwrap();
+        let local = get_local_media_library(music.path());
+
+        fs::write(
+            music.path().join("Party.pls"),
+            concat!(
+                "[playlist]\n",
+                "File1=Andrew W. K./I Get Wet/02 - Party Hard.mp3\n",
+                "File2=http://example.com/stream.mp3\n",
+                "NumberOfEntries=2\n",
+            ),
+        )
+        .unwrap();
+        fs::write(
+            music.path().join("Mellow.xspf"),
+            format!(
+                concat!(
+                    r#"<playlist version="1" xmlns="http://xspf.org/ns/0/"><trackList>"#,
+                    "<track><location>file://{}/M%C3%AAl%C3%A9e/Everyday%20Behavior/01%20-%20Got%20It%20All.flac</location></track>",
+                    "<track><location>file:///elsewhere/Track.mp3</location></track>",
+                    "</trackList></playlist>",
+                ),
+                music.path().display()
+            ),
+        )
+        .unwrap();
+
+        let report = sync_playlists(
+            &[music.path().to_path_buf()],
+            &local,
+            &[],
+            &[],
+            dms.path(),
+            OutputEncoding::Utf8,
+        )
+        .unwrap();
+        let playlists = dms.path().join(DMS_PLAYLISTS_DIR);
+
+        assert_eq!(vec!["Mellow", "Party"], report.written);
+        assert_eq!(
+            vec![
+                UnresolvedReason::OutsideLibrary,
+                UnresolvedReason::OutsideLibrary
+            ],
+            report
+                .unresolved
+                .iter()
+                .map(|e| e.reason.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "#EXTM3U\n/dos/data/Andrew W. K./I Get Wet/02 - Party Hard.mp3\n",
+            fs::read_to_string(playlists.join("Party.m3u")).unwrap()
+        );
+        assert_eq!(
+            "#EXTM3U\n/dos/data/Mêlée/Everyday Behavior/01 - Got It All.flac\n",
+            fs::read_to_string(playlists.join("Mellow.m3u")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sync_playlists_with_the_same_name() {
+        let music = music_dir();
+        let other = music_dir();
+        let dms = tempfile::tempdir().unwrap();
+        let dirs = [music.path().to_path_buf(), other.path().to_path_buf()];
+        let local = get_merged_media_library(&dirs);
+
+        fs::write(
+            music.path().join("Party.m3u"),
+            "Andrew W. K./I Get Wet/02 - Party Hard.mp3\n",
+        )
+        .unwrap();
+        fs::write(
+            music.path().join("party.pls"),
+            "[playlist]\nFile1=Mêlée/Everyday Behavior/01 - Got It All.flac\n",
+        )
+        .unwrap();
+        fs::write(
+            other.path().join("Party.m3u"),
+            "Mêlée/Everyday Behavior/01 - Got It All.flac\n",
+        )
+        .unwrap();
+
+        let report =
+            sync_playlists(&dirs, &local, &[], &[], dms.path(), OutputEncoding::Utf8).unwrap();
+
+        // the first playlist of a name wins, rather than being overwritten by the others
+        assert_eq!(vec!["Party"], report.written);
+        assert_eq!(
+            "#EXTM3U\n/dos/data/Andrew W. K./I Get Wet/02 - Party Hard.mp3\n",
+            fs::read_to_string(dms.path().join(DMS_PLAYLISTS_DIR).join("Party.m3u")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sync_playlists_encoding() {
+        let music = music_dir();
+        let dms = tempfile::tempdir().unwrap();
+        let local = get_local_media_library(music.path());
+        let playlists = dms.path().join(DMS_PLAYLISTS_DIR);
+
+        fs::write(
+            music.path().join("Mellow.m3u"),
+            "Mêlée/Everyday Behavior/01 - Got It All.flac\n",
+        )
+        .unwrap();
+
+        let report = sync_playlists(
+            &[music.path().to_path_buf()],
+            &local,
+            &[],
+            &[],
+            dms.path(),
+            OutputEncoding::Latin1,
+        )
+        .unwrap();
+
+        assert_eq!(
+            b"#EXTM3U\n/dos/data/M\xEAl\xE9e/Everyday Behavior/01 - Got It All.flac\n".to_vec(),
+            fs::read(playlists.join("Mellow.m3u")).unwrap()
+        );
+        assert!(report.unencodable.is_empty());
+
+        // the temporary file was renamed into place
+        assert_eq!(1, fs::read_dir(&playlists).unwrap().count());
+
+        // a location Latin-1 can't represent is written as UTF-8, and reported
+        let path = music.path().join("坂本龍一/01.mp3");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"").unwrap();
+        fs::write(music.path().join("Mellow.m3u"), "坂本龍一/01.mp3\n").unwrap();
+
+        let report = sync_playlists(
+            &[music.path().to_path_buf()],
+            &get_local_media_library(music.path()),
+            &[],
+            &[],
+            dms.path(),
+            OutputEncoding::Latin1,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "#EXTM3U\n/dos/data/坂本龍一/01.mp3\n",
+            fs::read_to_string(playlists.join("Mellow.m3u")).unwrap()
+        );
+        assert_eq!(
+            vec![UnencodableEntry {
+                playlist: "Mellow".to_string(),
+                location: "/dos/data/坂本龍一/01.mp3".to_string(),
+                reason: UnencodableReason::NotRepresentable,
+            }],
+            report.unencodable
+        );
+    }
+
+    #[test]
+    fn test_read_malformed_xspf() {
+        let music = music_dir();
+        let path = music.path().join("Broken.xspf");
+        fs::write(&path, "<playlist><trackList>").unwrap();
+
+        assert!(matches!(
+            Playlist::read(&path, music.path()),
+            Err(PlaylistError::ParseError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sync_with_malformed_playlist() {
+        let music = music_dir();
+        let dms = tempfile::tempdir().unwrap();
+        let local = get_local_media_library(music.path());
+        let playlists = dms.path().join(DMS_PLAYLISTS_DIR);
+
+        fs::write(music.path().join("Broken.xspf"), "<playlist><trackList>").unwrap();
+        fs::write(
+            music.path().join("Party.m3u"),
+            "Andrew W. K./I Get Wet/02 - Party Hard.mp3\n",
+        )
+        .unwrap();
+
+        // the broken playlist's last good copy, and a stale playlist
+        fs::create_dir(&playlists).unwrap();
+        fs::write(playlists.join("Broken.m3u"), "#EXTM3U\n").unwrap();
+        fs::write(playlists.join("Old.m3u"), "").unwrap();
+
+        let report = sync_playlists(
+            &[music.path().to_path_buf()],
+            &local,
+            &[],
+            &[],
+            dms.path(),
+            OutputEncoding::Utf8,
+        )
+        .unwrap();
+
+        // the other playlists are still synced, and the broken one is kept as it was
+        assert_eq!(vec!["Party"], report.written);
+        assert_eq!(vec!["Old"], report.removed);
+        assert_eq!(
+            vec![music.path().join("Broken.xspf").as_path()],
+            report.errors.iter().map(|e| e.path()).collect::<Vec<_>>()
+        );
+        assert!(playlists.join("Broken.m3u").exists());
+        assert!(playlists.join("Party.m3u").exists());
+    }
+
+    #[test]
+    fn test_sync_smart_playlists() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+        let music = music_dir();
+        let dms = tempfile::tempdir().unwrap();
+        let local = get_local_media_library(music.path());
+
+        // a local playlist with the same name as a smart playlist is left out
+        fs::write(music.path().join("composed.m3u"), "missing.mp3\n").unwrap();
+
+        let config: SmartPlaylistConfig = toml::from_str(concat!(
+            "[[playlist]]\n",
+            "name = \"Composed\"\n",
+            "[[playlist.rule]]\n",
+            "field = \"artist\"\n",
+            "is = \"composer\"\n",
+        ))
+        .unwrap();
+
+        let report = sync_playlists(
+            &[music.path().to_path_buf()],
+            &local,
+            &config.playlists,
+            &tracks,
+            dms.path(),
+            OutputEncoding::Utf8,
+        )
+        .unwrap();
+
+        assert_eq!(vec!["Composed"], report.written);
+        assert!(report.unresolved.is_empty());
+        assert_eq!(
+            "#EXTM3U\n/dos/data/flac/composer.flac\n",
+            fs::read_to_string(dms.path().join(DMS_PLAYLISTS_DIR).join("Composed.m3u")).unwrap()
+        );
+    }
+}
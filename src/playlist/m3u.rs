@@ -0,0 +1,93 @@
+//! Reading and writing of M3U and extended M3U playlists.
+
+use crate::playlist::PlaylistEntry;
+
+pub(crate) static EXTM3U_HEADER: &str = "#EXTM3U";
+static EXTINF_PREFIX: &str = "#EXTINF:";
+
+/// Parse the entries of an M3U playlist. Comments and blank lines are skipped, and titles from
+/// `#EXTINF` lines are attached to the entry which follows them.
+pub fn parse(contents: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut title: Option<String> = None;
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(info) = line.strip_prefix(EXTINF_PREFIX) {
+            // #EXTINF:<seconds>,<title>
+            title = info
+                .split_once(',')
+                .map(|(_, t)| t.trim().to_string())
+                .filter(|t| !t.is_empty());
+            continue;
+        }
+
+        if line.starts_with('#') {
+            continue;
+        }
+
+        entries.push(PlaylistEntry {
+            location: line.to_string(),
+            title: title.take(),
+        });
+    }
+
+    entries
+}
+
+/// Render a playlist of head unit locations as an extended M3U playlist.
+pub fn render<'a, I: IntoIterator<Item = &'a str>>(locations: I) -> String {
+    let mut contents = String::from(EXTM3U_HEADER);
+    contents.push('\n');
+
+    for location in locations {
+        contents.push_str(location);
+        contents.push('\n');
+    }
+
+    contents
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let entries = parse(concat!(
+            "\u{feff}#EXTM3U\n",
+            "#EXTINF:215,Andrew W.K. - Party Hard\n",
+            "Andrew W. K./I Get Wet/02 - Party Hard.mp3\r\n",
+            "\n",
+            "# a comment\n",
+            "/home/user/Music/Mêlée/01 - Got It All.flac\n",
+        ));
+
+        assert_eq!(
+            vec![
+                PlaylistEntry {
+                    location: "Andrew W. K./I Get Wet/02 - Party Hard.mp3".to_string(),
+                    title: Some("Andrew W.K. - Party Hard".to_string()),
+                },
+                PlaylistEntry {
+                    location: "/home/user/Music/Mêlée/01 - Got It All.flac".to_string(),
+                    title: None,
+                },
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            "#EXTM3U\n/dos/data/a.mp3\n/dos/data/b.mp3\n",
+            render(vec!["/dos/data/a.mp3", "/dos/data/b.mp3"])
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Reading of PLS playlists.
+
+use crate::playlist::PlaylistEntry;
+
+use std::collections::BTreeMap;
+
+static FILE_KEY: &str = "file";
+static TITLE_KEY: &str = "title";
+
+/// Parse the entries of a PLS playlist.
+///
+/// Entries are given as numbered `FileN` and `TitleN` keys, which are ordered by their number
+/// rather than by where they appear in the file. Keys are matched case-insensitively, and all
+/// other keys, such as `NumberOfEntries` and `Length`, are ignored.
+pub fn parse(contents: &str) -> Vec<PlaylistEntry> {
+    let mut files: BTreeMap<u32, String> = BTreeMap::new();
+    let mut titles: BTreeMap<u32, String> = BTreeMap::new();
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+
+        // skip blank lines, comments, and the [playlist] section header
+        if line.is_empty() || line.starts_with(';') || line.starts_with('[') {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+
+        if value.is_empty() {
+            continue;
+        }
+
+        if let Some(n) = numbered(&key, FILE_KEY) {
+            files.insert(n, value.to_string());
+        } else if let Some(n) = numbered(&key, TITLE_KEY) {
+            titles.insert(n, value.to_string());
+        }
+    }
+
+    files
+        .into_iter()
+        .map(|(n, location)| PlaylistEntry {
+            location,
+            title: titles.remove(&n),
+        })
+        .collect()
+}
+
+/// Get the number of a numbered key such as `File1`.
+fn numbered(key: &str, prefix: &str) -> Option<u32> {
+    key.strip_prefix(prefix)?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let entries = parse(concat!(
+            "[playlist]\r\n",
+            "File2=/home/user/Music/Mêlée/01 - Got It All.flac\r\n",
+            "Title2=Got It All\r\n",
+            "Length2=215\r\n",
+            "; a comment\r\n",
+            "file1 = Andrew W. K./I Get Wet/02 - Party Hard.mp3\r\n",
+            "File3=\r\n",
+            "NumberOfEntries=3\r\n",
+            "Version=2\r\n",
+        ));
+
+        assert_eq!(
+            vec![
+                PlaylistEntry {
+                    location: "Andrew W. K./I Get Wet/02 - Party Hard.mp3".to_string(),
+                    title: None,
+                },
+                PlaylistEntry {
+                    location: "/home/user/Music/Mêlée/01 - Got It All.flac".to_string(),
+                    title: Some("Got It All".to_string()),
+                },
+            ],
+            entries
+        );
+    }
+}
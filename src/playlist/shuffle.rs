@@ -0,0 +1,372 @@
+//! A shuffle which keeps tracks by the same artist, and from the same album, apart.
+//!
+//! A plain shuffle of a large library regularly plays several tracks from one album in a row.
+//! Instead, tracks are queued per artist, alternating between each artist's albums, and the next
+//! track is drawn from a random artist which hasn't been heard for long enough. Artists are
+//! weighted by how much room their remaining tracks need, and an artist is placed as soon as it
+//! risks running out of room, so prolific artists don't pile up at the end.
+
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use rand_chacha::ChaCha8Rng;
+
+use serde::Deserialize;
+
+use crate::metadata::MediaMetadata;
+
+use std::collections::{HashMap, VecDeque};
+
+/// How full the remaining positions may get, as a fraction, before the artist needing the most
+/// room is placed next rather than a random one. Lower values spread artists more reliably but
+/// make the shuffle more predictable.
+const URGENCY: (usize, usize) = (4, 5);
+
+/// The options of an artist-spread shuffle.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpreadShuffle {
+    /// The seed of the shuffle. The same seed and tracks always give the same order, while no
+    /// seed gives a different order every time.
+    pub seed: Option<u64>,
+    /// The minimum number of other tracks between two tracks by the same artist.
+    pub artist_distance: usize,
+    /// The minimum number of other tracks between two tracks from the same album.
+    pub album_distance: usize,
+}
+
+impl Default for SpreadShuffle {
+    fn default() -> Self {
+        SpreadShuffle {
+            seed: None,
+            artist_distance: 3,
+            album_distance: 6,
+        }
+    }
+}
+
+/// The tracks by a single artist which are still to be placed.
+struct ArtistQueue<'a> {
+    artist: &'a str,
+    tracks: VecDeque<&'a MediaMetadata>,
+}
+
+impl ArtistQueue<'_> {
+    /// The number of positions the remaining tracks need to be kept the minimum distance apart.
+    /// Tracks alternate between the artist's albums, so an artist with fewer albums left has to be
+    /// spread further to keep its albums apart.
+    fn span(&self, artist_distance: usize, album_distance: usize) -> usize {
+        let mut albums: Vec<&str> = self.tracks.iter().map(|t| &*t.album).collect();
+        albums.sort_unstable();
+        albums.dedup();
+
+        let count = albums.len().max(1);
+        let distance = artist_distance.max((album_distance + count) / count - 1);
+
+        self.tracks.len().saturating_sub(1) * (distance + 1) + 1
+    }
+}
+
+impl SpreadShuffle {
+    pub fn with_seed(seed: u64) -> Self {
+        SpreadShuffle {
+            seed: Some(seed),
+            ..Default::default()
+        }
+    }
+
+    /// Shuffle the given tracks.
+    ///
+    /// Where there are too few artists or albums to keep every track the minimum distance apart,
+    /// the track from the artist and album heard longest ago is placed next instead.
+    pub fn shuffle<'a>(&self, tracks: &[&'a MediaMetadata]) -> Vec<&'a MediaMetadata> {
+        let mut rng = match self.seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+            None => ChaCha8Rng::from_entropy(),
+        };
+
+        // sort first so that the order only depends on the seed, not on how tracks were found
+        let mut sorted = tracks.to_vec();
+        sorted.sort_by(|a, b| MediaMetadata::by_artist(a, b));
+
+        let mut queues = artist_queues(&sorted, &mut rng);
+        let mut result = Vec::with_capacity(sorted.len());
+        let mut artist_played: HashMap<&str, usize> = HashMap::new();
+        // albums are told apart by artist too, as many artists have a "Greatest Hits"
+        let mut album_played: HashMap<(&str, &str), usize> = HashMap::new();
+
+        while result.len() < sorted.len() {
+            let position = result.len();
+
+            // how many tracks have been placed since this artist, and the album of its next
+            // track, were last heard
+            let gaps = |queue: &ArtistQueue<'a>| {
+                let gap = |played: Option<&usize>| played.map_or(usize::MAX, |p| position - p - 1);
+                let album: &str = &queue.tracks[0].album;
+
+                (
+                    gap(artist_played.get(queue.artist)),
+                    gap(album_played.get(&(queue.artist, album))),
+                )
+            };
+
+            let pending: Vec<usize> = (0..queues.len())
+                .filter(|&i| !queues[i].tracks.is_empty())
+                .collect();
+
+            let eligible: Vec<usize> = pending
+                .iter()
+                .copied()
+                .filter(|&i| {
+                    let (artist_gap, album_gap) = gaps(&queues[i]);
+                    artist_gap >= self.artist_distance && album_gap >= self.album_distance
+                })
+                .collect();
+
+            // the queue needing the most room to fit its remaining tracks at the minimum distance
+            // has to be placed once it only just fits, or it will end up repeating back to back
+            let remaining = sorted.len() - position;
+            let span = |i: usize| queues[i].span(self.artist_distance, self.album_distance);
+            let urgent = eligible
+                .iter()
+                .copied()
+                .max_by_key(|&i| span(i))
+                .filter(|&i| span(i) * URGENCY.1 >= remaining * URGENCY.0);
+
+            let next = match urgent {
+                Some(i) => i,
+                None => match eligible.choose_weighted(&mut rng, |&i| span(i)) {
+                    Ok(&i) => i,
+                    Err(_) => *pending.iter().max_by_key(|&&i| gaps(&queues[i])).unwrap(),
+                },
+            };
+
+            let track = queues[next].tracks.pop_front().unwrap();
+
+            artist_played.insert(queues[next].artist, position);
+            album_played.insert((queues[next].artist, &track.album), position);
+            result.push(track);
+        }
+
+        result
+    }
+}
+
+/// Queue the tracks of each artist, alternating between the artist's albums in a random order so
+/// that the artist's next track is usually from a different album than the last.
+fn artist_queues<'a>(sorted: &[&'a MediaMetadata], rng: &mut ChaCha8Rng) -> Vec<ArtistQueue<'a>> {
+    let mut queues = Vec::new();
+    let mut start = 0;
+
+    while start < sorted.len() {
+        let artist: &'a str = &sorted[start].artist;
+        let end = start
+            + sorted[start..]
+                .iter()
+                .take_while(|t| &*t.artist == artist)
+                .count();
+
+        // tracks are sorted by album within an artist
+        let mut albums: Vec<Vec<&'a MediaMetadata>> = Vec::new();
+
+        for track in &sorted[start..end] {
+            match albums.last_mut() {
+                Some(album) if album[0].album == track.album => album.push(track),
+                _ => albums.push(vec![track]),
+            }
+        }
+
+        for album in albums.iter_mut() {
+            album.shuffle(rng);
+        }
+
+        albums.shuffle(rng);
+
+        let mut tracks = VecDeque::with_capacity(end - start);
+        let longest = albums.iter().map(Vec::len).max().unwrap_or(0);
+
+        for i in 0..longest {
+            tracks.extend(albums.iter().filter_map(|album| album.get(i)));
+        }
+
+        queues.push(ArtistQueue { artist, tracks });
+        start = end;
+    }
+
+    queues
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::metadata::{AudioProperties, BitrateMode, Codec};
+    use crate::utils::StringPool;
+
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// Build a library of the given artists, each with the given albums of the given length.
+    fn library(pool: &StringPool, artists: &[(&str, usize, usize)]) -> Vec<MediaMetadata> {
+        let mut tracks = Vec::new();
+
+        for &(artist, albums, length) in artists {
+            for album in 0..albums {
+                for track in 0..length {
+                    let album = format!("{} {}", artist, album);
+
+                    tracks.push(MediaMetadata {
+                        path: PathBuf::from(format!("/music/{}/{:02}.mp3", album, track)),
+                        base: PathBuf::from("/music"),
+                        artist: pool.get(artist),
+                        album: pool.get(&album),
+                        genre: pool.get("Rock"),
+                        title: format!("Track {}", track),
+                        track_number: track as u16 + 1,
+                        duration: 180,
+                        properties: AudioProperties {
+                            codec: Codec::MP3,
+                            bitrate: 320,
+                            bitrate_mode: BitrateMode::Constant,
+                            sample_rate: 44100,
+                            channels: 2,
+                            channel_mode: None,
+                            mpeg_version: None,
+                            mpeg_layer: None,
+                            duration: Duration::from_secs(180),
+                        },
+                        uncertainties: Vec::new(),
+                    });
+                }
+            }
+        }
+
+        tracks
+    }
+
+    fn paths<'a>(tracks: &[&'a MediaMetadata]) -> Vec<&'a PathBuf> {
+        tracks.iter().map(|t| &t.path).collect()
+    }
+
+    /// The smallest number of tracks between two tracks sharing a key.
+    fn min_distance<F: Fn(&MediaMetadata) -> &str>(tracks: &[&MediaMetadata], key: F) -> usize {
+        let mut last: HashMap<&str, usize> = HashMap::new();
+        let mut distance = usize::MAX;
+
+        for (i, track) in tracks.iter().enumerate() {
+            if let Some(previous) = last.insert(key(track), i) {
+                distance = distance.min(i - previous - 1);
+            }
+        }
+
+        distance
+    }
+
+    #[test]
+    fn test_shuffle_spreads_artists_and_albums() {
+        let pool = StringPool::new();
+        let tracks = library(
+            &pool,
+            &[
+                ("A", 3, 8),
+                ("B", 2, 10),
+                ("C", 2, 9),
+                ("D", 2, 8),
+                ("E", 1, 12),
+                ("F", 2, 5),
+                ("G", 1, 9),
+                ("H", 1, 8),
+            ],
+        );
+        let selection: Vec<&MediaMetadata> = tracks.iter().collect();
+        let mut expected = paths(&selection);
+        expected.sort();
+
+        for seed in 0..50 {
+            let options = SpreadShuffle::with_seed(seed);
+            let shuffled = options.shuffle(&selection);
+
+            let mut sorted = paths(&shuffled);
+            sorted.sort();
+            assert_eq!(expected, sorted);
+
+            assert!(min_distance(&shuffled, |t| &t.artist) >= options.artist_distance);
+            assert!(min_distance(&shuffled, |t| &t.album) >= options.album_distance);
+        }
+    }
+
+    #[test]
+    fn test_shuffle_albums_sharing_a_title() {
+        let pool = StringPool::new();
+        let mut tracks = library(
+            &pool,
+            &[
+                ("A", 2, 8),
+                ("B", 2, 8),
+                ("C", 2, 8),
+                ("D", 2, 8),
+                ("E", 1, 8),
+            ],
+        );
+
+        // every artist has a "Greatest Hits", which are different albums
+        for track in tracks.iter_mut() {
+            let title = format!("Greatest Hits {}", track.album.rsplit(' ').next().unwrap());
+            track.album = pool.get(&title);
+        }
+
+        let selection: Vec<&MediaMetadata> = tracks.iter().collect();
+
+        for seed in 0..50 {
+            let options = SpreadShuffle::with_seed(seed);
+            let shuffled = options.shuffle(&selection);
+
+            // each album is still in a directory of its own
+            assert!(min_distance(&shuffled, |t| &t.artist) >= options.artist_distance);
+            assert!(
+                min_distance(&shuffled, |t| t.path.parent().unwrap().to_str().unwrap())
+                    >= options.album_distance
+            );
+        }
+    }
+
+    #[test]
+    fn test_shuffle_is_reproducible() {
+        let pool = StringPool::new();
+        let tracks = library(&pool, &[("A", 2, 10), ("B", 2, 10), ("C", 1, 10)]);
+        let selection: Vec<&MediaMetadata> = tracks.iter().collect();
+        let reversed: Vec<&MediaMetadata> = tracks.iter().rev().collect();
+
+        let first = SpreadShuffle::with_seed(42).shuffle(&selection);
+
+        // the same seed gives the same order, however the tracks were selected
+        assert_eq!(
+            paths(&first),
+            paths(&SpreadShuffle::with_seed(42).shuffle(&reversed))
+        );
+        assert_ne!(
+            paths(&first),
+            paths(&SpreadShuffle::with_seed(43).shuffle(&selection))
+        );
+    }
+
+    #[test]
+    fn test_shuffle_too_few_artists() {
+        let pool = StringPool::new();
+        let tracks = library(&pool, &[("A", 3, 4), ("B", 1, 2)]);
+        let selection: Vec<&MediaMetadata> = tracks.iter().collect();
+
+        let shuffled = SpreadShuffle::with_seed(1).shuffle(&selection);
+
+        // every track is still placed, and a single artist's albums still alternate
+        assert_eq!(tracks.len(), shuffled.len());
+
+        let a: Vec<&MediaMetadata> = shuffled
+            .iter()
+            .copied()
+            .filter(|t| &*t.artist == "A")
+            .collect();
+
+        assert!(a.windows(2).all(|w| w[0].album != w[1].album));
+    }
+}
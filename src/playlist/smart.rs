@@ -0,0 +1,460 @@
+//! Smart playlists, defined by rules over track metadata in a configuration file and evaluated
+//! against the local library on every sync.
+//!
+//! A configuration holds any number of playlists, each with at least one rule:
+//!
+//! ```toml
+//! [[playlist]]
+//! name = "Short Jazz"
+//! order = "shuffle"
+//!
+//! [[playlist.rule]]
+//! field = "genre"
+//! is = "Jazz"
+//!
+//! [[playlist.rule]]
+//! field = "duration"
+//! under = 600
+//!
+//! [playlist.shuffle]
+//! seed = 42
+//! artist_distance = 3
+//! ```
+//!
+//! A track is included when it matches all of the rules, or any of them if the playlist sets
+//! `match = "any"`. Text comparisons ignore case, and durations are in seconds. Shuffled playlists
+//! keep artists and albums apart, and are reproducible when given a seed.
+
+use serde::Deserialize;
+
+use crate::metadata::MediaMetadata;
+use crate::playlist::shuffle::SpreadShuffle;
+
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The file name of the smart playlist configuration within the configuration directory.
+static CONFIG_FILE_NAME: &str = "smart-playlists.toml";
+
+/// The smart playlists defined in a configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmartPlaylistConfig {
+    #[serde(default, rename = "playlist")]
+    pub playlists: Vec<SmartPlaylist>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmartPlaylist {
+    pub name: String,
+    #[serde(default, rename = "match")]
+    pub combine: Combine,
+    #[serde(default)]
+    pub order: Order,
+    /// The options for the shuffle when the order is `shuffle`.
+    #[serde(default)]
+    pub shuffle: SpreadShuffle,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+/// How the rules of a playlist are combined.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Combine {
+    #[default]
+    All,
+    Any,
+}
+
+/// The order of the tracks in a playlist.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    /// Ordered as `MediaMetadata::by_artist`.
+    #[default]
+    Artist,
+    /// Ordered as `MediaMetadata::by_genre`.
+    Genre,
+    /// Shuffled with a `SpreadShuffle`.
+    Shuffle,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    pub field: Field,
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+/// The track metadata a rule can test.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    /// The artist as shown on the head unit, which is the album artist where a track has one.
+    #[serde(alias = "album_artist")]
+    Artist,
+    Album,
+    Genre,
+    Title,
+    TrackNumber,
+    /// The duration in whole seconds.
+    Duration,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Is(Value),
+    IsNot(Value),
+    In(Vec<Value>),
+    Contains(String),
+    Under(u64),
+    Over(u64),
+}
+
+/// A value to compare a field with, which must be of the same kind as the field.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Value {
+    Number(u64),
+    Text(String),
+}
+
+enum FieldValue<'a> {
+    Number(u64),
+    Text(&'a str),
+}
+
+#[derive(Debug)]
+pub enum SmartPlaylistError {
+    IOError { path: PathBuf, err: io::Error },
+    ParseError { path: PathBuf, err: toml::de::Error },
+    InvalidPlaylist { playlist: String, reason: String },
+}
+
+impl Error for SmartPlaylistError {
+    fn description(&self) -> &str {
+        match *self {
+            SmartPlaylistError::IOError { .. } => "I/O error reading smart playlists.",
+            SmartPlaylistError::ParseError { .. } => "Unable to parse smart playlists.",
+            SmartPlaylistError::InvalidPlaylist { .. } => "Invalid smart playlist.",
+        }
+    }
+}
+
+impl fmt::Display for SmartPlaylistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SmartPlaylistError::IOError { ref path, ref err } => write!(
+                f,
+                "I/O error reading smart playlists from {}: {}",
+                path.display(),
+                err
+            ),
+            SmartPlaylistError::ParseError { ref path, ref err } => write!(
+                f,
+                "Unable to parse smart playlists from {}: {}",
+                path.display(),
+                err
+            ),
+            SmartPlaylistError::InvalidPlaylist {
+                ref playlist,
+                ref reason,
+            } => write!(f, "Invalid smart playlist {:?}: {}", playlist, reason),
+        }
+    }
+}
+
+impl SmartPlaylistConfig {
+    /// The default location of the configuration, under `$XDG_CONFIG_HOME` or `$HOME/.config`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+
+        Some(config_dir.join("phatnoise").join(CONFIG_FILE_NAME))
+    }
+
+    /// Load the configuration at the given path. A missing file defines no smart playlists.
+    pub fn load(path: &Path) -> Result<Self, SmartPlaylistError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(SmartPlaylistError::IOError {
+                    path: path.to_path_buf(),
+                    err,
+                })
+            }
+        };
+
+        let config: SmartPlaylistConfig =
+            toml::from_str(&contents).map_err(|err| SmartPlaylistError::ParseError {
+                path: path.to_path_buf(),
+                err,
+            })?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that playlist names are unique and that every rule compares a field with a value of
+    /// the same kind.
+    pub fn validate(&self) -> Result<(), SmartPlaylistError> {
+        let mut names = HashSet::new();
+
+        for playlist in &self.playlists {
+            let invalid = |reason: String| SmartPlaylistError::InvalidPlaylist {
+                playlist: playlist.name.clone(),
+                reason,
+            };
+
+            if playlist.name.trim().is_empty() {
+                return Err(invalid("the name is empty".to_string()));
+            }
+
+            if playlist.name.contains(['/', '\\']) {
+                return Err(invalid("the name contains a path separator".to_string()));
+            }
+
+            if !names.insert(playlist.name.to_lowercase()) {
+                return Err(invalid("the name is used more than once".to_string()));
+            }
+
+            // with no rules, a playlist would hold either every track or none of them
+            if playlist.rules.is_empty() {
+                return Err(invalid("there are no rules".to_string()));
+            }
+
+            for rule in &playlist.rules {
+                rule.validate().map_err(invalid)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SmartPlaylist {
+    /// Select the matching tracks and put them in the playlist's order.
+    pub fn evaluate<'a>(&self, tracks: &'a [MediaMetadata]) -> Vec<&'a MediaMetadata> {
+        let mut selected: Vec<&MediaMetadata> = tracks.iter().filter(|t| self.matches(t)).collect();
+
+        match self.order {
+            Order::Artist => selected.sort_by(|a, b| MediaMetadata::by_artist(a, b)),
+            Order::Genre => selected.sort_by(|a, b| MediaMetadata::by_genre(a, b)),
+            Order::Shuffle => return self.shuffle.shuffle(&selected),
+        }
+
+        selected
+    }
+
+    pub fn matches(&self, track: &MediaMetadata) -> bool {
+        match self.combine {
+            Combine::All => self.rules.iter().all(|r| r.matches(track)),
+            Combine::Any => self.rules.iter().any(|r| r.matches(track)),
+        }
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, track: &MediaMetadata) -> bool {
+        let value = self.field.value(track);
+
+        match self.condition {
+            Condition::Is(ref expected) => value.equals(expected),
+            Condition::IsNot(ref expected) => !value.equals(expected),
+            Condition::In(ref expected) => expected.iter().any(|e| value.equals(e)),
+            Condition::Contains(ref needle) => match value {
+                FieldValue::Text(text) => text.to_lowercase().contains(&needle.to_lowercase()),
+                FieldValue::Number(_) => false,
+            },
+            Condition::Under(limit) => matches!(value, FieldValue::Number(n) if n < limit),
+            Condition::Over(limit) => matches!(value, FieldValue::Number(n) if n > limit),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let numeric = self.field.is_numeric();
+        let mismatched = |value: &Value| matches!(value, Value::Number(_)) != numeric;
+
+        let valid = match self.condition {
+            Condition::Is(ref value) | Condition::IsNot(ref value) => !mismatched(value),
+            Condition::In(ref values) => !values.iter().any(mismatched),
+            Condition::Contains(_) => !numeric,
+            Condition::Under(_) | Condition::Over(_) => numeric,
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(format!(
+                "the {:?} condition doesn't apply to the {:?} field",
+                self.condition, self.field
+            ))
+        }
+    }
+}
+
+impl Field {
+    fn is_numeric(self) -> bool {
+        matches!(self, Field::TrackNumber | Field::Duration)
+    }
+
+    fn value(self, track: &MediaMetadata) -> FieldValue<'_> {
+        match self {
+            Field::Artist => FieldValue::Text(&track.artist),
+            Field::Album => FieldValue::Text(&track.album),
+            Field::Genre => FieldValue::Text(&track.genre),
+            Field::Title => FieldValue::Text(&track.title),
+            Field::TrackNumber => FieldValue::Number(u64::from(track.track_number)),
+            Field::Duration => FieldValue::Number(track.duration),
+        }
+    }
+}
+
+impl FieldValue<'_> {
+    fn equals(&self, expected: &Value) -> bool {
+        match (self, expected) {
+            (FieldValue::Number(n), Value::Number(e)) => n == e,
+            (FieldValue::Text(t), Value::Text(e)) => t.to_lowercase() == e.to_lowercase(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::data::test::load_fixtures;
+    use crate::utils::StringPool;
+
+    fn parse(config: &str) -> SmartPlaylistConfig {
+        let config: SmartPlaylistConfig = toml::from_str(config).unwrap();
+        config.validate().unwrap();
+        config
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = parse(concat!(
+            "[[playlist]]\n",
+            "name = \"Short Jazz\"\n",
+            "order = \"shuffle\"\n",
+            "[[playlist.rule]]\n",
+            "field = \"genre\"\n",
+            "is = \"Jazz\"\n",
+            "[[playlist.rule]]\n",
+            "field = \"duration\"\n",
+            "under = 600\n",
+            "[playlist.shuffle]\n",
+            "seed = 42\n",
+            "[[playlist]]\n",
+            "name = \"Favourites\"\n",
+            "[[playlist.rule]]\n",
+            "field = \"album_artist\"\n",
+            "in = [\"Andrew W.K.\", \"Mêlée\"]\n",
+        ));
+
+        assert_eq!(2, config.playlists.len());
+
+        let jazz = &config.playlists[0];
+        assert_eq!(Order::Shuffle, jazz.order);
+        assert_eq!(Combine::All, jazz.combine);
+        assert_eq!(Field::Genre, jazz.rules[0].field);
+        assert!(
+            matches!(jazz.rules[0].condition, Condition::Is(Value::Text(ref g)) if g == "Jazz")
+        );
+        assert!(matches!(jazz.rules[1].condition, Condition::Under(600)));
+        assert_eq!(SpreadShuffle::with_seed(42), jazz.shuffle);
+
+        let favourites = &config.playlists[1];
+        assert_eq!(Order::Artist, favourites.order);
+        assert_eq!(Field::Artist, favourites.rules[0].field);
+    }
+
+    #[test]
+    fn test_validate() {
+        for config in &[
+            "[[playlist]]\nname = \"A\"\n[[playlist.rule]]\nfield = \"genre\"\nunder = 3\n",
+            "[[playlist]]\nname = \"A\"\n[[playlist.rule]]\nfield = \"duration\"\nis = \"3\"\n",
+            "[[playlist]]\nname = \"A\"\n[[playlist.rule]]\nfield = \"duration\"\ncontains = \"3\"\n",
+            "[[playlist]]\nname = \"A\"\n",
+            concat!(
+                "[[playlist]]\nname = \"A\"\n[[playlist.rule]]\nfield = \"genre\"\nis = \"Jazz\"\n",
+                "[[playlist]]\nname = \"a\"\n[[playlist.rule]]\nfield = \"genre\"\nis = \"Rock\"\n",
+            ),
+        ] {
+            let config: SmartPlaylistConfig = toml::from_str(config).unwrap();
+            assert!(config.validate().is_err(), "{}", config.playlists[0].name);
+        }
+
+        // rules without a condition, or with an unknown field, don't parse at all
+        assert!(toml::from_str::<SmartPlaylistConfig>(
+            "[[playlist]]\nname = \"A\"\n[[playlist.rule]]\nfield = \"genre\"\n"
+        )
+        .is_err());
+        assert!(toml::from_str::<SmartPlaylistConfig>(
+            "[[playlist]]\nname = \"A\"\n[[playlist.rule]]\nfield = \"mood\"\nis = \"Happy\"\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SmartPlaylistConfig::load(&dir.path().join(CONFIG_FILE_NAME)).unwrap();
+
+        assert!(config.playlists.is_empty());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let pool = StringPool::new();
+        let tracks = load_fixtures(&pool);
+
+        let config = parse(concat!(
+            "[[playlist]]\n",
+            "name = \"Known Artists\"\n",
+            "order = \"genre\"\n",
+            "[[playlist.rule]]\n",
+            "field = \"artist\"\n",
+            "is_not = \"unknown artist\"\n",
+            "[[playlist]]\n",
+            "name = \"Either\"\n",
+            "match = \"any\"\n",
+            "[[playlist.rule]]\n",
+            "field = \"artist\"\n",
+            "in = [\"composer\", \"album artist\"]\n",
+            "[[playlist.rule]]\n",
+            "field = \"artist\"\n",
+            "contains = \"UNKNOWN\"\n",
+        ));
+
+        let known = config.playlists[0].evaluate(&tracks);
+        let mut expected: Vec<&MediaMetadata> = tracks
+            .iter()
+            .filter(|t| &*t.artist != "Unknown Artist")
+            .collect();
+        expected.sort_by(|a, b| MediaMetadata::by_genre(a, b));
+
+        assert_eq!(3, known.len());
+        assert_eq!(
+            expected.iter().map(|t| &t.path).collect::<Vec<_>>(),
+            known.iter().map(|t| &t.path).collect::<Vec<_>>()
+        );
+
+        let either = config.playlists[1].evaluate(&tracks);
+        assert_eq!(6, either.len());
+        assert!(either.iter().all(|t| &*t.artist != "Artist"));
+    }
+}
@@ -0,0 +1,101 @@
+//! Reading of XSPF playlists.
+
+use percent_encoding::percent_decode_str;
+
+use crate::playlist::{has_scheme, PlaylistEntry};
+
+use roxmltree::{Document, Node};
+
+/// Parse the entries of an XSPF playlist.
+///
+/// Each track's first `location` becomes an entry, and tracks without one are skipped. Locations
+/// are URIs, so relative references are percent-decoded here while absolute URIs are left for
+/// resolution. Elements are matched by local name, so a missing or unusual namespace is accepted.
+pub fn parse(contents: &str) -> Result<Vec<PlaylistEntry>, roxmltree::Error> {
+    let document = Document::parse(contents.trim_start_matches('\u{feff}'))?;
+
+    let entries = document
+        .root_element()
+        .children()
+        .filter(|n| is_element(n, "trackList"))
+        .flat_map(|list| list.children().filter(|n| is_element(n, "track")))
+        .filter_map(|track| {
+            let location = child_text(&track, "location")?;
+
+            let location = if has_scheme(&location) {
+                location
+            } else {
+                percent_decode_str(&location)
+                    .decode_utf8_lossy()
+                    .into_owned()
+            };
+
+            Some(PlaylistEntry {
+                location,
+                title: child_text(&track, "title"),
+            })
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+fn is_element(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
+
+/// Get the trimmed text of the first child element with the given name, if it isn't empty.
+fn child_text(node: &Node, name: &str) -> Option<String> {
+    node.children()
+        .find(|n| is_element(n, name))
+        .and_then(|n| n.text())
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let entries = parse(concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<playlist version="1" xmlns="http://xspf.org/ns/0/">"#,
+            "<trackList>",
+            "<track>",
+            "<location>file:///home/user/Music/M%C3%AAl%C3%A9e/01%20-%20Got%20It%20All.flac</location>",
+            "<title>Got It All</title>",
+            "</track>",
+            "<track><title>No Location</title></track>",
+            "<track>",
+            "<location>Andrew%20W.%20K./I%20Get%20Wet/02%20-%20Party%20Hard.mp3</location>",
+            "<location>ignored.mp3</location>",
+            "</track>",
+            "</trackList>",
+            "</playlist>",
+        ))
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                PlaylistEntry {
+                    location:
+                        "file:///home/user/Music/M%C3%AAl%C3%A9e/01%20-%20Got%20It%20All.flac"
+                            .to_string(),
+                    title: Some("Got It All".to_string()),
+                },
+                PlaylistEntry {
+                    location: "Andrew W. K./I Get Wet/02 - Party Hard.mp3".to_string(),
+                    title: None,
+                },
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        assert!(parse("<playlist><trackList>").is_err());
+    }
+}
@@ -1,124 +1,705 @@
-use log::{debug, error, info};
+pub mod manifest;
+pub mod plan;
+pub mod report;
+pub mod timestamps;
 
+use log::{debug, info, warn};
+
+use crate::data::encoding::OutputEncoding;
 use crate::dms;
-use crate::library::get_dms_media_library;
-use crate::library::get_local_media_library;
+use crate::library::get_dms_media_library_at;
+use crate::library::scan_merged_media_library;
 use crate::library::LibraryFile;
-use crate::library::LibrarySource;
+use crate::metadata::cache::MetadataCache;
+use crate::metadata::MediaMetadata;
+use crate::playlist::smart::SmartPlaylistConfig;
+use crate::playlist::sync_playlists;
+use crate::sync::manifest::{Manifest, ManifestEntry};
+use crate::sync::plan::{ChangeReason, PlannedCopy, PlannedRename, SyncPlan};
+use crate::sync::report::{Operation, SyncFailure, SyncReport};
+use crate::sync::timestamps::{TimeComparison, DEFAULT_TOLERANCE};
 use crate::utils::crypto::sha256sum;
-use crate::utils::fs::copy_mtime;
+use crate::utils::fs::{copy_mtime, sync_dir};
+use crate::utils::StringPool;
 
 use rayon::prelude::*;
 
+use walkdir::WalkDir;
+
+use std::borrow::Cow;
 use std::collections::{BTreeSet, HashSet};
 use std::env;
+use std::error::Error;
+use std::fmt;
 use std::fs;
-use std::path::Path;
-use std::process;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The suffix of the temporary files copies are written to on the DMS.
+const TEMP_SUFFIX: &str = ".phatnoise-partial";
+
+/// The failure of a copy or rename to a destination without a parent, which is never on the DMS.
+const NO_PARENT: &str = "the destination has no parent directory";
+
+/// Where a sync reads media from and writes it to.
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    /// The library roots to sync from, in order of precedence. When empty, `$HOME/Music` is used.
+    pub sources: Vec<PathBuf>,
+    /// The directory to sync to. When unset, the mount point of the attached DMS is used; when
+    /// set, device detection is skipped entirely, so any directory can stand in for a DMS.
+    pub target: Option<PathBuf>,
+    pub error_policy: ErrorPolicy,
+    /// The largest difference between local and DMS modification times which counts as unchanged.
+    /// When unset, the 2 second resolution of FAT is allowed for.
+    pub mtime_tolerance: Option<Duration>,
+    /// The encoding playlists are written in, as for the databases.
+    pub encoding: OutputEncoding,
+    /// The smart playlist configuration to evaluate. When unset, no smart playlists are written.
+    pub smart_playlists: Option<PathBuf>,
+    /// The cache of the local metadata smart playlists are evaluated against. When unset, the
+    /// metadata is parsed afresh on every sync.
+    pub metadata_cache: Option<PathBuf>,
+}
+
+/// What a sync does when a single file can't be synced.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ErrorPolicy {
+    /// Stop the sync at the first failure.
+    FailFast,
+    /// Record the failure in the report and carry on with the next file.
+    #[default]
+    BestEffort,
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    NoDmsDevice,
+    DmsNotMounted,
+    /// No library roots were given and there is no home directory to default to.
+    NoLibraryRoot,
+    /// A library root isn't a directory, such as a network share which isn't mounted. Syncing
+    /// from it would delete everything under it from the DMS.
+    MissingSource {
+        path: PathBuf,
+    },
+    MissingTarget {
+        path: PathBuf,
+    },
+    /// The target is a library root, or is inside or contains one.
+    OverlappingTarget {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    /// A file couldn't be synced and the error policy is to fail fast.
+    Failed {
+        failure: SyncFailure,
+    },
+}
+
+impl Error for SyncError {
+    fn description(&self) -> &str {
+        match *self {
+            SyncError::NoDmsDevice => "No DMS device detected.",
+            SyncError::DmsNotMounted => "DMS device is present but not mounted.",
+            SyncError::NoLibraryRoot => "No library root available.",
+            SyncError::MissingSource { .. } => "Library root does not exist.",
+            SyncError::MissingTarget { .. } => "Target directory does not exist.",
+            SyncError::OverlappingTarget { .. } => "Target directory overlaps a library root.",
+            SyncError::Failed { .. } => "Unable to sync a file.",
+        }
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyncError::NoDmsDevice => write!(f, "No DMS device detected."),
+            SyncError::DmsNotMounted => write!(f, "DMS device is present but not mounted."),
+            SyncError::NoLibraryRoot => write!(
+                f,
+                "Unable to detect home directory, and no library root was given."
+            ),
+            SyncError::MissingSource { ref path } => {
+                write!(f, "Library root {} is not a directory.", path.display())
+            }
+            SyncError::MissingTarget { ref path } => {
+                write!(f, "Target directory {} does not exist.", path.display())
+            }
+            SyncError::OverlappingTarget {
+                ref source,
+                ref target,
+            } => write!(
+                f,
+                "Target directory {} overlaps library root {}.",
+                target.display(),
+                source.display()
+            ),
+            SyncError::Failed { ref failure } => write!(f, "{}", failure),
+        }
+    }
+}
+
+impl SyncOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn source<P: Into<PathBuf>>(mut self, source: P) -> Self {
+        self.sources.push(source.into());
+        self
+    }
+
+    pub fn target<P: Into<PathBuf>>(mut self, target: P) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    pub fn mtime_tolerance(mut self, tolerance: Duration) -> Self {
+        self.mtime_tolerance = Some(tolerance);
+        self
+    }
+
+    pub fn encoding(mut self, encoding: OutputEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn smart_playlists<P: Into<PathBuf>>(mut self, config: P) -> Self {
+        self.smart_playlists = Some(config.into());
+        self
+    }
+
+    pub fn metadata_cache<P: Into<PathBuf>>(mut self, cache: P) -> Self {
+        self.metadata_cache = Some(cache.into());
+        self
+    }
+
+    /// The library roots to sync from, falling back to the default music directory, checking
+    /// that each one exists.
+    fn source_dirs(&self) -> Result<Vec<PathBuf>, SyncError> {
+        let sources = if self.sources.is_empty() {
+            vec![default_music_dir().ok_or(SyncError::NoLibraryRoot)?]
+        } else {
+            self.sources.clone()
+        };
+
+        match sources.iter().find(|source| !source.is_dir()) {
+            Some(source) => Err(SyncError::MissingSource {
+                path: source.clone(),
+            }),
+            None => Ok(sources),
+        }
+    }
+
+    /// The library roots and the directory to sync to, checking that the target neither is nor
+    /// overlaps a library root, so that a sync can't delete or overwrite the library.
+    fn dirs(&self) -> Result<(Vec<PathBuf>, PathBuf), SyncError> {
+        let (sources, target) = (self.source_dirs()?, self.target_dir()?);
+        let canonical = |dir: &Path| fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        let target_path = canonical(&target);
+
+        for source in &sources {
+            let source_path = canonical(source);
+
+            if source_path.starts_with(&target_path) || target_path.starts_with(&source_path) {
+                return Err(SyncError::OverlappingTarget {
+                    source: source.clone(),
+                    target,
+                });
+            }
+        }
+
+        Ok((sources, target))
+    }
+
+    /// The directory to sync to, checking that it exists.
+    fn target_dir(&self) -> Result<PathBuf, SyncError> {
+        match self.target {
+            Some(ref target) if target.is_dir() => Ok(target.clone()),
+            Some(ref target) => Err(SyncError::MissingTarget {
+                path: target.clone(),
+            }),
+            None => {
+                check_dms()?;
+                dms::get_dms_mount_point().ok_or(SyncError::DmsNotMounted)
+            }
+        }
+    }
+
+    /// Record a failure in the report, or stop the sync if failing fast.
+    fn fail(&self, report: &mut SyncReport, failure: SyncFailure) -> Result<(), SyncError> {
+        warn!("{}", failure);
+
+        match self.error_policy {
+            ErrorPolicy::FailFast => Err(SyncError::Failed { failure }),
+            ErrorPolicy::BestEffort => {
+                report.failures.push(failure);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The default library root, `$HOME/Music`.
+pub fn default_music_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(|home| PathBuf::from(home).join("Music"))
+}
+
+pub fn synchronize(options: &SyncOptions) -> Result<SyncReport, SyncError> {
+    synchronize_media_files(options)
+}
+
+/// Plan a sync of the local media library to the target without changing anything there.
+pub fn plan(options: &SyncOptions) -> Result<SyncPlan, SyncError> {
+    let (local_dirs, dms_dir) = options.dirs()?;
+    let (local, dms, unreadable) = load_libraries(&local_dirs, &dms_dir);
+
+    let mut plan = SyncPlan::compute(
+        &local,
+        &dms,
+        &dms_dir,
+        &Manifest::load(&dms_dir),
+        options.mtime_tolerance.unwrap_or(DEFAULT_TOLERANCE),
+    );
+    plan.failures.splice(0..0, unreadable);
+
+    Ok(plan)
+}
+
+/// Load the local library and the library on the DMS.
+///
+/// Files on the DMS under a local directory which couldn't be read are left out, so that a sync
+/// neither deletes nor moves them; the unreadable directories are returned as failures.
+fn load_libraries(
+    local_dirs: &[PathBuf],
+    dms_dir: &Path,
+) -> (
+    BTreeSet<LibraryFile>,
+    BTreeSet<LibraryFile>,
+    Vec<SyncFailure>,
+) {
+    let (local, unreadable) = scan_merged_media_library(local_dirs);
+    let mut dms = get_dms_media_library_at(dms_dir);
+
+    dms.retain(|file| !unreadable.iter().any(|path| file.is_under(path)));
+
+    let failures = unreadable
+        .into_iter()
+        .map(|path| SyncFailure::new(path.path, Operation::Scan, path.err))
+        .collect();
 
-pub fn synchronize() {
+    (local, dms, failures)
+}
+
+fn check_dms() -> Result<(), SyncError> {
     if !dms::is_dms_present() {
-        error!("No DMS device detected.");
-        process::exit(1);
+        return Err(SyncError::NoDmsDevice);
     }
 
     if !dms::is_dms_mounted() {
-        error!("DMS device is present but not mounted.");
-        process::exit(1);
+        return Err(SyncError::DmsNotMounted);
     }
 
-    synchronize_media_files();
+    Ok(())
 }
 
-pub fn synchronize_media_files() {
+/// Sync the local media library and playlists to the target. Files which can't be synced are
+/// handled according to the error policy.
+pub fn synchronize_media_files(options: &SyncOptions) -> Result<SyncReport, SyncError> {
     info!("Synchronizing media files with DMS...");
 
-    let local_dir = Path::join(
-        Path::new(&match env::var("HOME") {
-            Ok(value) => value,
-            Err(e) => {
-                error!("Unable to detect home directory: {}", e);
-                process::exit(1);
-            }
-        }),
-        Path::new("Music"),
-    );
+    let (local_dirs, dms_dir) = options.dirs()?;
+    let mut report = SyncReport::default();
 
-    let dms_dir = dms::get_dms_mount_point().expect("DMS not present or not mounted.");
+    for dir in &local_dirs {
+        debug!("Music directory: {}", dir.display());
+    }
+
+    debug!("Target directory: {}", dms_dir.display());
 
-    debug!("Music directory: {}", local_dir.display());
+    // an interrupted sync may have left partial copies behind
+    for temp in temp_files(&dms_dir) {
+        debug!("Removing partial copy {}", temp.display());
+
+        if let Err(e) = fs::remove_file(&temp) {
+            options.fail(&mut report, SyncFailure::new(&temp, Operation::Delete, e))?;
+        }
+    }
 
     // load a list of files from the local media library and from the DMS
-    let (local, dms) = (get_local_media_library(&local_dir), get_dms_media_library());
+    let (local, dms, unreadable) = load_libraries(&local_dirs, &dms_dir);
+    let mut manifest = Manifest::load(&dms_dir);
     // use hardcore HashSet intersections to detect what is new, changed, and deleted
-    let (added, deleted, changed) = (
-        added_files(&local, &dms),
-        deleted_files(&local, &dms),
-        changed_files(&local, &dms),
-    );
+    let tolerance = options.mtime_tolerance.unwrap_or(DEFAULT_TOLERANCE);
+    let mut plan = SyncPlan::compute(&local, &dms, &dms_dir, &manifest, tolerance);
+    plan.failures.splice(0..0, unreadable);
+
+    // files which couldn't be read or compared are left alone
+    for failure in &plan.failures {
+        options.fail(&mut report, failure.clone())?;
+    }
+
+    // move files which were moved locally, rather than copying them again
+    info!("Renaming {} moved files on the DMS...", plan.renames.len());
+
+    for file in &plan.renames {
+        match rename_file(file) {
+            Ok(()) => {
+                report.renamed += 1;
+                record(&mut manifest, &file.path, &file.source);
+            }
+            Err(failure) => options.fail(&mut report, failure)?,
+        }
+    }
 
     // copy new files
-    info!("Copying {} new files to the DMS...", added.len());
-    copy_files(&added, &local, &dms_dir);
+    info!("Copying {} new files to the DMS...", plan.copies.len());
+
+    for file in &plan.copies {
+        match copy_file(file) {
+            Ok(()) => {
+                report.copied += 1;
+                report.bytes_copied += file.bytes;
+                record(&mut manifest, &file.path, &file.source);
+            }
+            Err(failure) => options.fail(&mut report, failure)?,
+        }
+    }
 
     // copy updated files
-    info!("Copying {} changed files to the DMS...", changed.len());
-    copy_files(&changed, &local, &dms_dir);
+    info!("Copying {} changed files to the DMS...", plan.updates.len());
+
+    for file in &plan.updates {
+        match copy_file(file) {
+            Ok(()) => {
+                report.updated += 1;
+                report.bytes_copied += file.bytes;
+                record(&mut manifest, &file.path, &file.source);
+            }
+            Err(failure) => {
+                // the copy on the DMS may be the old or the new contents now
+                manifest.remove(&file.path);
+                options.fail(&mut report, failure)?
+            }
+        }
+    }
 
     // delete removed files
-    info!("Deleting {} orphaned files from the DMS...", deleted.len());
-    delete_files(&deleted);
-}
+    info!(
+        "Deleting {} orphaned files from the DMS...",
+        plan.deletes.len()
+    );
 
-fn copy_files(files: &Vec<&LibraryFile>, local: &BTreeSet<LibraryFile>, dms_dir: &Path) {
-    for file in files {
-        let (source, dest) = (
-            &local.get(file).unwrap().path,
-            Path::join(&dms_dir, Path::new(&file.debase())),
-        );
-        debug!(
-            "Copying local file {} to DMS at {}...",
-            source.display(),
-            dest.display()
-        );
+    // we only delete files that are explicitly on the DMS to be safe
+    for file in plan.deletes.iter().filter(|f| f.dest.starts_with(&dms_dir)) {
+        debug!("Deleting orphaned file from DMS {}", file.dest.display());
 
-        let dest_dir = &dest
-            .parent()
-            .expect(format!("Unable to get parent directory for {}", dest.display()).as_str());
+        match fs::remove_file(&file.dest) {
+            Ok(()) => report.deleted += 1,
+            Err(e) => options.fail(
+                &mut report,
+                SyncFailure::new(&file.dest, Operation::Delete, e),
+            )?,
+        }
+    }
 
-        // create parent directory for file
-        if !dest_dir.is_dir() {
-            debug!("Creating parent directory {}", dest_dir.display());
-            fs::create_dir_all(&dest_dir).expect(
-                format!("Unable to create parent directory for {}", dest.display()).as_str(),
+    // checksums matched, so copy the modification time from local to remote to resolve future
+    // comparisons
+    for file in &plan.touches {
+        match copy_mtime(&file.source, &file.dest) {
+            Ok(()) => report.touched += 1,
+            Err(e) => options.fail(
+                &mut report,
+                SyncFailure::new(&file.dest, Operation::Touch, e),
+            )?,
+        }
+    }
+
+    // fill in the manifest for files synced before it was lost, or before it existed, and forget
+    // files which are gone
+    rebuild_manifest(&mut manifest, &local, &dms, &plan);
+
+    if let Err(e) = manifest.save(&dms_dir) {
+        let failure = SyncFailure::new(Manifest::path(&dms_dir), Operation::Manifest, e);
+        options.fail(&mut report, failure)?;
+    }
+
+    // rewrite local playlists to point at the files on the DMS
+    info!("Synchronizing playlists with DMS...");
+
+    let smart = match options.smart_playlists {
+        Some(ref config_path) => match SmartPlaylistConfig::load(config_path) {
+            Ok(config) => config.playlists,
+            Err(e) => {
+                // leave the playlists on the DMS alone rather than deleting the smart ones
+                let failure = SyncFailure::new(config_path, Operation::Playlist, e);
+                options.fail(&mut report, failure)?;
+                return Ok(report);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    let pool = StringPool::new();
+    let tracks = if smart.is_empty() {
+        Vec::new()
+    } else {
+        load_metadata(
+            &local_dirs,
+            &local,
+            options.metadata_cache.as_deref(),
+            &pool,
+        )
+    };
+
+    match sync_playlists(
+        &local_dirs,
+        &local,
+        &smart,
+        &tracks,
+        &dms_dir,
+        options.encoding,
+    ) {
+        Ok(mut playlists) => {
+            for e in playlists.errors.drain(..) {
+                let failure = SyncFailure::new(e.path(), Operation::Playlist, &e);
+                options.fail(&mut report, failure)?;
+            }
+
+            for entry in &playlists.unresolved {
+                warn!(
+                    "Dropping entry {} from playlist {}: {}",
+                    entry.location,
+                    entry.playlist.display(),
+                    entry.reason
+                );
+            }
+
+            info!(
+                "Wrote {} playlists and deleted {} orphaned playlists from the DMS.",
+                playlists.written.len(),
+                playlists.removed.len()
             );
+
+            report.playlists = Some(playlists);
+        }
+        Err(e) => {
+            let failure = SyncFailure::new(e.path(), Operation::Playlist, &e);
+            options.fail(&mut report, failure)?;
         }
+    }
 
-        // copy file
-        fs::copy(&source, &dest)
-            .expect(format!("Unable to copy file {} to DMS", source.display()).as_str());
-        // update modification time
-        copy_mtime(&source, &dest).expect(
-            format!(
-                "Unable to copy modification time from source to destination {}",
-                dest.display()
-            )
-            .as_str(),
-        );
+    Ok(report)
+}
+
+/// Load the metadata for the local library, which smart playlists are evaluated against, through
+/// the cache at the given path if there is one.
+fn load_metadata(
+    local_dirs: &[PathBuf],
+    local: &BTreeSet<LibraryFile>,
+    cache_path: Option<&Path>,
+    pool: &StringPool,
+) -> Vec<MediaMetadata> {
+    let roots: Vec<(PathBuf, Vec<PathBuf>)> = local_dirs
+        .iter()
+        .map(|dir| {
+            let paths = local
+                .iter()
+                .filter(|f| &f.base == dir)
+                .map(|f| f.path.clone())
+                .collect();
+
+            (dir.clone(), paths)
+        })
+        .collect();
+
+    match cache_path {
+        Some(cache_path) => {
+            let mut cache = MetadataCache::open(cache_path);
+            let tracks = cache.load_roots(&roots, pool);
+
+            if let Err(e) = cache.save() {
+                warn!("Unable to save the metadata cache: {}", e);
+            }
+
+            tracks
+        }
+        None => roots
+            .iter()
+            .flat_map(|(base, paths)| paths.iter().map(move |p| (base, p)))
+            .filter_map(|(base, p)| match MediaMetadata::load(p, base, pool) {
+                Ok(metadata) => Some(metadata),
+                Err(e) => {
+                    warn!("Unable to load metadata for {}: {}", p.display(), e);
+                    None
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Record a local file which was just synced in the manifest. If the local file can't be read, the
+/// file is forgotten instead, so that it is compared in full next time.
+fn record(manifest: &mut Manifest, path: &str, source: &Path) {
+    match ManifestEntry::of(source) {
+        Ok(entry) => manifest.insert(path, entry),
+        Err(e) => {
+            warn!("Unable to add {} to the sync manifest: {}", path, e);
+            manifest.remove(path);
+        }
     }
 }
 
-fn delete_files(files: &Vec<&LibraryFile>) {
-    // we find all files in the list that are explicitly on the DMS to be safe
-    for file in files
+/// Record the files present on both sides which the manifest is missing or out of date on, and
+/// forget the files no longer in the local library.
+fn rebuild_manifest(
+    manifest: &mut Manifest,
+    local: &BTreeSet<LibraryFile>,
+    dms: &BTreeSet<LibraryFile>,
+    plan: &SyncPlan,
+) {
+    // updated files were recorded as they were copied, and files which couldn't be compared
+    // may differ on the DMS
+    let skipped: HashSet<&Path> = plan
+        .updates
+        .iter()
+        .map(|f| f.source.as_path())
+        .chain(plan.failures.iter().map(|f| f.path.as_path()))
+        .collect();
+
+    let stale: Vec<&LibraryFile> = local
         .iter()
-        .filter(|f| f.source == LibrarySource::DMS)
-        .map(|f| &f.path)
-    {
-        debug!("Deleting orphaned file from DMS {}", file.display());
-        fs::remove_file(file)
-            .expect(format!("Unable to remove file from DMS: {}", file.display()).as_str());
+        .filter(|f| dms.contains(*f) && !skipped.contains(f.path.as_path()))
+        .filter(|f| {
+            !manifest
+                .get(&f.debase())
+                .is_some_and(|e| e.is_current(&f.path))
+        })
+        .collect();
+
+    if !stale.is_empty() {
+        info!("Adding {} files to the sync manifest...", stale.len());
+    }
+
+    let entries: Vec<(&LibraryFile, io::Result<ManifestEntry>)> = stale
+        .into_par_iter()
+        .map(|f| (f, ManifestEntry::of(&f.path)))
+        .collect();
+
+    for (file, entry) in entries {
+        match entry {
+            Ok(entry) => manifest.insert(&file.debase(), entry),
+            Err(e) => warn!(
+                "Unable to add {} to the sync manifest: {}",
+                file.debase(),
+                e
+            ),
+        }
+    }
+
+    let paths: HashSet<Cow<str>> = local.iter().map(LibraryFile::debase).collect();
+    manifest.retain(|path| paths.contains(path));
+}
+
+/// Copy a file to the DMS.
+///
+/// The file is written to a temporary name alongside its destination, synced with its modification
+/// time set, and only then renamed into place, so that an interrupted copy never leaves a truncated
+/// file under the real name.
+fn copy_file(file: &PlannedCopy) -> Result<(), SyncFailure> {
+    let (source, dest) = (&file.source, &file.dest);
+    debug!(
+        "Copying local file {} to DMS at {}...",
+        source.display(),
+        dest.display()
+    );
+
+    let failed = |e: &dyn fmt::Display| SyncFailure::new(source, Operation::Copy, e);
+    let dest_dir = dest.parent().ok_or_else(|| failed(&NO_PARENT))?;
+
+    // create parent directory for file
+    if !dest_dir.is_dir() {
+        debug!("Creating parent directory {}", dest_dir.display());
+        fs::create_dir_all(dest_dir).map_err(|e| failed(&e))?;
+    }
+
+    let temp = temp_path(dest);
+
+    let result = fs::copy(source, &temp)
+        .map_err(|e| failed(&e))
+        // update modification time before syncing, so that it is written out with the contents
+        .and_then(|_| copy_mtime(source, &temp).map_err(|e| failed(&e)))
+        .and_then(|_| {
+            File::open(&temp)
+                .and_then(|f| f.sync_all())
+                .map_err(|e| failed(&e))
+        })
+        .and_then(|_| fs::rename(&temp, dest).map_err(|e| failed(&e)));
+
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
     }
+
+    result?;
+
+    // the rename is only durable once the directory is synced
+    sync_dir(dest_dir).map_err(|e| failed(&e))
+}
+
+/// Move a file on the DMS to where a local file with the same contents belongs.
+fn rename_file(file: &PlannedRename) -> Result<(), SyncFailure> {
+    debug!(
+        "Renaming DMS file {} to {}...",
+        file.from.display(),
+        file.dest.display()
+    );
+
+    let failed = |e: &dyn fmt::Display| SyncFailure::new(&file.from, Operation::Rename, e);
+    let dest_dir = file.dest.parent().ok_or_else(|| failed(&NO_PARENT))?;
+
+    if !dest_dir.is_dir() {
+        debug!("Creating parent directory {}", dest_dir.display());
+        fs::create_dir_all(dest_dir).map_err(|e| failed(&e))?;
+    }
+
+    fs::rename(&file.from, &file.dest).map_err(|e| failed(&e))?;
+    // the file on the DMS may not have the same modification time as the local one
+    copy_mtime(&file.source, &file.dest).map_err(|e| failed(&e))?;
+
+    sync_dir(dest_dir).map_err(|e| failed(&e))?;
+
+    match file.from.parent() {
+        Some(from_dir) if from_dir != dest_dir => sync_dir(from_dir).map_err(|e| failed(&e)),
+        _ => Ok(()),
+    }
+}
+
+/// The temporary name a file is copied to before being renamed into place.
+pub(crate) fn temp_path(dest: &Path) -> PathBuf {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    dest.with_file_name(format!(".{}{}", name, TEMP_SUFFIX))
+}
+
+/// Find the partial copies left on the DMS by an interrupted sync.
+fn temp_files(dms_dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(dms_dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| e.file_name().to_string_lossy().ends_with(TEMP_SUFFIX))
+        .map(|e| e.into_path())
+        .collect()
 }
 
 /// Retrieve a list of new files to be copied to the DMS.
@@ -137,62 +718,395 @@ pub fn deleted_files<'a>(
     dms.difference(local).collect()
 }
 
-/// Retrieve a list of changed files to be updated on the DMS.
-pub fn changed_files<'a>(
-    local: &'a BTreeSet<LibraryFile>,
-    dms: &'a BTreeSet<LibraryFile>,
-) -> Vec<&'a LibraryFile> {
-    local
-        .into_par_iter()
-        .filter(|p| {
-            if !dms.contains(p) {
-                // if the DMS does not have the file, omit it
-                return false;
-            }
+/// The result of comparing a local file with its copy on the DMS.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Comparison {
+    Unchanged,
+    /// The contents match, but the modification times are too far apart.
+    Touched,
+    Changed(ChangeReason),
+}
 
-            let (local, remote) = (p, dms.get(*p).unwrap());
-            let (lmeta, rmeta) = (
-                fs::metadata(&local.path).unwrap(),
-                fs::metadata(&remote.path).unwrap(),
-            );
-            let (llen, rlen) = (lmeta.len(), rmeta.len());
-            let (lmod, rmod) = (lmeta.modified().unwrap(), rmeta.modified().unwrap());
+/// Compare a local file with its copy on the DMS, without changing either.
+///
+/// Where the sizes match but the modification times don't, the local file is compared with what the
+/// manifest says was synced, so that the copy on the DMS only has to be read back when the manifest
+/// doesn't know the file.
+pub fn compare_files(
+    local: &LibraryFile,
+    remote: &LibraryFile,
+    manifest: &Manifest,
+    times: &TimeComparison,
+) -> Result<Comparison, SyncFailure> {
+    let failed = |e: io::Error| SyncFailure::new(&local.path, Operation::Compare, e);
+
+    let (lmeta, rmeta) = (
+        fs::metadata(&local.path).map_err(failed)?,
+        fs::metadata(&remote.path).map_err(failed)?,
+    );
+    let (llen, rlen) = (lmeta.len(), rmeta.len());
+    let (lmod, rmod) = (
+        lmeta.modified().map_err(failed)?,
+        rmeta.modified().map_err(failed)?,
+    );
+
+    if llen != rlen {
+        // if the size doesn't match, always taint
+        debug!("{}: changed - size not equal", local.debase());
+        return Ok(Comparison::Changed(ChangeReason::Size {
+            local: llen,
+            dms: rlen,
+        }));
+    }
+
+    if times.matches(lmod, rmod) {
+        // if the size matches and the modified times match, allowing for FAT's resolution and
+        // timezone
+        return Ok(Comparison::Unchanged);
+    }
+
+    // now we have a situation where the size is equal but the modified time is off
+    let synced = manifest.get(&local.debase()).filter(|e| e.size == rlen);
+
+    if synced.is_some_and(|e| e.is_current(&local.path)) {
+        // the local file hasn't changed since it was synced, so neither has the copy
+        debug!("{}: unchanged - matches manifest", local.debase());
+        return Ok(Comparison::Touched);
+    }
 
-            if llen != rlen {
-                // if the size doesn't match, always taint
-                debug!("{}: changed - size not equal", local.debase());
-                return true;
+    // to correct this issue, we hash the source and either the manifest's checksum or the
+    // destination
+    let source = sha256sum(&local.path).map_err(failed)?.to_string();
+    let destination = match synced {
+        Some(entry) => entry.sha256.clone(),
+        None => sha256sum(&remote.path).map_err(failed)?.to_string(),
+    };
+
+    if source == destination {
+        debug!("{}: unchanged - checksums match", local.debase());
+        Ok(Comparison::Touched)
+    } else {
+        // checksums differed, so we must mark dirty
+        debug!("{}: changed - checksums differ", local.debase());
+        Ok(Comparison::Changed(ChangeReason::Checksum))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::playlist::DMS_PLAYLISTS_DIR;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write(base: &Path, path: &str, contents: &[u8]) {
+        let path = base.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_synchronize_to_target() {
+        let (first, second) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let target = tempfile::tempdir().unwrap();
+
+        write(first.path(), "A/01.mp3", b"first");
+        write(second.path(), "B/01.mp3", b"second");
+        write(second.path(), "mix.m3u", b"B/01.mp3\n");
+        write(target.path(), "C/01.mp3", b"orphan");
+
+        let options = SyncOptions::new()
+            .source(first.path())
+            .source(second.path())
+            .target(target.path());
+
+        let planned = plan(&options).unwrap();
+        assert_eq!(2, planned.copies.len());
+        assert_eq!(1, planned.deletes.len());
+
+        let report = synchronize_media_files(&options).unwrap();
+        assert!(report.is_ok());
+        assert_eq!((2, 1), (report.copied, report.deleted));
+
+        assert_eq!(
+            b"first",
+            &fs::read(target.path().join("A/01.mp3")).unwrap()[..]
+        );
+        assert_eq!(
+            b"second",
+            &fs::read(target.path().join("B/01.mp3")).unwrap()[..]
+        );
+        assert!(!target.path().join("C/01.mp3").exists());
+        assert_eq!(
+            "#EXTM3U\n/dos/data/B/01.mp3\n",
+            fs::read_to_string(target.path().join(DMS_PLAYLISTS_DIR).join("mix.m3u")).unwrap()
+        );
+
+        assert!(plan(&options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_synchronize_smart_playlists() {
+        let (source, target) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let config_dir = tempfile::tempdir().unwrap();
+
+        for file in &["artist.flac", "composer.flac"] {
+            let contents = fs::read(Path::new("test/fixtures/flac").join(file)).unwrap();
+            write(source.path(), &format!("flac/{}", file), &contents);
+        }
+
+        let (config, cache) = (
+            config_dir.path().join("smart-playlists.toml"),
+            config_dir.path().join("metadata.json"),
+        );
+        fs::write(
+            &config,
+            "[[playlist]]\nname = \"Composed\"\n[[playlist.rule]]\nfield = \"artist\"\nis = \"composer\"\n",
+        )
+        .unwrap();
+
+        let options = SyncOptions::new()
+            .source(source.path())
+            .target(target.path())
+            .smart_playlists(&config)
+            .metadata_cache(&cache);
+        let report = synchronize_media_files(&options).unwrap();
+
+        assert!(report.is_ok());
+        assert_eq!(
+            "#EXTM3U\n/dos/data/flac/composer.flac\n",
+            fs::read_to_string(target.path().join(DMS_PLAYLISTS_DIR).join("Composed.m3u")).unwrap()
+        );
+        assert!(cache.exists());
+
+        // an invalid configuration fails the sync of the playlists
+        fs::write(&config, "[[playlist]]\nname = \"Composed\"\n").unwrap();
+        let report = synchronize_media_files(&options).unwrap();
+
+        assert_eq!(
+            vec![config],
+            report
+                .failures
+                .iter()
+                .map(|f| f.path.clone())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_synchronize_error_policy() {
+        let (source, target) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+
+        write(source.path(), "A/01.mp3", b"first");
+        write(source.path(), "B/01.mp3", b"second");
+        // a file in the way of a directory makes copying into it fail
+        write(target.path(), "A", b"");
+
+        let options = SyncOptions::new()
+            .source(source.path())
+            .target(target.path());
+
+        // fail fast stops at the first file
+        match synchronize_media_files(&options.clone().error_policy(ErrorPolicy::FailFast)) {
+            Err(SyncError::Failed { failure }) => {
+                assert_eq!(source.path().join("A/01.mp3"), failure.path);
+                assert_eq!(Operation::Copy, failure.operation);
             }
+            result => panic!("expected a failure, got {:?}", result),
+        }
+        assert!(!target.path().join("B/01.mp3").exists());
+
+        // best effort carries on and reports the failure
+        let report = synchronize_media_files(&options).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(1, report.copied);
+        assert_eq!(
+            vec![source.path().join("A/01.mp3")],
+            report
+                .failures
+                .iter()
+                .map(|f| f.path.clone())
+                .collect::<Vec<_>>()
+        );
+        assert!(target.path().join("B/01.mp3").exists());
+    }
+
+    #[test]
+    fn test_copies_replace_partial_files() {
+        let (source, target) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+
+        write(source.path(), "A/01.mp3", b"first");
+        // an interrupted sync left a partial copy behind
+        let partial = temp_path(&target.path().join("B/02.mp3"));
+        write(target.path(), "B/.02.mp3.phatnoise-partial", b"sec");
+        assert_eq!(vec![partial.clone()], temp_files(target.path()));
+
+        let options = SyncOptions::new()
+            .source(source.path())
+            .target(target.path());
+        let report = synchronize_media_files(&options).unwrap();
+
+        assert!(report.is_ok());
+        assert!(!partial.exists());
+        assert!(temp_files(target.path()).is_empty());
+
+        let (local, dms) = (
+            source.path().join("A/01.mp3"),
+            target.path().join("A/01.mp3"),
+        );
+        assert_eq!(b"first", &fs::read(&dms).unwrap()[..]);
+        assert_eq!(
+            fs::metadata(local).unwrap().modified().unwrap(),
+            fs::metadata(dms).unwrap().modified().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_synchronize_renames() {
+        let (source, target) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+
+        write(source.path(), "Artist/01.mp3", b"one");
+        write(target.path(), "Artsit/01.mp3", b"one");
+
+        let options = SyncOptions::new()
+            .source(source.path())
+            .target(target.path());
+        let report = synchronize_media_files(&options).unwrap();
+
+        assert!(report.is_ok());
+        assert_eq!((1, 0, 0), (report.renamed, report.copied, report.deleted));
+        assert_eq!(0, report.bytes_copied);
+        assert!(!target.path().join("Artsit/01.mp3").exists());
+        assert_eq!(
+            b"one",
+            &fs::read(target.path().join("Artist/01.mp3")).unwrap()[..]
+        );
+
+        assert!(plan(&options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_synchronize_manifest() {
+        let (source, target) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+
+        write(source.path(), "A/01.mp3", b"first");
+        write(source.path(), "A/02.mp3", b"second");
+
+        let options = SyncOptions::new()
+            .source(source.path())
+            .target(target.path());
+        synchronize_media_files(&options).unwrap();
+
+        let manifest = Manifest::load(target.path());
+        assert_eq!(2, manifest.len());
+        assert_eq!(
+            Some(&ManifestEntry::of(&source.path().join("A/01.mp3")).unwrap()),
+            manifest.get("A/01.mp3")
+        );
+
+        // a lost manifest is rebuilt, and deleted files are forgotten
+        fs::write(Manifest::path(target.path()), b"garbage").unwrap();
+        fs::remove_file(source.path().join("A/02.mp3")).unwrap();
+        synchronize_media_files(&options).unwrap();
+
+        let manifest = Manifest::load(target.path());
+        assert_eq!(1, manifest.len());
+        assert!(manifest.get("A/01.mp3").is_some());
+    }
+
+    #[test]
+    fn test_unreadable_source_directory() {
+        if unsafe { libc::geteuid() } == 0 {
+            // permissions don't stop root from reading the directory
+            return;
+        }
+
+        let (source, target) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        write(source.path(), "A/01.mp3", b"first");
+        write(source.path(), "B/01.mp3", b"second");
+        write(target.path(), "B/01.mp3", b"second");
+        write(target.path(), "B/02.mp3", b"third");
+
+        let locked = source.path().join("B");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+
+        let options = SyncOptions::new()
+            .source(source.path())
+            .target(target.path());
+        let report = synchronize_media_files(&options);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // the unreadable directory is reported, and nothing under it is deleted
+        let report = report.unwrap();
+        assert_eq!(
+            vec![locked],
+            report
+                .failures
+                .iter()
+                .map(|f| f.path.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Operation::Scan, report.failures[0].operation);
+        assert_eq!((1, 0), (report.copied, report.deleted));
+        assert!(target.path().join("B/02.mp3").exists());
+    }
 
-            let (first, last) = (lmod.min(rmod), lmod.max(rmod));
-            let diff = last.duration_since(first).unwrap();
+    #[test]
+    fn test_missing_target() {
+        let source = tempfile::tempdir().unwrap();
+        let options = SyncOptions::new()
+            .source(source.path())
+            .target("/nonexistent/target");
 
-            if diff.as_secs() <= 3 {
-                // if the size matches and the modified time difference is less than or equal to 3s
-                return false;
+        match plan(&options) {
+            Err(SyncError::MissingTarget { path }) => {
+                assert_eq!(PathBuf::from("/nonexistent/target"), path)
             }
+            result => panic!("expected a missing target, got {:?}", result),
+        }
+    }
 
-            // now we have a situation where the size is equal but the modified time is off
-            // to correct this issue, we hash both the source and destination. if the source and dest
-            // have the same checksum, we update the remote mtime to equal the local mtime
-            let (source, destination) = (
-                sha256sum(&local.path).expect("unable to compute checksum for local file"),
-                sha256sum(&remote.path).expect("unable to compute checksum for remote file"),
-            );
+    #[test]
+    fn test_missing_source() {
+        let (source, target) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        write(target.path(), "A/01.mp3", b"first");
+
+        // an unmounted share must not look like an empty library
+        let options = SyncOptions::new()
+            .source(source.path())
+            .source(source.path().join("nas"))
+            .target(target.path());
+
+        match synchronize_media_files(&options) {
+            Err(SyncError::MissingSource { path }) => assert_eq!(source.path().join("nas"), path),
+            result => panic!("expected a missing source, got {:?}", result),
+        }
+        assert!(target.path().join("A/01.mp3").exists());
+    }
 
-            if source == destination {
-                // the hashes map, so let's copy the modification time from local to remote to resolve
-                // future comparisons
-                debug!("{}: unchanged - checksums match", local.debase());
-                copy_mtime(&local.path, &remote.path).ok();
+    #[test]
+    fn test_overlapping_target() {
+        let source = tempfile::tempdir().unwrap();
+        fs::create_dir(source.path().join("dms")).unwrap();
 
-                // checksums matched, so we're done with this file
-                false
-            } else {
-                // checksums differed, so we must mark dirty
-                debug!("{}: changed - checksums differ", local.debase());
-                true
+        for target in &[source.path().to_path_buf(), source.path().join("dms")] {
+            let options = SyncOptions::new().source(source.path()).target(target);
+
+            match plan(&options) {
+                Err(SyncError::OverlappingTarget { .. }) => {}
+                result => panic!("expected an overlapping target, got {:?}", result),
             }
-        })
-        .collect()
+        }
+
+        // a target containing the library root
+        let options = SyncOptions::new()
+            .source(source.path().join("dms"))
+            .target(source.path());
+
+        match plan(&options) {
+            Err(SyncError::OverlappingTarget { .. }) => {}
+            result => panic!("expected an overlapping target, got {:?}", result),
+        }
+    }
 }
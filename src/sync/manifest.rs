@@ -0,0 +1,224 @@
+//! A manifest of the files synced to the DMS, stored on the DMS itself.
+//!
+//! When the modification times of a local file and its copy on the DMS drift apart, the contents
+//! have to be compared. Reading the copy back over USB is slow, so instead the local file is
+//! compared against what the manifest says was synced.
+
+use log::{info, warn};
+
+use serde::{Deserialize, Serialize};
+
+use crate::sync::temp_path;
+use crate::utils::crypto::sha256sum;
+use crate::utils::fs::sync_dir;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The name of the manifest in the root of the DMS.
+pub static MANIFEST_FILE: &str = "phatnoise-manifest.json";
+
+/// The version of the manifest format. Manifests of any other version are rebuilt.
+const MANIFEST_VERSION: u32 = 1;
+
+/// What was synced for a single file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    pub size: u64,
+    /// The modification time of the local file when it was synced, since the epoch.
+    pub modified: Duration,
+    /// The SHA-256 checksum of the contents, in hex.
+    pub sha256: String,
+}
+
+impl ManifestEntry {
+    /// Describe the local file at the given path, checksumming it.
+    pub fn of(path: &Path) -> io::Result<ManifestEntry> {
+        let metadata = fs::metadata(path)?;
+
+        Ok(ManifestEntry {
+            size: metadata.len(),
+            modified: modified(&metadata)?,
+            sha256: sha256sum(path)?.to_string(),
+        })
+    }
+
+    /// Check whether the local file at the given path still has the size and modification time it
+    /// had when it was synced, in which case its contents are assumed to be unchanged too.
+    pub fn is_current(&self, path: &Path) -> bool {
+        match fs::metadata(path) {
+            Ok(metadata) => {
+                metadata.len() == self.size && modified(&metadata).ok() == Some(self.modified)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// The files synced to the DMS, by their path relative to the library root.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Manifest {
+    version: u32,
+    files: BTreeMap<String, ManifestEntry>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn path(dms_dir: &Path) -> PathBuf {
+        dms_dir.join(MANIFEST_FILE)
+    }
+
+    /// Load the manifest from the DMS mounted at the given directory.
+    ///
+    /// A missing, unreadable or corrupt manifest gives an empty one, which is rebuilt as files are
+    /// synced.
+    pub fn load(dms_dir: &Path) -> Manifest {
+        let path = Manifest::path(dms_dir);
+
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No sync manifest on the DMS, it will be rebuilt.");
+                return Manifest::default();
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to read sync manifest {}, it will be rebuilt: {}",
+                    path.display(),
+                    e
+                );
+                return Manifest::default();
+            }
+        };
+
+        match serde_json::from_slice::<Manifest>(&bytes) {
+            Ok(ref manifest) if manifest.version != MANIFEST_VERSION => {
+                warn!(
+                    "Sync manifest {} has unsupported version {}, it will be rebuilt.",
+                    path.display(),
+                    manifest.version
+                );
+                Manifest::default()
+            }
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!(
+                    "Sync manifest {} is corrupt, it will be rebuilt: {}",
+                    path.display(),
+                    e
+                );
+                Manifest::default()
+            }
+        }
+    }
+
+    /// Write the manifest to the DMS mounted at the given directory, replacing the old one only
+    /// once the new one is safely written.
+    pub fn save(&self, dms_dir: &Path) -> io::Result<()> {
+        let path = Manifest::path(dms_dir);
+        let temp = temp_path(&path);
+
+        let result = File::create(&temp).and_then(|mut f| {
+            serde_json::to_writer(&mut f, self)?;
+            f.write_all(b"\n")?;
+            f.sync_all()
+        });
+
+        if let Err(e) = result.and_then(|_| fs::rename(&temp, &path)) {
+            fs::remove_file(&temp).ok();
+            return Err(e);
+        }
+
+        sync_dir(dms_dir)
+    }
+
+    pub fn get(&self, path: &str) -> Option<&ManifestEntry> {
+        self.files.get(path)
+    }
+
+    pub fn insert(&mut self, path: &str, entry: ManifestEntry) {
+        self.files.insert(path.to_string(), entry);
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.files.remove(path);
+    }
+
+    /// Forget every file for which the predicate is false.
+    pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut keep: F) {
+        self.files.retain(|path, _| keep(path));
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+fn modified(metadata: &fs::Metadata) -> io::Result<Duration> {
+    // times before the epoch can't be represented on the DMS anyway
+    Ok(metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let (local, dms) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let track = local.path().join("01.mp3");
+        fs::write(&track, b"track").unwrap();
+
+        let entry = ManifestEntry::of(&track).unwrap();
+        assert_eq!(5, entry.size);
+        assert!(entry.is_current(&track));
+
+        let mut manifest = Manifest::default();
+        manifest.insert("A/01.mp3", entry.clone());
+        manifest.save(dms.path()).unwrap();
+
+        let loaded = Manifest::load(dms.path());
+        assert_eq!(Some(&entry), loaded.get("A/01.mp3"));
+        assert_eq!(manifest, loaded);
+
+        // a changed file is no longer current
+        fs::write(&track, b"longer").unwrap();
+        assert!(!entry.is_current(&track));
+    }
+
+    #[test]
+    fn test_missing_or_corrupt() {
+        let dms = tempfile::tempdir().unwrap();
+        assert!(Manifest::load(dms.path()).is_empty());
+
+        fs::write(Manifest::path(dms.path()), b"{\"version\": 1, \"files\": {").unwrap();
+        assert!(Manifest::load(dms.path()).is_empty());
+
+        fs::write(
+            Manifest::path(dms.path()),
+            b"{\"version\": 99, \"files\": {}}",
+        )
+        .unwrap();
+        assert!(Manifest::load(dms.path()).is_empty());
+    }
+}
@@ -0,0 +1,849 @@
+//! A plan of everything a sync will do to the DMS, computed without touching it, so that a sync
+//! can be previewed before it runs.
+
+use log::debug;
+
+use rayon::prelude::*;
+
+use serde::Serialize;
+
+use crate::library::LibraryFile;
+use crate::sync::manifest::Manifest;
+use crate::sync::report::SyncFailure;
+use crate::sync::timestamps::TimeComparison;
+use crate::sync::{added_files, compare_files, deleted_files, Comparison};
+use crate::utils::crypto::{sampled_sha256sum, sha256sum, Sha256Digest, SAMPLE_SIZE};
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Why a file present on both sides counts as changed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChangeReason {
+    /// The sizes differ, so the contents must too.
+    Size { local: u64, dms: u64 },
+    /// The sizes match but the modification times don't, and the checksums differ.
+    Checksum,
+}
+
+impl fmt::Display for ChangeReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChangeReason::Size { local, dms } => {
+                write!(f, "size {} on DMS, {} locally", dms, local)
+            }
+            ChangeReason::Checksum => write!(f, "checksums differ"),
+        }
+    }
+}
+
+/// A single local file the sync will copy to the DMS, or whose modification time it will copy.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlannedCopy {
+    /// The path of the file relative to the library root.
+    pub path: String,
+    /// The local file to copy from.
+    pub source: PathBuf,
+    /// The file on the DMS which will be written.
+    pub dest: PathBuf,
+    /// The size of the file being copied.
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<ChangeReason>,
+}
+
+/// A single orphaned file the sync will remove from the DMS.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlannedDelete {
+    /// The path of the file relative to the library root.
+    pub path: String,
+    /// The file on the DMS which will be removed.
+    pub dest: PathBuf,
+    /// The size of the file being removed.
+    pub bytes: u64,
+}
+
+/// A file on the DMS which will be moved to where a new local file with the same contents belongs,
+/// rather than being deleted while the new file is copied.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlannedRename {
+    /// The new path of the file relative to the library root.
+    pub path: String,
+    /// The old path of the file relative to the library root.
+    pub previous: String,
+    /// The local file with the same contents.
+    pub source: PathBuf,
+    /// The file on the DMS which will be moved.
+    pub from: PathBuf,
+    /// Where the file on the DMS will be moved to.
+    pub dest: PathBuf,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct PlanTotals {
+    pub copy_bytes: u64,
+    /// The bytes which renames save copying.
+    pub rename_bytes: u64,
+    pub update_bytes: u64,
+    pub delete_bytes: u64,
+}
+
+/// Everything a sync will do to the DMS.
+#[derive(Debug, Default, Serialize)]
+pub struct SyncPlan {
+    /// New files to copy to the DMS.
+    pub copies: Vec<PlannedCopy>,
+    /// Changed files to copy over their outdated copies on the DMS.
+    pub updates: Vec<PlannedCopy>,
+    /// Orphaned files to delete from the DMS.
+    pub deletes: Vec<PlannedDelete>,
+    /// Orphaned files on the DMS to move to where new files with the same contents belong.
+    pub renames: Vec<PlannedRename>,
+    /// Files on the DMS whose contents match but whose modification time will be corrected so
+    /// that they don't need to be checksummed again.
+    pub touches: Vec<PlannedCopy>,
+    pub totals: PlanTotals,
+    /// The whole number of hours, in seconds, by which modification times on the DMS are off and
+    /// which is ignored when comparing them.
+    pub mtime_offset: i64,
+    /// Files and directories which couldn't be read or compared, and so are left alone.
+    pub failures: Vec<SyncFailure>,
+}
+
+impl SyncPlan {
+    /// Compare the local library with the library on the DMS mounted at the given directory.
+    ///
+    /// Only reads are made: changed files are found by size and, where sizes match but
+    /// modification times don't, by checksum. New files with the same size and checksum as an
+    /// orphaned file on the DMS are planned as renames of the orphaned file.
+    pub fn compute(
+        local: &BTreeSet<LibraryFile>,
+        dms: &BTreeSet<LibraryFile>,
+        dms_dir: &Path,
+        manifest: &Manifest,
+        tolerance: Duration,
+    ) -> SyncPlan {
+        let (added, deleted) = (added_files(local, dms), deleted_files(local, dms));
+        let moved = find_renames(&added, &deleted, manifest);
+        let renamed: HashSet<&str> = moved.values().map(|from| &*from.id).collect();
+
+        let copies: Vec<PlannedCopy> = added
+            .iter()
+            .filter(|file| !moved.contains_key(&*file.id))
+            .map(|file| planned_copy(file, dms_dir, None))
+            .collect();
+
+        let mut renames: Vec<PlannedRename> = added
+            .iter()
+            .filter_map(|file| moved.get(&*file.id).map(|from| (file, from)))
+            .map(|(file, from)| PlannedRename {
+                path: file.debase().into_owned(),
+                previous: from.debase().into_owned(),
+                source: file.path.clone(),
+                from: from.path.clone(),
+                dest: dms_dir.join(file.relative_path()),
+                bytes: file_size(&file.path),
+            })
+            .collect();
+        renames.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let times = time_comparison(local, dms, tolerance);
+
+        let comparisons: Vec<(&LibraryFile, Result<Comparison, SyncFailure>)> = local
+            .par_iter()
+            .filter_map(|file| {
+                dms.get(file)
+                    .map(|remote| (file, compare_files(file, remote, manifest, &times)))
+            })
+            .collect();
+
+        let mut updates = Vec::new();
+        let mut touches = Vec::new();
+        let mut failures = Vec::new();
+
+        for (file, comparison) in comparisons {
+            match comparison {
+                Ok(Comparison::Unchanged) => {}
+                Ok(Comparison::Touched) => touches.push(planned_copy(file, dms_dir, None)),
+                Ok(Comparison::Changed(reason)) => {
+                    updates.push(planned_copy(file, dms_dir, Some(reason)))
+                }
+                Err(failure) => failures.push(failure),
+            }
+        }
+
+        let deletes: Vec<PlannedDelete> = deleted
+            .into_iter()
+            .filter(|file| !renamed.contains(&*file.id))
+            .map(|file| PlannedDelete {
+                path: file.debase().into_owned(),
+                dest: file.path.clone(),
+                bytes: file_size(&file.path),
+            })
+            .collect();
+
+        let total = |files: &[PlannedCopy]| files.iter().map(|f| f.bytes).sum();
+
+        SyncPlan {
+            totals: PlanTotals {
+                copy_bytes: total(&copies),
+                rename_bytes: renames.iter().map(|f| f.bytes).sum(),
+                update_bytes: total(&updates),
+                delete_bytes: deletes.iter().map(|f| f.bytes).sum(),
+            },
+            copies,
+            updates,
+            deletes,
+            renames,
+            touches,
+            failures,
+            mtime_offset: times.offset,
+        }
+    }
+
+    /// Check whether the sync has nothing to do.
+    pub fn is_empty(&self) -> bool {
+        self.copies.is_empty()
+            && self.updates.is_empty()
+            && self.deletes.is_empty()
+            && self.renames.is_empty()
+            && self.touches.is_empty()
+    }
+}
+
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Copy {} new files ({}):",
+            self.copies.len(),
+            format_bytes(self.totals.copy_bytes)
+        )?;
+
+        for file in &self.copies {
+            writeln!(f, "  + {} ({})", file.path, format_bytes(file.bytes))?;
+        }
+
+        writeln!(
+            f,
+            "Update {} changed files ({}):",
+            self.updates.len(),
+            format_bytes(self.totals.update_bytes)
+        )?;
+
+        for file in &self.updates {
+            match file.reason {
+                Some(ref reason) => writeln!(f, "  ~ {} ({})", file.path, reason)?,
+                None => writeln!(f, "  ~ {}", file.path)?,
+            }
+        }
+
+        writeln!(
+            f,
+            "Delete {} orphaned files ({}):",
+            self.deletes.len(),
+            format_bytes(self.totals.delete_bytes)
+        )?;
+
+        for file in &self.deletes {
+            writeln!(f, "  - {} ({})", file.path, format_bytes(file.bytes))?;
+        }
+
+        writeln!(
+            f,
+            "Rename {} moved files ({} not copied):",
+            self.renames.len(),
+            format_bytes(self.totals.rename_bytes)
+        )?;
+
+        for file in &self.renames {
+            writeln!(f, "  > {} -> {}", file.previous, file.path)?;
+        }
+
+        write!(
+            f,
+            "Correct the modification time of {} unchanged files.",
+            self.touches.len()
+        )?;
+
+        if self.mtime_offset != 0 {
+            write!(
+                f,
+                "\nIgnore modification times on the DMS being {} hours off.",
+                self.mtime_offset / 3600
+            )?;
+        }
+
+        if !self.failures.is_empty() {
+            write!(
+                f,
+                "\nSkip {} files which couldn't be read or compared:",
+                self.failures.len()
+            )?;
+
+            for failure in &self.failures {
+                write!(f, "\n  ! {}", failure)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn planned_copy(file: &LibraryFile, dms_dir: &Path, reason: Option<ChangeReason>) -> PlannedCopy {
+    PlannedCopy {
+        path: file.debase().into_owned(),
+        source: file.path.clone(),
+        dest: dms_dir.join(file.relative_path()),
+        bytes: file_size(&file.path),
+        reason,
+    }
+}
+
+/// Detect an offset between the modification times of the files present on both sides with the
+/// same size, such as after a daylight saving change.
+fn time_comparison(
+    local: &BTreeSet<LibraryFile>,
+    dms: &BTreeSet<LibraryFile>,
+    tolerance: Duration,
+) -> TimeComparison {
+    let times: Vec<(SystemTime, SystemTime)> = local
+        .par_iter()
+        .filter_map(|file| dms.get(file).map(|remote| (file, remote)))
+        .filter_map(|(file, remote)| {
+            let (lmeta, rmeta) = (
+                fs::metadata(&file.path).ok()?,
+                fs::metadata(&remote.path).ok()?,
+            );
+
+            if lmeta.len() != rmeta.len() {
+                return None;
+            }
+
+            Some((lmeta.modified().ok()?, rmeta.modified().ok()?))
+        })
+        .collect();
+
+    TimeComparison::detect(tolerance, times)
+}
+
+/// How the contents of a file are identified when matching new files against orphans.
+#[derive(Eq, Hash, PartialEq)]
+enum Fingerprint {
+    /// The checksum of the whole file, in hex, as recorded in the manifest.
+    Full(String),
+    /// The checksum of the start and end of the file.
+    Sampled(Sha256Digest),
+}
+
+/// Match new local files against orphaned files on the DMS by size and checksum, returning the
+/// orphaned file to rename for each new file by ID.
+///
+/// Orphans are identified by their checksum in the manifest where it has one for them, and are
+/// only read when it doesn't, as reading them back over USB is slow. Even then only the start and
+/// end of large files are read at first, and an orphan is only read in full once its samples
+/// match a new file's. Only files whose size matches a file on the other side are checksummed,
+/// and files which can't be read are simply copied and deleted.
+fn find_renames<'a>(
+    added: &[&'a LibraryFile],
+    deleted: &[&'a LibraryFile],
+    manifest: &Manifest,
+) -> HashMap<&'a str, &'a LibraryFile> {
+    let sized = |files: &[&'a LibraryFile]| -> Vec<(&'a LibraryFile, u64)> {
+        files
+            .iter()
+            .filter_map(|&f| match fs::metadata(&f.path) {
+                Ok(metadata) => Some((f, metadata.len())),
+                Err(e) => {
+                    debug!("{}: unable to stat for rename detection: {}", f.debase(), e);
+                    None
+                }
+            })
+            .collect()
+    };
+    let (added, deleted) = (sized(added), sized(deleted));
+    let added_sizes: HashSet<u64> = added.iter().map(|&(_, size)| size).collect();
+
+    let unreadable = |file: &LibraryFile, e: io::Error| {
+        debug!(
+            "{}: unable to checksum for rename detection: {}",
+            file.debase(),
+            e
+        );
+    };
+
+    let fingerprints: Vec<(&'a LibraryFile, (u64, Fingerprint))> = deleted
+        .par_iter()
+        .filter(|(_, size)| added_sizes.contains(size))
+        .filter_map(|&(file, size)| {
+            let fingerprint = match manifest.get(&file.debase()).filter(|e| e.size == size) {
+                Some(entry) => Fingerprint::Full(entry.sha256.clone()),
+                None => match sampled_sha256sum(&file.path, size) {
+                    Ok(digest) => Fingerprint::Sampled(digest),
+                    Err(e) => {
+                        unreadable(file, e);
+                        return None;
+                    }
+                },
+            };
+
+            Some((file, (size, fingerprint)))
+        })
+        .collect();
+
+    let (mut full_sizes, mut sampled_sizes) = (HashSet::new(), HashSet::new());
+
+    for (_, (size, fingerprint)) in &fingerprints {
+        match fingerprint {
+            Fingerprint::Full(_) => full_sizes.insert(*size),
+            Fingerprint::Sampled(_) => sampled_sizes.insert(*size),
+        };
+    }
+
+    // orphans are matched in order, so that duplicate contents are paired deterministically
+    let mut orphans: HashMap<(u64, Fingerprint), Vec<&'a LibraryFile>> = HashMap::new();
+
+    for (file, key) in fingerprints.into_iter().rev() {
+        orphans.entry(key).or_default().push(file);
+    }
+
+    // new files are local, so they're checksummed whichever way the orphans of their size were
+    let candidates: Vec<(&'a LibraryFile, Vec<(u64, Fingerprint)>)> = added
+        .par_iter()
+        .filter(|(_, size)| full_sizes.contains(size) || sampled_sizes.contains(size))
+        .map(|&(file, size)| {
+            let mut keys = Vec::new();
+
+            if full_sizes.contains(&size) {
+                match sha256sum(&file.path) {
+                    Ok(digest) => keys.push((size, Fingerprint::Full(digest.to_string()))),
+                    Err(e) => unreadable(file, e),
+                }
+            }
+
+            if sampled_sizes.contains(&size) {
+                match sampled_sha256sum(&file.path, size) {
+                    Ok(digest) => keys.push((size, Fingerprint::Sampled(digest))),
+                    Err(e) => unreadable(file, e),
+                }
+            }
+
+            (file, keys)
+        })
+        .collect();
+
+    let checksum = |file: &LibraryFile| {
+        sha256sum(&file.path)
+            .map(|digest| digest.to_string())
+            .map_err(|e| unreadable(file, e))
+            .ok()
+    };
+
+    // the full checksums of orphans which were shortlisted by their samples, read at most once
+    let mut confirmed: HashMap<&Path, Option<String>> = HashMap::new();
+    let mut renames = HashMap::new();
+
+    for (file, keys) in candidates {
+        let mut local: Option<Option<String>> = None;
+        let mut from = None;
+
+        for key in &keys {
+            let files = match orphans.get_mut(key) {
+                Some(files) => files,
+                None => continue,
+            };
+
+            let index = match key.1 {
+                Fingerprint::Full(_) => files.len().checked_sub(1),
+                // small files are sampled whole, so their samples are their full checksums
+                Fingerprint::Sampled(_) if key.0 <= 2 * SAMPLE_SIZE => files.len().checked_sub(1),
+                // otherwise the samples only shortlist orphans, which have to match in full
+                Fingerprint::Sampled(_) => {
+                    let local = local.get_or_insert_with(|| checksum(file));
+
+                    files.iter().rposition(|orphan| {
+                        local.is_some()
+                            && *confirmed
+                                .entry(&orphan.path)
+                                .or_insert_with(|| checksum(orphan))
+                                == *local
+                    })
+                }
+            };
+
+            if let Some(index) = index {
+                from = Some(files.remove(index));
+                break;
+            }
+        }
+
+        if let Some(from) = from {
+            debug!("{}: moved from {}", file.debase(), from.debase());
+            renames.insert(&*file.id, from);
+        }
+    }
+
+    renames
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Format a byte count for people, in binary units.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::library::{get_local_media_library, LibrarySource};
+    use crate::sync::manifest::ManifestEntry;
+    use crate::sync::timestamps::DEFAULT_TOLERANCE;
+    use crate::utils::fs::copy_mtime;
+
+    use std::time::{Duration, SystemTime};
+
+    fn write(base: &Path, path: &str, contents: &[u8]) {
+        let path = base.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn dms_library(base: &Path) -> BTreeSet<LibraryFile> {
+        crate::utils::media::get_media_library(base)
+            .iter()
+            .map(|p| LibraryFile::new(p, base, LibrarySource::DMS))
+            .collect()
+    }
+
+    /// Set the modification time of a file an hour and a half into the past, so that it isn't
+    /// mistaken for a timezone offset.
+    fn age(path: &Path) {
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(5400))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_compute() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let dms_dir = tempfile::tempdir().unwrap();
+        let (local_path, dms_path) = (local_dir.path(), dms_dir.path());
+
+        write(local_path, "A/new.mp3", b"new");
+        write(local_path, "A/same.mp3", b"same");
+        write(dms_path, "A/same.mp3", b"same");
+        copy_mtime(&local_path.join("A/same.mp3"), &dms_path.join("A/same.mp3")).unwrap();
+        write(local_path, "A/longer.mp3", b"longer");
+        write(dms_path, "A/longer.mp3", b"long");
+        write(local_path, "A/edited.mp3", b"edit");
+        write(dms_path, "A/edited.mp3", b"EDIT");
+        age(&dms_path.join("A/edited.mp3"));
+        write(local_path, "A/touched.mp3", b"touch");
+        write(dms_path, "A/touched.mp3", b"touch");
+        age(&dms_path.join("A/touched.mp3"));
+        write(dms_path, "B/orphan.mp3", b"orphan");
+
+        let (local, dms) = (get_local_media_library(local_path), dms_library(dms_path));
+        let plan = SyncPlan::compute(
+            &local,
+            &dms,
+            dms_path,
+            &Manifest::default(),
+            DEFAULT_TOLERANCE,
+        );
+
+        assert_eq!(
+            vec![PlannedCopy {
+                path: "A/new.mp3".to_string(),
+                source: local_path.join("A/new.mp3"),
+                dest: dms_path.join("A/new.mp3"),
+                bytes: 3,
+                reason: None,
+            }],
+            plan.copies
+        );
+        assert_eq!(
+            vec![
+                ("A/edited.mp3", Some(ChangeReason::Checksum)),
+                (
+                    "A/longer.mp3",
+                    Some(ChangeReason::Size { local: 6, dms: 4 })
+                ),
+            ],
+            plan.updates
+                .iter()
+                .map(|f| (f.path.as_str(), f.reason.clone()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["B/orphan.mp3"],
+            plan.deletes.iter().map(|f| &f.path).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["A/touched.mp3"],
+            plan.touches.iter().map(|f| &f.path).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            PlanTotals {
+                copy_bytes: 3,
+                rename_bytes: 0,
+                update_bytes: 10,
+                delete_bytes: 6,
+            },
+            plan.totals
+        );
+
+        // computing the plan must not have touched the DMS
+        let dms_modified = fs::metadata(dms_path.join("A/touched.mp3"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(dms_modified < SystemTime::now() - Duration::from_secs(3000));
+        assert!(!dms_path.join("A/new.mp3").exists());
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(json.contains(r#""reason":{"kind":"size","local":6,"dms":4}"#));
+
+        let text = plan.to_string();
+        assert!(text.starts_with("Copy 1 new files (3 B):\n  + A/new.mp3 (3 B)\n"));
+        assert!(text.contains("  ~ A/edited.mp3 (checksums differ)\n"));
+    }
+
+    #[test]
+    fn test_compute_renames() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let dms_dir = tempfile::tempdir().unwrap();
+        let (local_path, dms_path) = (local_dir.path(), dms_dir.path());
+
+        // a fixed artist folder name, and a track re-encoded while it was moved
+        write(local_path, "Artist/01.mp3", b"one");
+        write(dms_path, "Artsit/01.mp3", b"one");
+        write(local_path, "Artist/02.mp3", b"two");
+        write(dms_path, "Artsit/02.mp3", b"TWO");
+        // duplicate contents are each matched once
+        write(local_path, "Silence/a.mp3", b"....");
+        write(local_path, "Silence/b.mp3", b"....");
+        write(dms_path, "Old/a.mp3", b"....");
+
+        let (local, dms) = (get_local_media_library(local_path), dms_library(dms_path));
+        let plan = SyncPlan::compute(
+            &local,
+            &dms,
+            dms_path,
+            &Manifest::default(),
+            DEFAULT_TOLERANCE,
+        );
+
+        assert_eq!(
+            vec![
+                PlannedRename {
+                    path: "Artist/01.mp3".to_string(),
+                    previous: "Artsit/01.mp3".to_string(),
+                    source: local_path.join("Artist/01.mp3"),
+                    from: dms_path.join("Artsit/01.mp3"),
+                    dest: dms_path.join("Artist/01.mp3"),
+                    bytes: 3,
+                },
+                PlannedRename {
+                    path: "Silence/a.mp3".to_string(),
+                    previous: "Old/a.mp3".to_string(),
+                    source: local_path.join("Silence/a.mp3"),
+                    from: dms_path.join("Old/a.mp3"),
+                    dest: dms_path.join("Silence/a.mp3"),
+                    bytes: 4,
+                },
+            ],
+            plan.renames
+        );
+        assert_eq!(
+            vec!["Artist/02.mp3", "Silence/b.mp3"],
+            plan.copies.iter().map(|f| &f.path).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["Artsit/02.mp3"],
+            plan.deletes.iter().map(|f| &f.path).collect::<Vec<_>>()
+        );
+        assert_eq!(7, plan.totals.rename_bytes);
+        assert_eq!(7, plan.totals.copy_bytes);
+        assert!(plan
+            .to_string()
+            .contains("  > Artsit/01.mp3 -> Artist/01.mp3\n"));
+    }
+
+    #[test]
+    fn test_compute_renames_confirms_samples() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let dms_dir = tempfile::tempdir().unwrap();
+        let (local_path, dms_path) = (local_dir.path(), dms_dir.path());
+
+        // large enough to be sampled, and differing only between the samples
+        let contents = vec![0; 3 * SAMPLE_SIZE as usize];
+        let mut edited = contents.clone();
+        edited[contents.len() / 2] = 1;
+
+        write(local_path, "Artist/01.mp3", &contents);
+        write(dms_path, "Artsit/01.mp3", &edited);
+
+        let (local, dms) = (get_local_media_library(local_path), dms_library(dms_path));
+        let plan = SyncPlan::compute(
+            &local,
+            &dms,
+            dms_path,
+            &Manifest::default(),
+            DEFAULT_TOLERANCE,
+        );
+
+        // the samples match, but the full checksums don't
+        assert!(plan.renames.is_empty());
+        assert_eq!(
+            vec!["Artist/01.mp3"],
+            plan.copies.iter().map(|f| &f.path).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["Artsit/01.mp3"],
+            plan.deletes.iter().map(|f| &f.path).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_compute_renames_with_manifest() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let dms_dir = tempfile::tempdir().unwrap();
+        let (local_path, dms_path) = (local_dir.path(), dms_dir.path());
+
+        write(local_path, "Artist/01.mp3", b"one");
+        write(local_path, "Artist/02.mp3", b"two");
+        write(dms_path, "Artsit/02.mp3", b"two");
+
+        // the orphan differs from the manifest, so matching it means it wasn't read
+        let mut manifest = Manifest::default();
+        let entry = ManifestEntry::of(&local_path.join("Artist/01.mp3")).unwrap();
+        manifest.insert("Artsit/01.mp3", entry);
+        write(dms_path, "Artsit/01.mp3", b"ONE");
+
+        let (local, dms) = (get_local_media_library(local_path), dms_library(dms_path));
+        let plan = SyncPlan::compute(&local, &dms, dms_path, &manifest, DEFAULT_TOLERANCE);
+
+        // orphans without an entry are still read
+        assert_eq!(
+            vec![
+                ("Artsit/01.mp3", "Artist/01.mp3"),
+                ("Artsit/02.mp3", "Artist/02.mp3")
+            ],
+            plan.renames
+                .iter()
+                .map(|r| (r.previous.as_str(), r.path.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert!(plan.copies.is_empty());
+        assert!(plan.deletes.is_empty());
+    }
+
+    #[test]
+    fn test_compute_with_manifest() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let dms_dir = tempfile::tempdir().unwrap();
+        let (local_path, dms_path) = (local_dir.path(), dms_dir.path());
+
+        // the copies on the DMS differ, so only the manifest can say they are unchanged
+        write(local_path, "A/synced.mp3", b"synced");
+        write(dms_path, "A/synced.mp3", b"SYNCED");
+        age(&dms_path.join("A/synced.mp3"));
+        write(local_path, "A/edited.mp3", b"edited");
+        write(dms_path, "A/edited.mp3", b"edited");
+        age(&dms_path.join("A/edited.mp3"));
+
+        let mut manifest = Manifest::default();
+
+        for path in &["A/synced.mp3", "A/edited.mp3"] {
+            let entry = ManifestEntry::of(&local_path.join(path)).unwrap();
+            manifest.insert(path, entry);
+        }
+
+        // edited locally since it was synced, without changing its size
+        write(local_path, "A/edited.mp3", b"EDITED");
+
+        let (local, dms) = (get_local_media_library(local_path), dms_library(dms_path));
+        let plan = SyncPlan::compute(&local, &dms, dms_path, &manifest, DEFAULT_TOLERANCE);
+
+        assert_eq!(
+            vec!["A/synced.mp3"],
+            plan.touches.iter().map(|f| &f.path).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![("A/edited.mp3", Some(ChangeReason::Checksum))],
+            plan.updates
+                .iter()
+                .map(|f| (f.path.as_str(), f.reason.clone()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_compute_timezone_offset() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let dms_dir = tempfile::tempdir().unwrap();
+        let (local_path, dms_path) = (local_dir.path(), dms_dir.path());
+
+        // every file on the DMS is an hour behind after a daylight saving change
+        for path in &["A/01.mp3", "A/02.mp3", "A/03.mp3"] {
+            write(local_path, path, path.as_bytes());
+            write(dms_path, path, path.as_bytes());
+
+            let modified = fs::metadata(local_path.join(path))
+                .unwrap()
+                .modified()
+                .unwrap();
+            let file = fs::File::options()
+                .write(true)
+                .open(dms_path.join(path))
+                .unwrap();
+            file.set_modified(modified - Duration::from_secs(3600))
+                .unwrap();
+        }
+
+        let (local, dms) = (get_local_media_library(local_path), dms_library(dms_path));
+        let plan = SyncPlan::compute(
+            &local,
+            &dms,
+            dms_path,
+            &Manifest::default(),
+            DEFAULT_TOLERANCE,
+        );
+
+        assert!(plan.is_empty());
+        assert_eq!(-3600, plan.mtime_offset);
+        assert!(plan.to_string().ends_with("being -1 hours off."));
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!("512 B", format_bytes(512));
+        assert_eq!("1.5 KiB", format_bytes(1536));
+        assert_eq!("3.0 GiB", format_bytes(3 * 1024 * 1024 * 1024));
+    }
+}
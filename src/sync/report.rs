@@ -0,0 +1,93 @@
+//! The outcome of a sync, including every file which couldn't be synced.
+
+use serde::Serialize;
+
+use crate::playlist::PlaylistSyncReport;
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// What a sync was doing to a file when it failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// Listing the files under a library root.
+    Scan,
+    /// Comparing a local file with its copy on the DMS.
+    Compare,
+    Copy,
+    Delete,
+    /// Moving a file on the DMS to where a moved local file belongs.
+    Rename,
+    /// Correcting the modification time of an unchanged file on the DMS.
+    Touch,
+    Playlist,
+    /// Writing the sync manifest to the DMS.
+    Manifest,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operation::Scan => write!(f, "read"),
+            Operation::Compare => write!(f, "compare"),
+            Operation::Copy => write!(f, "copy"),
+            Operation::Delete => write!(f, "delete"),
+            Operation::Rename => write!(f, "rename"),
+            Operation::Touch => write!(f, "update the modification time of"),
+            Operation::Playlist => write!(f, "synchronize playlists from"),
+            Operation::Manifest => write!(f, "write the sync manifest"),
+        }
+    }
+}
+
+/// A file which couldn't be synced.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SyncFailure {
+    pub path: PathBuf,
+    pub operation: Operation,
+    pub error: String,
+}
+
+impl SyncFailure {
+    pub fn new<P: Into<PathBuf>, E: fmt::Display>(path: P, operation: Operation, error: E) -> Self {
+        SyncFailure {
+            path: path.into(),
+            operation,
+            error: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SyncFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unable to {} {}: {}",
+            self.operation,
+            self.path.display(),
+            self.error
+        )
+    }
+}
+
+/// What a sync did.
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    pub copied: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    pub renamed: usize,
+    pub touched: usize,
+    /// The bytes written to the DMS by copies and updates.
+    pub bytes_copied: u64,
+    /// The outcome of the playlist sync, unless playlists couldn't be synced at all.
+    pub playlists: Option<PlaylistSyncReport>,
+    pub failures: Vec<SyncFailure>,
+}
+
+impl SyncReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
@@ -0,0 +1,178 @@
+//! Comparing modification times with those on the DMS's FAT filesystem.
+//!
+//! FAT stores modification times with a resolution of 2 seconds, and in local time rather than
+//! UTC. After a daylight saving change or a move to another timezone, every file on the DMS seems
+//! to be off by a whole number of hours, so an offset shared by most files whose times differ is
+//! ignored.
+
+use log::info;
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The resolution of modification times on FAT, in seconds.
+pub const FAT_RESOLUTION: i64 = 2;
+
+/// The largest difference between modification times which counts as the same time by default.
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(2);
+
+const HOUR: i64 = 3600;
+
+/// The largest offset between local and DMS times which is put down to timezones, in hours.
+const MAX_OFFSET_HOURS: i64 = 14;
+
+/// How local modification times are compared with those on the DMS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeComparison {
+    /// The largest difference between two modification times which counts as the same time.
+    pub tolerance: Duration,
+    /// A whole number of hours, in seconds, by which most times on the DMS are ahead of the local
+    /// ones.
+    pub offset: i64,
+}
+
+impl Default for TimeComparison {
+    fn default() -> Self {
+        TimeComparison::new(DEFAULT_TOLERANCE)
+    }
+}
+
+impl TimeComparison {
+    pub fn new(tolerance: Duration) -> Self {
+        TimeComparison {
+            tolerance,
+            offset: 0,
+        }
+    }
+
+    /// Detect whether most of the given pairs of local and DMS modification times which don't
+    /// match are off by the same whole number of hours.
+    pub fn detect<I>(tolerance: Duration, pairs: I) -> Self
+    where
+        I: IntoIterator<Item = (SystemTime, SystemTime)>,
+    {
+        let mut comparison = TimeComparison::new(tolerance);
+        let mut offsets: HashMap<i64, usize> = HashMap::new();
+        let mut total = 0;
+
+        for (local, dms) in pairs
+            .into_iter()
+            .filter(|&(l, d)| !comparison.matches(l, d))
+        {
+            total += 1;
+
+            if let Some(hours) = comparison.whole_hours(local, dms) {
+                *offsets.entry(hours).or_insert(0) += 1;
+            }
+        }
+
+        // ties are broken by the smallest offset, so that detection doesn't depend on hash order
+        let common = offsets
+            .into_iter()
+            .max_by_key(|&(hours, count)| (count, -hours.abs(), hours));
+
+        if let Some((hours, count)) = common.filter(|&(_, count)| count * 2 > total) {
+            info!(
+                "Modification times on the DMS are {} hours off for {} of {} changed files, \
+                 ignoring the offset.",
+                hours, count, total
+            );
+            comparison.offset = hours * HOUR;
+        }
+
+        comparison
+    }
+
+    /// Check whether a local modification time and one on the DMS are the same time.
+    pub fn matches(&self, local: SystemTime, dms: SystemTime) -> bool {
+        let diff = fat_seconds(dms) - fat_seconds(local);
+        let tolerance = self.tolerance.as_secs() as i64;
+
+        // files synced since the offset appeared have no offset
+        diff.abs() <= tolerance || (diff - self.offset).abs() <= tolerance
+    }
+
+    /// The number of hours by which a DMS time is off from a local one, if it's off by a whole,
+    /// non-zero number of hours.
+    fn whole_hours(&self, local: SystemTime, dms: SystemTime) -> Option<i64> {
+        let diff = fat_seconds(dms) - fat_seconds(local);
+        let hours = (diff as f64 / HOUR as f64).round() as i64;
+
+        Some(hours).filter(|&hours| {
+            hours != 0
+                && hours.abs() <= MAX_OFFSET_HOURS
+                && (diff - hours * HOUR).abs() <= self.tolerance.as_secs() as i64
+        })
+    }
+}
+
+/// The seconds since the epoch of a modification time, rounded down to the resolution of FAT.
+pub fn fat_seconds(time: SystemTime) -> i64 {
+    let seconds = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+
+    seconds - seconds.rem_euclid(FAT_RESOLUTION)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(seconds: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_fat_seconds() {
+        assert_eq!(1000, fat_seconds(time(1000, 0)));
+        assert_eq!(1000, fat_seconds(time(1001, 999)));
+        assert_eq!(1002, fat_seconds(time(1002, 1)));
+    }
+
+    #[test]
+    fn test_matches() {
+        let comparison = TimeComparison::default();
+
+        // FAT truncated the odd second away
+        assert!(comparison.matches(time(1001, 500), time(1000, 0)));
+        assert!(comparison.matches(time(1000, 0), time(1002, 0)));
+        assert!(!comparison.matches(time(1000, 0), time(1004, 0)));
+        assert!(!comparison.matches(time(1000, 0), time(1000 + 3600, 0)));
+
+        let strict = TimeComparison::new(Duration::from_secs(0));
+        assert!(strict.matches(time(1001, 0), time(1000, 0)));
+        assert!(!strict.matches(time(1000, 0), time(1002, 0)));
+    }
+
+    #[test]
+    fn test_detect_offset() {
+        let shifted = (0..10).map(|i| (time(i * 100, 0), time(i * 100 + 3601, 0)));
+        let comparison = TimeComparison::detect(DEFAULT_TOLERANCE, shifted.clone());
+        assert_eq!(3600, comparison.offset);
+
+        // shifted files match, as do files synced since the shift
+        assert!(comparison.matches(time(100, 0), time(3700, 0)));
+        assert!(comparison.matches(time(100, 0), time(100, 0)));
+        assert!(!comparison.matches(time(100, 0), time(7300, 0)));
+
+        // files synced since the shift don't count against it
+        let unshifted = (0..20).map(|i| (time(i * 100, 0), time(i * 100, 0)));
+        let comparison =
+            TimeComparison::detect(DEFAULT_TOLERANCE, shifted.clone().chain(unshifted));
+        assert_eq!(3600, comparison.offset);
+
+        // an offset only shared by a few changed files is a coincidence
+        let edited = (0..20).map(|i| (time(i * 100, 0), time(i * 100 + 500, 0)));
+        let comparison = TimeComparison::detect(DEFAULT_TOLERANCE, shifted.chain(edited));
+        assert_eq!(0, comparison.offset);
+        assert!(!comparison.matches(time(100, 0), time(3700, 0)));
+
+        let behind = (0..10).map(|i| (time(i * 100 + 7200, 0), time(i * 100, 0)));
+        assert_eq!(
+            -7200,
+            TimeComparison::detect(DEFAULT_TOLERANCE, behind).offset
+        );
+    }
+}
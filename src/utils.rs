@@ -4,10 +4,11 @@ mod test;
 pub mod crypto;
 pub mod fs;
 pub mod media;
+pub mod sanitize;
 pub mod stringpool;
 
 // export
 pub use crate::utils::stringpool::StringPool;
 
-pub static FAT32_DELETE_CHARS: &'static [char] = &['\t'];
-pub static FAT32_HYPHENIZE_CHARS: &'static [char] = &[':'];
+pub static FAT32_DELETE_CHARS: &[char] = &['\t'];
+pub static FAT32_HYPHENIZE_CHARS: &[char] = &[':'];
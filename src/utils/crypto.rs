@@ -2,12 +2,14 @@ use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::path::Path;
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 
-#[derive(Eq,PartialEq)]
+#[derive(Eq,Hash,PartialEq)]
 pub struct Sha256Digest([u8; 32]);
 
 impl fmt::Display for Sha256Digest {
@@ -39,3 +41,33 @@ pub fn sha256sum(path: &Path) -> Result<Sha256Digest,io::Error> {
 
     Ok(Sha256Digest(result))
 }
+
+/// The number of bytes read from each end of a file for a sampled checksum.
+pub const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Checksum the first and last `SAMPLE_SIZE` bytes of a file of the given size, or the whole file
+/// if it's no larger than the two samples.
+///
+/// This only reads a fraction of a large file, which matters on slow storage such as the DMS, but
+/// misses any differences between the samples.
+pub fn sampled_sha256sum(path: &Path, size: u64) -> Result<Sha256Digest,io::Error> {
+    if size <= 2 * SAMPLE_SIZE {
+        return sha256sum(path);
+    }
+
+    let mut f = File::open(path)?;
+    let mut buf = vec![0; SAMPLE_SIZE as usize];
+    let mut digest = Sha256::new();
+
+    f.read_exact(&mut buf)?;
+    digest.input(&buf);
+
+    f.seek(SeekFrom::Start(size - SAMPLE_SIZE))?;
+    f.read_exact(&mut buf)?;
+    digest.input(&buf);
+
+    let mut result: [u8; 32] = [0; 32];
+    digest.result(&mut result);
+
+    Ok(Sha256Digest(result))
+}
@@ -6,42 +6,56 @@ use std::error::Error;
 use std::fmt;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::time::UNIX_EPOCH;
 
 #[derive(Debug)]
-pub struct ModTimeUpdateError {
-    rc: isize,
+pub enum ModTimeUpdateError {
+    IOError { err: io::Error },
+    CallFailed { rc: isize },
 }
 
 impl Error for ModTimeUpdateError {
     fn description(&self) -> &str {
-        "Unable to call futimens without error"
+        match *self {
+            ModTimeUpdateError::IOError { .. } => "Unable to read or open a file to update",
+            ModTimeUpdateError::CallFailed { .. } => "Unable to call futimens without error",
+        }
     }
 }
 
 impl fmt::Display for ModTimeUpdateError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "failed to call futimens, return code {}", self.rc)
+        match *self {
+            ModTimeUpdateError::IOError { ref err } => write!(f, "I/O error: {}", err),
+            ModTimeUpdateError::CallFailed { rc } => {
+                write!(f, "failed to call futimens, return code {}", rc)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for ModTimeUpdateError {
+    fn from(err: io::Error) -> Self {
+        ModTimeUpdateError::IOError { err }
     }
 }
 
 // Copy the modified time of source to dest
 #[cfg(target_os = "linux")]
 pub fn copy_mtime(source: &Path, dest: &Path) -> Result<(), ModTimeUpdateError> {
-    let smeta = fs::metadata(source).unwrap();
-    let s_modified = smeta.modified().unwrap();
+    let s_modified = fs::metadata(source)?.modified()?;
+    let d_accessed = fs::metadata(dest)?.accessed()?;
 
-    let dmeta = fs::metadata(dest).unwrap();
-    let d_accessed = dmeta.accessed().unwrap();
+    // times before the epoch can't be represented on the DMS anyway
+    let accessed_duration = d_accessed.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let modified_duration = s_modified.duration_since(UNIX_EPOCH).unwrap_or_default();
 
-    let accessed_duration = d_accessed.duration_since(UNIX_EPOCH).unwrap();
-    let modified_duration = s_modified.duration_since(UNIX_EPOCH).unwrap();
+    let file = File::open(dest)?;
 
     let rc = unsafe {
-        let file = File::open(dest).unwrap();
-
         let accessed = timespec {
             tv_sec: accessed_duration.as_secs() as time_t,
             tv_nsec: accessed_duration.subsec_nanos() as libc::c_long,
@@ -60,6 +74,11 @@ pub fn copy_mtime(source: &Path, dest: &Path) -> Result<(), ModTimeUpdateError>
     if rc == 0 {
         Ok(())
     } else {
-        Err(ModTimeUpdateError { rc })
+        Err(ModTimeUpdateError::CallFailed { rc })
     }
 }
+
+/// Sync a directory so that renames and deletions of its entries are durable.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
@@ -1,21 +1,42 @@
 use std::path::Path;
 use std::path::PathBuf;
+use log::warn;
+
 use regex::Regex;
 
 use walkdir::WalkDir;
 
 lazy_static! {
-    static ref MEDIA_FILE_EXTENSION: Regex = Regex::new(r"(?i)(mp3)$").unwrap();
+    static ref MEDIA_FILE_EXTENSION: Regex = Regex::new(r"(?i)^(?:mp3|flac|ogg|wma)$").unwrap();
 }
 
 pub fn get_media_library(base: &Path) -> Vec<PathBuf> {
-    let walker = WalkDir::new(base).min_depth(1).into_iter();
-    // find all media files and collect them
-    walker.filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .filter(|e| is_media_filename(e.path()))
-        .map(|e| e.path().to_owned())
-        .collect()
+    let (files, errors) = walk_media_library(base);
+
+    for err in errors {
+        warn!("Unable to read media library: {}", err);
+    }
+
+    files
+}
+
+/// Find all media files under the given directory, along with the errors for everything under it
+/// which couldn't be read.
+pub fn walk_media_library(base: &Path) -> (Vec<PathBuf>, Vec<walkdir::Error>) {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+
+    for entry in WalkDir::new(base).min_depth(1) {
+        match entry {
+            Ok(ref e) if e.file_type().is_file() && is_media_filename(e.path()) => {
+                files.push(e.path().to_owned())
+            }
+            Ok(_) => {}
+            Err(err) => errors.push(err),
+        }
+    }
+
+    (files, errors)
 }
 
 pub fn is_media_filename(path: &Path) -> bool {
@@ -34,4 +55,8 @@ fn test_is_media_filename() {
     // test true cases
     assert!(is_media_filename(Path::new("/home/naftuli/Music/01 - Track.mp3")));
     assert!(is_media_filename(Path::new("/home/naftuli/Music/01 - Track.MP3")));
+    assert!(is_media_filename(Path::new("/home/naftuli/Music/01 - Track.flac")));
+    assert!(is_media_filename(Path::new("/home/naftuli/Music/01 - Track.FLAC")));
+    assert!(is_media_filename(Path::new("/home/naftuli/Music/01 - Track.ogg")));
+    assert!(is_media_filename(Path::new("/home/naftuli/Music/01 - Track.wma")));
 }
@@ -0,0 +1,113 @@
+use std::borrow::Cow;
+
+/// The longest a field may be, in characters, before it is truncated for display on the head unit.
+pub const MAX_FIELD_LENGTH: usize = 128;
+
+/// Make a tag value safe to write into a tab-separated database row.
+///
+/// Tabs, carriage returns, and line feeds become a single space, NULs and other control characters
+/// are dropped, surrounding whitespace is trimmed, and the result is truncated to
+/// `MAX_FIELD_LENGTH` characters. Values which are already safe are returned as they are.
+pub fn sanitize_field(value: &str) -> Cow<'_, str> {
+    if is_clean(value) {
+        return Cow::Borrowed(value);
+    }
+
+    let mut result = String::with_capacity(value.len());
+    let mut pending_space = false;
+
+    for c in value.chars() {
+        match c {
+            '\t' | '\r' | '\n' => pending_space = true,
+            c if c.is_control() => continue,
+            c => {
+                // collapse any run of separators into a single space, but never lead with one
+                if pending_space && !result.is_empty() {
+                    result.push(' ');
+                }
+
+                pending_space = false;
+                result.push(c);
+            }
+        }
+    }
+
+    let trimmed = result.trim();
+
+    Cow::Owned(match trimmed.char_indices().nth(MAX_FIELD_LENGTH) {
+        Some((end, _)) => trimmed[..end].trim_end().to_string(),
+        None => trimmed.to_string(),
+    })
+}
+
+/// Make a file location safe to write into a tab-separated database row.
+///
+/// Control characters, including tabs and line breaks, are dropped, as they are from media file
+/// IDs. Nothing else is altered, as the location must still point at the file.
+pub fn sanitize_location(location: &str) -> Cow<'_, str> {
+    if location.chars().any(char::is_control) {
+        Cow::Owned(location.chars().filter(|c| !c.is_control()).collect())
+    } else {
+        Cow::Borrowed(location)
+    }
+}
+
+fn is_clean(value: &str) -> bool {
+    !value.chars().any(char::is_control)
+        && value.trim() == value
+        && value.chars().nth(MAX_FIELD_LENGTH).is_none()
+}
+
+#[test]
+fn test_sanitize_field_clean() {
+    assert!(matches!(
+        sanitize_field("Party Hard"),
+        Cow::Borrowed("Party Hard")
+    ));
+    assert!(matches!(sanitize_field(""), Cow::Borrowed("")));
+    assert_eq!("Mêlée", sanitize_field("Mêlée"));
+}
+
+#[test]
+fn test_sanitize_field_separators() {
+    assert_eq!(
+        "Artist One Artist Two",
+        sanitize_field("Artist One\tArtist Two")
+    );
+    assert_eq!("Line One Line Two", sanitize_field("Line One\r\nLine Two"));
+    assert_eq!("Title", sanitize_field("\n\tTitle\t\r\n"));
+    assert_eq!("", sanitize_field("\t\r\n"));
+}
+
+#[test]
+fn test_sanitize_field_control_characters() {
+    assert_eq!("Title", sanitize_field("Ti\0tle\0"));
+    assert_eq!("Bell", sanitize_field("\u{7}Bell\u{1b}"));
+    assert_eq!("Next Line", sanitize_field("Next \u{85}Line"));
+}
+
+#[test]
+fn test_sanitize_field_length() {
+    let long = "x".repeat(MAX_FIELD_LENGTH + 50);
+    assert_eq!(MAX_FIELD_LENGTH, sanitize_field(&long).chars().count());
+
+    // truncation must respect character boundaries
+    let wide = "é".repeat(MAX_FIELD_LENGTH + 1);
+    assert_eq!("é".repeat(MAX_FIELD_LENGTH), sanitize_field(&wide));
+
+    // trailing whitespace left by truncation is trimmed
+    let spaced = format!("{} tail", "x".repeat(MAX_FIELD_LENGTH - 1));
+    assert_eq!("x".repeat(MAX_FIELD_LENGTH - 1), sanitize_field(&spaced));
+}
+
+#[test]
+fn test_sanitize_location() {
+    let long = format!("/dos/data/{}.mp3", "x".repeat(MAX_FIELD_LENGTH));
+    assert!(matches!(sanitize_location(&long), Cow::Borrowed(_)));
+
+    assert_eq!(
+        "/dos/data/TheEnd/ Something.mp3",
+        sanitize_location("/dos/data/The\tEnd/ Something.mp3")
+    );
+    assert_eq!("/dos/data/a.mp3", sanitize_location("/dos/data/a\r\n.mp3"));
+}
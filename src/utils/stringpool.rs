@@ -8,6 +8,12 @@ pub struct StringPool {
     map: RwLock<HashSet<Arc<str>>>,
 }
 
+impl Default for StringPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StringPool {
     pub fn new() -> Self {
         StringPool {
@@ -1,5 +1,8 @@
 use super::*;
 
+use crate::utils::crypto::{sampled_sha256sum, sha256sum, SAMPLE_SIZE};
+
+use std::fs;
 use std::sync::Arc;
 
 #[test]
@@ -10,3 +13,31 @@ fn test_string_pool() {
 
     assert!(Arc::ptr_eq(&new, &old));
 }
+
+#[test]
+fn test_sampled_sha256sum() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("file");
+    let checksum = |contents: &[u8]| {
+        fs::write(&path, contents).unwrap();
+        sampled_sha256sum(&path, contents.len() as u64).unwrap().to_string()
+    };
+
+    // small files are checksummed whole
+    fs::write(&path, b"small").unwrap();
+    assert_eq!(
+        sha256sum(&path).unwrap().to_string(),
+        sampled_sha256sum(&path, 5).unwrap().to_string()
+    );
+
+    let mut contents = vec![0; 3 * SAMPLE_SIZE as usize];
+    let original = checksum(&contents);
+
+    // only the start and end of larger files are sampled
+    contents[SAMPLE_SIZE as usize + 1] = 1;
+    assert_eq!(original, checksum(&contents));
+
+    let last = contents.len() - 1;
+    contents[last] = 1;
+    assert_ne!(original, checksum(&contents));
+}